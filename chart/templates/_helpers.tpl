@@ -40,3 +40,35 @@ app.kubernetes.io/instance: {{ .Release.Name }}
 {{- default "default" .Values.serviceAccount.name }}
 {{- end }}
 {{- end }}
+
+{{/*
+The certificate of the conversion webhook. Unless conversion.secretName is
+set, a certificate is generated once and kept in a secret that is reused on
+upgrades.
+*/}}
+{{- define "..conversionSecretName" -}}
+{{- default (printf "%s-conversion-tls" (include "..fullname" .)) .Values.conversion.secretName }}
+{{- end }}
+
+{{- define "..conversionTLS" -}}
+{{- if not (hasKey .Values.conversion "generated") }}
+{{- $existing := lookup "v1" "Secret" .Release.Namespace (include "..conversionSecretName" .) }}
+{{- if and $existing (hasKey $existing.data "ca.crt") }}
+{{- $_ := set .Values.conversion "generated" (dict "ca.crt" (index $existing.data "ca.crt") "tls.crt" (index $existing.data "tls.crt") "tls.key" (index $existing.data "tls.key")) }}
+{{- else }}
+{{- $service := printf "%s-conversion" (include "..fullname" .) }}
+{{- $ca := genCA (printf "%s-ca" $service) 3650 }}
+{{- $cert := genSignedCert $service nil (list (printf "%s.%s.svc" $service .Release.Namespace)) 3650 $ca }}
+{{- $_ := set .Values.conversion "generated" (dict "ca.crt" ($ca.Cert | b64enc) "tls.crt" ($cert.Cert | b64enc) "tls.key" ($cert.Key | b64enc)) }}
+{{- end }}
+{{- end }}
+{{- toYaml .Values.conversion.generated }}
+{{- end }}
+
+{{- define "..conversionCABundle" -}}
+{{- if .Values.conversion.secretName }}
+{{- required "conversion.caBundle must be set with conversion.secretName" .Values.conversion.caBundle }}
+{{- else }}
+{{- index (include "..conversionTLS" . | fromYaml) "ca.crt" }}
+{{- end }}
+{{- end }}
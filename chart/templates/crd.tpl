@@ -6,16 +6,411 @@ metadata:
   labels:
     {{- include "..labels" . | nindent 4 }}
 spec:
+  conversion:
+    strategy: Webhook
+    webhook:
+      conversionReviewVersions: ["v1"]
+      clientConfig:
+        service:
+          name: {{ include "..fullname" . }}-conversion
+          namespace: {{ .Release.Namespace }}
+          path: /convert/workflows
+          port: 443
+        caBundle: {{ include "..conversionCABundle" . }}
   group: workflow-deploy.ngerakines.me
   names:
     categories: []
     kind: Workflow
     plural: workflows
-    shortNames: []
+    shortNames:
+    - wf
     singular: workflow
   scope: Cluster
   versions:
-  - additionalPrinterColumns: []
+  - additionalPrinterColumns:
+    - jsonPath: .spec.version
+      name: Version
+      type: string
+    - jsonPath: .spec.parallel
+      name: Parallel
+      type: integer
+    - jsonPath: .status.in_flight
+      name: In-Flight
+      type: integer
+    - jsonPath: .status.last_result
+      name: Last-Result
+      type: string
+    - jsonPath: .metadata.creationTimestamp
+      name: Age
+      type: date
+    name: v1beta1
+    schema:
+      openAPIV3Schema:
+        description: Auto-generated derived type for WorkflowSpec via `CustomResource`
+        properties:
+          spec:
+            properties:
+              debounce:
+                format: uint32
+                maximum: 86400.0
+                minimum: 0.0
+                nullable: true
+                type: integer
+              namespaces:
+                items:
+                  type: string
+                minItems: 1
+                type: array
+                x-kubernetes-list-type: set
+              notifications:
+                default: []
+                items:
+                  properties:
+                    events:
+                      default: []
+                      items:
+                        type: string
+                      type: array
+                    template:
+                      nullable: true
+                      type: string
+                    url:
+                      type: string
+                  required:
+                  - url
+                  type: object
+                type: array
+              parallel:
+                format: uint32
+                maximum: 255.0
+                minimum: 1.0
+                nullable: true
+                type: integer
+              steps:
+                items:
+                  properties:
+                    actions:
+                      items:
+                        oneOf:
+                        - required:
+                          - update_deployment
+                        - required:
+                          - update_env
+                        - required:
+                          - update_config
+                        - required:
+                          - restart_deployment
+                        - required:
+                          - scale
+                        - required:
+                          - run_job
+                        - required:
+                          - http_check
+                        - required:
+                          - unknown
+                        properties:
+                          http_check:
+                            properties:
+                              targets:
+                                items:
+                                  properties:
+                                    body:
+                                      nullable: true
+                                      type: string
+                                    interval_seconds:
+                                      format: uint32
+                                      minimum: 1.0
+                                      nullable: true
+                                      type: integer
+                                    name:
+                                      type: string
+                                    status:
+                                      items:
+                                        format: uint16
+                                        maximum: 599.0
+                                        minimum: 100.0
+                                        type: integer
+                                      nullable: true
+                                      type: array
+                                    successes:
+                                      format: uint32
+                                      minimum: 1.0
+                                      nullable: true
+                                      type: integer
+                                    timeout_seconds:
+                                      format: uint32
+                                      minimum: 1.0
+                                      nullable: true
+                                      type: integer
+                                    url:
+                                      type: string
+                                  required:
+                                  - name
+                                  - url
+                                  type: object
+                                minItems: 1
+                                type: array
+                            required:
+                            - targets
+                            type: object
+                          restart_deployment:
+                            properties:
+                              targets:
+                                items:
+                                  properties:
+                                    name:
+                                      type: string
+                                  required:
+                                  - name
+                                  type: object
+                                minItems: 1
+                                type: array
+                            required:
+                            - targets
+                            type: object
+                          run_job:
+                            properties:
+                              targets:
+                                items:
+                                  properties:
+                                    cleanup:
+                                      nullable: true
+                                      type: boolean
+                                    containers:
+                                      default: []
+                                      items:
+                                        type: string
+                                      type: array
+                                    job:
+                                      nullable: true
+                                      type: object
+                                      x-kubernetes-preserve-unknown-fields: true
+                                    name:
+                                      type: string
+                                    template:
+                                      nullable: true
+                                      type: string
+                                    timeout_seconds:
+                                      format: uint32
+                                      minimum: 1.0
+                                      nullable: true
+                                      type: integer
+                                  required:
+                                  - name
+                                  type: object
+                                  x-kubernetes-validations:
+                                  - message: run_job targets must set job or template
+                                    rule: has(self.job) || has(self.template)
+                                minItems: 1
+                                type: array
+                            required:
+                            - targets
+                            type: object
+                          scale:
+                            properties:
+                              targets:
+                                items:
+                                  properties:
+                                    name:
+                                      type: string
+                                    replicas:
+                                      pattern: ^[+-]?[0-9]+$
+                                      type: string
+                                  required:
+                                  - name
+                                  - replicas
+                                  type: object
+                                minItems: 1
+                                type: array
+                            required:
+                            - targets
+                            type: object
+                          unknown:
+                            properties:
+                              action:
+                                type: string
+                              targets:
+                                default: []
+                                items:
+                                  nullable: true
+                                  type: object
+                                  x-kubernetes-preserve-unknown-fields: true
+                                type: array
+                            required:
+                            - action
+                            type: object
+                          update_config:
+                            properties:
+                              targets:
+                                items:
+                                  properties:
+                                    data:
+                                      additionalProperties:
+                                        type: string
+                                      type: object
+                                    deployments:
+                                      default: []
+                                      items:
+                                        type: string
+                                      type: array
+                                    name:
+                                      type: string
+                                  required:
+                                  - data
+                                  - name
+                                  type: object
+                                minItems: 1
+                                type: array
+                            required:
+                            - targets
+                            type: object
+                          update_deployment:
+                            properties:
+                              targets:
+                                items:
+                                  properties:
+                                    containers:
+                                      items:
+                                        type: string
+                                      minItems: 1
+                                      type: array
+                                    name:
+                                      type: string
+                                  required:
+                                  - containers
+                                  - name
+                                  type: object
+                                minItems: 1
+                                type: array
+                            required:
+                            - targets
+                            type: object
+                          update_env:
+                            properties:
+                              targets:
+                                items:
+                                  properties:
+                                    containers:
+                                      default: []
+                                      items:
+                                        type: string
+                                      type: array
+                                    env:
+                                      items:
+                                        properties:
+                                          name:
+                                            type: string
+                                          value:
+                                            nullable: true
+                                            type: string
+                                        required:
+                                        - name
+                                        type: object
+                                      type: array
+                                    name:
+                                      type: string
+                                  required:
+                                  - env
+                                  - name
+                                  type: object
+                                minItems: 1
+                                type: array
+                            required:
+                            - targets
+                            type: object
+                        type: object
+                      minItems: 1
+                      type: array
+                    analysis:
+                      default: []
+                      items:
+                        properties:
+                          interval_seconds:
+                            format: uint32
+                            minimum: 1.0
+                            nullable: true
+                            type: integer
+                          max:
+                            format: double
+                            nullable: true
+                            type: number
+                          min:
+                            format: double
+                            nullable: true
+                            type: number
+                          name:
+                            type: string
+                          query:
+                            type: string
+                          window_seconds:
+                            format: uint32
+                            minimum: 1.0
+                            nullable: true
+                            type: integer
+                        required:
+                        - name
+                        - query
+                        type: object
+                        x-kubernetes-validations:
+                        - message: min must not be greater than max
+                          rule: '!has(self.min) || !has(self.max) || self.min <= self.max'
+                      type: array
+                  required:
+                  - actions
+                  type: object
+                type: array
+              suppressions:
+                default: []
+                items:
+                  type: string
+                type: array
+              version:
+                minLength: 1
+                type: string
+            required:
+            - namespaces
+            - steps
+            - version
+            type: object
+          status:
+            nullable: true
+            properties:
+              in_flight:
+                format: uint32
+                minimum: 0.0
+                nullable: true
+                type: integer
+              last_group:
+                nullable: true
+                type: string
+              last_result:
+                nullable: true
+                type: string
+            type: object
+        required:
+        - spec
+        title: Workflow
+        type: object
+    served: true
+    storage: true
+    subresources:
+      status: {}
+  - additionalPrinterColumns:
+    - jsonPath: .spec.version
+      name: Version
+      type: string
+    - jsonPath: .spec.parallel
+      name: Parallel
+      type: integer
+    - jsonPath: .status.in_flight
+      name: In-Flight
+      type: integer
+    - jsonPath: .status.last_result
+      name: Last-Result
+      type: string
+    - jsonPath: .metadata.creationTimestamp
+      name: Age
+      type: date
     name: v1alpha
     schema:
       openAPIV3Schema:
@@ -25,16 +420,38 @@ spec:
             properties:
               debounce:
                 format: uint32
-                minimum: 0
+                maximum: 86400.0
+                minimum: 0.0
                 nullable: true
                 type: integer
               namespaces:
                 items:
                   type: string
+                minItems: 1
+                type: array
+                x-kubernetes-list-type: set
+              notifications:
+                items:
+                  properties:
+                    events:
+                      items:
+                        type: string
+                      nullable: true
+                      type: array
+                    template:
+                      nullable: true
+                      type: string
+                    url:
+                      type: string
+                  required:
+                  - url
+                  type: object
+                nullable: true
                 type: array
               parallel:
                 format: uint32
-                minimum: 0
+                maximum: 255.0
+                minimum: 1.0
                 nullable: true
                 type: integer
               steps:
@@ -44,28 +461,151 @@ spec:
                       items:
                         properties:
                           action:
+                            enum:
+                            - update_deployment
+                            - update_env
+                            - update_config
+                            - restart_deployment
+                            - scale
+                            - run_job
+                            - http_check
                             type: string
                           targets:
                             items:
                               properties:
+                                body:
+                                  nullable: true
+                                  type: string
+                                cleanup:
+                                  nullable: true
+                                  type: boolean
                                 containers:
+                                  default: []
                                   items:
                                     type: string
                                   type: array
+                                data:
+                                  additionalProperties:
+                                    type: string
+                                  nullable: true
+                                  type: object
+                                deployments:
+                                  items:
+                                    type: string
+                                  nullable: true
+                                  type: array
+                                env:
+                                  items:
+                                    properties:
+                                      name:
+                                        type: string
+                                      value:
+                                        nullable: true
+                                        type: string
+                                    required:
+                                    - name
+                                    type: object
+                                  nullable: true
+                                  type: array
+                                interval_seconds:
+                                  format: uint32
+                                  minimum: 1.0
+                                  nullable: true
+                                  type: integer
+                                job:
+                                  nullable: true
+                                  type: object
+                                  x-kubernetes-preserve-unknown-fields: true
                                 name:
                                   type: string
+                                replicas:
+                                  nullable: true
+                                  pattern: ^[+-]?[0-9]+$
+                                  type: string
                                 resource:
+                                  enum:
+                                  - Deployment
+                                  - ConfigMap
+                                  - Job
+                                  - CronJob
+                                  - Service
+                                  type: string
+                                status:
+                                  items:
+                                    format: uint16
+                                    maximum: 599.0
+                                    minimum: 100.0
+                                    type: integer
+                                  nullable: true
+                                  type: array
+                                successes:
+                                  format: uint32
+                                  minimum: 1.0
+                                  nullable: true
+                                  type: integer
+                                template:
+                                  nullable: true
+                                  type: string
+                                timeout_seconds:
+                                  format: uint32
+                                  minimum: 1.0
+                                  nullable: true
+                                  type: integer
+                                url:
+                                  nullable: true
                                   type: string
                               required:
-                              - containers
                               - name
                               - resource
                               type: object
+                            minItems: 1
                             type: array
                         required:
                         - action
                         - targets
                         type: object
+                        x-kubernetes-validations:
+                        - message: scale targets must set replicas
+                          rule: self.action != 'scale' || self.targets.all(t, has(t.replicas))
+                        - message: http_check targets must set url
+                          rule: self.action != 'http_check' || self.targets.all(t, has(t.url))
+                        - message: run_job targets must set job or template
+                          rule: self.action != 'run_job' || self.targets.all(t, has(t.job) || has(t.template))
+                      minItems: 1
+                      type: array
+                    analysis:
+                      items:
+                        properties:
+                          interval_seconds:
+                            format: uint32
+                            minimum: 1.0
+                            nullable: true
+                            type: integer
+                          max:
+                            format: double
+                            nullable: true
+                            type: number
+                          min:
+                            format: double
+                            nullable: true
+                            type: number
+                          name:
+                            type: string
+                          query:
+                            type: string
+                          window_seconds:
+                            format: uint32
+                            minimum: 1.0
+                            nullable: true
+                            type: integer
+                        required:
+                        - name
+                        - query
+                        type: object
+                        x-kubernetes-validations:
+                        - message: min must not be greater than max
+                          rule: '!has(self.min) || !has(self.max) || self.min <= self.max'
+                      nullable: true
                       type: array
                   required:
                   - actions
@@ -76,6 +616,7 @@ spec:
                   type: string
                 type: array
               version:
+                minLength: 1
                 type: string
             required:
             - namespaces
@@ -83,10 +624,26 @@ spec:
             - supression
             - version
             type: object
+          status:
+            nullable: true
+            properties:
+              in_flight:
+                format: uint32
+                minimum: 0.0
+                nullable: true
+                type: integer
+              last_group:
+                nullable: true
+                type: string
+              last_result:
+                nullable: true
+                type: string
+            type: object
         required:
         - spec
         title: Workflow
         type: object
     served: true
-    storage: true
-    subresources: {}
\ No newline at end of file
+    storage: false
+    subresources:
+      status: {}
@@ -1,6 +1,10 @@
-#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Action {
     WorkflowUpdated(String, bool),
     ReconcileWorkflow(String),
-    WorkflowJobFinished(String, String, bool),
+    // The workflow, group, whether the job succeeded, and why it failed if known.
+    WorkflowJobFinished(String, String, bool, Option<String>),
 }
@@ -1,49 +1,206 @@
 use std::{
-    borrow::BorrowMut,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Debug,
+    hash::Hasher,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, Duration, Utc};
-use k8s_openapi::api::apps::v1::Deployment;
-use kube::{
-    api::{Patch, PatchParams},
-    Api, Client,
+use fnv::FnvHasher;
+use futures::future::BoxFuture;
+use k8s_openapi::api::{
+    apps::v1::Deployment,
+    batch::v1::{CronJob, Job, JobSpec},
+    core::v1::ConfigMap,
 };
-use tokio::{
-    sync::broadcast::Receiver,
-    sync::mpsc::Receiver as ActionReceiver,
-    time::{sleep, Instant},
+use kube::{
+    api::{DeleteParams, Patch, PatchParams, PostParams},
+    runtime::events::EventType,
+    Api, Client, ResourceExt,
 };
+use serde::Serialize;
+use tokio::{sync::broadcast::Receiver, sync::mpsc::Receiver as ActionReceiver};
 use tracing::{debug, error, info, trace, warn};
 
 use crate::{
     action::Action,
+    analysis::{check_thresholds, query},
     context::Context,
-    k8s_util::replace_last,
+    crd::{Workflow, WorkflowStatus, WorkflowStepAction},
+    crd_storage::{HistoryEntry, HistoryRecord, JobChanges, JobCheckpoint, PlanStep, WorkflowPlan},
+    events::{publish_deployment_event, publish_workflow_event},
+    http_check::http_check,
+    job::build_job,
+    k8s_util::{annotation_true, deployment_scaled},
+    notify::{Notification, NotificationEvent},
+    patch::{
+        config_map_data_patch, env_patch, image_changes, image_patch,
+        pod_template_annotation_patch, scale_patch, ReplicaChange,
+    },
+    recorder::Entry,
+    supervisor::{record_checkpoint, JobOutcome, JobSupervisor, StopSignal},
+    template::render,
+    watch_pod::{deployment_revision, is_new_revision, revision_selector, PodFailureWatch},
     when::{parse_supressions, Supression},
+    work_queue::WorkQueue,
 };
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
-struct WorkflowJob {
-    workflow: String,
-    checksum: u64,
-    group: String,
-    after: DateTime<Utc>,
-    in_flight: bool,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct WorkflowJob {
+    pub(crate) workflow: String,
+    pub(crate) checksum: u64,
+    pub(crate) group: String,
+    pub(crate) after: DateTime<Utc>,
+    // The number of actions that were completed before the controller last
+    // stopped, and what they changed, when the job resumes from a checkpoint.
+    pub(crate) completed: usize,
+    pub(crate) changes: JobChanges,
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
-enum WorkflowAction {
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum WorkflowAction {
     Started(),
     UpdateDeployment(String, Vec<(String, String)>),
+    UpdateEnv(String, Vec<String>, Vec<(String, Option<String>)>),
+    UpdateConfig(String, BTreeMap<String, String>),
+    AnnotateDeployment(String, String, String),
+    RestartDeployment(String),
     WaitDeploymentReady(String),
+    // The replicas are parsed when the step runs, so that an invalid value fails the group.
+    ScaleDeployment(String, String),
+    WaitDeploymentScaled(String),
+    RunJob(JobTarget),
+    WaitJobFinished(String, u32, bool),
+    HttpCheck(HttpCheckTarget),
+    Analysis(AnalysisTarget),
+    // Only recorded in history: the name of an analysis, whether it passed, and the values it observed.
+    AnalysisResult(String, bool, Vec<f64>),
+}
+
+// A job to create in a group. The inline spec, when set, is stored as rendered
+// JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct JobTarget {
+    pub(crate) name: String,
+    pub(crate) spec: Option<String>,
+    pub(crate) template: Option<String>,
+    pub(crate) containers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HttpCheckTarget {
+    pub(crate) name: String,
+    pub(crate) url: String,
+    pub(crate) status: Vec<u16>,
+    pub(crate) body: Option<String>,
+    pub(crate) interval_seconds: u32,
+    pub(crate) timeout_seconds: u32,
+    pub(crate) successes: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AnalysisTarget {
+    pub(crate) name: String,
+    pub(crate) query: String,
+    pub(crate) min: Option<f64>,
+    pub(crate) max: Option<f64>,
+    pub(crate) window_seconds: u32,
+    pub(crate) interval_seconds: u32,
 }
 
-impl WorkflowJob {
-    fn should_retain(&self, workflow: &String) -> bool {
-        self.in_flight || self.workflow != *workflow
+// Tracks the progress of an analysis across ticks of the work loop.
+struct AnalysisProgress {
+    started_at: DateTime<Utc>,
+    last_attempt_at: Option<DateTime<Utc>>,
+    errors: u32,
+    values: Vec<f64>,
+}
+
+// Tracks the progress of an http check across ticks of the work loop.
+struct HttpCheckProgress {
+    started_at: DateTime<Utc>,
+    last_attempt_at: Option<DateTime<Utc>>,
+    successes: u32,
+}
+
+impl WorkflowAction {
+    // The name of the deployment whose pods are rolled by this action, if any.
+    fn updated_deployment(&self) -> Option<&String> {
+        match self {
+            WorkflowAction::UpdateDeployment(name, _)
+            | WorkflowAction::UpdateEnv(name, _, _)
+            | WorkflowAction::AnnotateDeployment(name, _, _)
+            | WorkflowAction::RestartDeployment(name) => Some(name),
+            _ => None,
+        }
     }
+
+    // The name of the action, as used in history and plans.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            WorkflowAction::Started() => "started",
+            WorkflowAction::UpdateDeployment(_, _) => "update_deployment",
+            WorkflowAction::UpdateEnv(_, _, _) => "update_env",
+            WorkflowAction::UpdateConfig(_, _) => "update_config",
+            WorkflowAction::AnnotateDeployment(_, _, _) => "annotate_deployment",
+            WorkflowAction::RestartDeployment(_) => "restart_deployment",
+            WorkflowAction::WaitDeploymentReady(_) => "wait_deployment_ready",
+            WorkflowAction::ScaleDeployment(_, _) => "scale",
+            WorkflowAction::WaitDeploymentScaled(_) => "wait_deployment_scaled",
+            WorkflowAction::RunJob(_) => "run_job",
+            WorkflowAction::WaitJobFinished(_, _, _) => "wait_job_finished",
+            WorkflowAction::HttpCheck(_) => "http_check",
+            WorkflowAction::Analysis(_) | WorkflowAction::AnalysisResult(_, _, _) => "analysis",
+        }
+    }
+
+    // The name of the resource, check or analysis the action is for.
+    pub(crate) fn target(&self) -> &str {
+        match self {
+            WorkflowAction::Started() => "",
+            WorkflowAction::UpdateDeployment(name, _)
+            | WorkflowAction::UpdateEnv(name, _, _)
+            | WorkflowAction::UpdateConfig(name, _)
+            | WorkflowAction::AnnotateDeployment(name, _, _)
+            | WorkflowAction::RestartDeployment(name)
+            | WorkflowAction::WaitDeploymentReady(name)
+            | WorkflowAction::ScaleDeployment(name, _)
+            | WorkflowAction::WaitDeploymentScaled(name)
+            | WorkflowAction::WaitJobFinished(name, _, _)
+            | WorkflowAction::AnalysisResult(name, _, _) => name,
+            WorkflowAction::RunJob(target) => &target.name,
+            WorkflowAction::HttpCheck(target) => &target.name,
+            WorkflowAction::Analysis(target) => &target.name,
+        }
+    }
+
+    // Whether the action only waits on or observes the group without changing it.
+    pub(crate) fn observes(&self) -> bool {
+        matches!(
+            self,
+            WorkflowAction::WaitDeploymentReady(_)
+                | WorkflowAction::WaitDeploymentScaled(_)
+                | WorkflowAction::WaitJobFinished(_, _, _)
+                | WorkflowAction::HttpCheck(_)
+                | WorkflowAction::Analysis(_)
+        )
+    }
+}
+
+// How often the action loop dispatches queued jobs when no actions arrive.
+pub(crate) const DISPATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3);
+
+// The work queue of the action loop and what it knows about each workflow.
+// The queue is ordered so that jobs are dispatched in the same order when
+// actions are replayed.
+#[derive(Default)]
+pub(crate) struct Dispatcher {
+    queue: WorkQueue,
+    workflow_supressions: HashMap<String, Vec<Supression>>,
+    workflow_max_in_flight: HashMap<String, u8>,
+    // Workflows that were suppressed on the last tick, used to only publish an
+    // event when a workflow enters a suppression window.
+    supressed_workflows: HashSet<String>,
 }
 
 pub(crate) async fn action_loop(
@@ -53,14 +210,10 @@ pub(crate) async fn action_loop(
 ) -> Result<()> {
     info!("action loop started");
 
-    let one_second = Duration::seconds(3).to_std().unwrap();
-
-    let sleeper = sleep(one_second);
-    tokio::pin!(sleeper);
+    let mut sleeper = context.clock.sleep(DISPATCH_INTERVAL);
 
-    let mut workflow_queue: HashSet<WorkflowJob> = HashSet::new();
-    let mut workflow_supressions: HashMap<String, Vec<Supression>> = HashMap::new();
-    let mut workflow_max_in_flight: HashMap<String, u8> = HashMap::new();
+    let mut dispatcher = Dispatcher::default();
+    let mut jobs = JobSupervisor::default();
 
     'outer: loop {
         tokio::select! {
@@ -69,7 +222,7 @@ pub(crate) async fn action_loop(
                 break 'outer;
             },
             () = &mut sleeper => {
-                sleeper.as_mut().reset(Instant::now() + one_second);
+                sleeper = context.clock.sleep(DISPATCH_INTERVAL);
                 trace!("action loop timed out, resetting sleep");
             }
             r = rx.recv() => {
@@ -81,144 +234,336 @@ pub(crate) async fn action_loop(
                 let val = r.unwrap();
                 debug!("action loop got value: {:?}", val);
 
-                match val.clone() {
-                    Action::WorkflowJobFinished(workflow_name, group, everything_ok) => {
-                        context
-                            .metrics
-                            .count_with_tags("action_loop.event", 1)
-                            .with_tag("event", "workflow_job_finished")
-                            .with_tag("workflow_name", workflow_name.as_str())
-                            .with_tag("workflow_group", group.as_str())
-                            .with_tag("everything_ok", everything_ok.to_string().as_str())
-                            .send();
+                context.recorder.record(context.clock.now(), Entry::Action(val.clone()));
+                dispatcher.handle(&context, val).await;
+            }
+        }
 
-                        if everything_ok {
-                            workflow_queue.retain(|x| x.workflow != workflow_name && x.group != group);
-                        } else {
-                            let found_workflow = workflow_queue.iter().find(|x| x.workflow == workflow_name && x.group == group);
-                            let purge_workflows = match found_workflow {
-                                Some(v) => {
-                                    workflow_queue.iter().filter(|x| x.checksum != v.checksum).cloned().collect::<Vec<WorkflowJob>>()
-                                },
-                                None => {
-                                    workflow_queue.iter().filter(|x| x.workflow != workflow_name).cloned().collect::<Vec<WorkflowJob>>()
-                                }
-                            };
+        for next_job in dispatcher.dispatch(&context) {
+            context
+                .recorder
+                .record(context.clock.now(), Entry::Dispatched((&next_job).into()));
 
-                            warn!("purging {} {workflow_name} workflows", purge_workflows.len());
+            let job_context = context.clone();
+            let job = next_job.clone();
+            jobs.spawn(&context, next_job, |stop| {
+                action_workflow_updated(job_context, job, stop)
+            });
+        }
+        jobs.report(&context);
+    }
 
-                            context
-                                .metrics
-                                .count_with_tags("action_loop.purge", purge_workflows.len() as i64)
-                                .with_tag("workflow_name", workflow_name.as_str())
-                                .send();
+    jobs.shutdown(&context).await;
+    info!("action loop ended");
+    Ok(())
+}
 
-                            for purge_workflow in purge_workflows {
-                                workflow_queue.remove(&purge_workflow);
-                            }
-                        }
+impl Dispatcher {
+    // Updates the queue for an action.
+    pub(crate) async fn handle(&mut self, context: &Context, action: Action) {
+        match action.clone() {
+            Action::WorkflowJobFinished(workflow_name, group, everything_ok, reason) => {
+                context
+                    .metrics
+                    .count_with_tags("action_loop.event", 1)
+                    .with_tag("event", "workflow_job_finished")
+                    .with_tag("workflow_name", workflow_name.as_str())
+                    .with_tag("workflow_group", group.as_str())
+                    .with_tag("everything_ok", everything_ok.to_string().as_str())
+                    .send();
 
+                if everything_ok {
+                    publish_workflow_event(
+                        context,
+                        &context.client,
+                        &workflow_name,
+                        EventType::Normal,
+                        "GroupSucceeded",
+                        format!("Group {group} finished"),
+                    );
+                    context.notifier.notify(
+                        Notification::new(
+                            NotificationEvent::GroupSucceeded,
+                            &workflow_name,
+                            format!("Group {group} finished"),
+                            context.clock.now(),
+                        )
+                        .with_group(&group),
+                    );
+
+                    self.queue.finish(&workflow_name, &group, true);
+                } else {
+                    let reason = reason.unwrap_or("unknown reason".to_string());
+                    warn!("{workflow_name} job for {group} failed: {reason}");
+                    publish_workflow_event(
+                        context,
+                        &context.client,
+                        &workflow_name,
+                        EventType::Warning,
+                        "GroupFailed",
+                        format!("Group {group} failed: {reason}"),
+                    );
+                    context.notifier.notify(
+                        Notification::new(
+                            NotificationEvent::GroupFailed,
+                            &workflow_name,
+                            format!("Group {group} failed: {reason}"),
+                            context.clock.now(),
+                        )
+                        .with_group(&group),
+                    );
+
+                    // The failed job is done, and the groups of the same workflow version
+                    // that have not been dispatched yet are purged.
+                    let purged = match self.queue.finish(&workflow_name, &group, false) {
+                        Some(failed) => self.queue.purge(&workflow_name, Some(failed.checksum)),
+                        None => self.queue.purge(&workflow_name, None),
+                    };
+
+                    warn!("purged {purged} {workflow_name} workflows");
+
+                    context
+                        .metrics
+                        .count_with_tags("action_loop.purge", purged as i64)
+                        .with_tag("workflow_name", workflow_name.as_str())
+                        .send();
+
+                    if purged > 0 {
+                        publish_workflow_event(
+                            context,
+                            &context.client,
+                            &workflow_name,
+                            EventType::Warning,
+                            "Purged",
+                            format!("Purged {} queued groups after group {group} failed", purged),
+                        );
+                        context.notifier.notify(
+                            Notification::new(
+                                NotificationEvent::Purge,
+                                &workflow_name,
+                                format!(
+                                    "Purged {} queued groups after group {group} failed",
+                                    purged
+                                ),
+                                context.clock.now(),
+                            )
+                            .with_group(&group),
+                        );
                     }
-                    Action::WorkflowUpdated(workflow_name, version_changed) => {
-                        context
-                            .metrics
-                            .count_with_tags("action_loop.event", 1)
-                            .with_tag("event", "workflow_updated")
-                            .with_tag("workflow_name", workflow_name.as_str())
-                            .send();
+                }
 
-                        // 1. Get the latest workflow checksum
+                update_workflow_status(
+                    &context.client,
+                    &workflow_name,
+                    WorkflowStatus {
+                        in_flight: Some(self.queue.in_flight(&workflow_name) as u32),
+                        last_result: Some(
+                            if everything_ok { "Succeeded" } else { "Failed" }.to_string(),
+                        ),
+                        last_group: Some(group.clone()),
+                    },
+                );
 
-                        let latest_workflow_res = context.workflow_storage.lastest_workflow(workflow_name.clone()).await;
-                        if latest_workflow_res.is_err() {
-                            error!("unable to get latest workflow for action: {:?}", val);
-                            continue 'outer;
-                        }
-                        let latest_workflow = latest_workflow_res.unwrap();
+                if self.queue.is_idle(&workflow_name) {
+                    publish_workflow_event(
+                        context,
+                        &context.client,
+                        &workflow_name,
+                        EventType::Normal,
+                        "Finished",
+                        "No groups remain queued or in flight".to_string(),
+                    );
+                }
+            }
+            Action::WorkflowUpdated(workflow_name, version_changed) => {
+                context
+                    .metrics
+                    .count_with_tags("action_loop.event", 1)
+                    .with_tag("event", "workflow_updated")
+                    .with_tag("workflow_name", workflow_name.as_str())
+                    .send();
 
-                        // 2. Get all of the groups for the workflow
+                // 1. Get the latest workflow checksum
 
-                        let workflow_res = context.workflow_storage.get_workflow(workflow_name.clone(), Some(latest_workflow)).await;
-                        if workflow_res.is_err() {
-                            error!("unable to get workflow version: {:?} {:?}", val, latest_workflow);
-                            continue 'outer;
-                        }
-                        let workflow = workflow_res.unwrap();
+                let latest_workflow_res = context
+                    .workflow_storage
+                    .lastest_workflow(workflow_name.clone())
+                    .await;
+                if latest_workflow_res.is_err() {
+                    error!("unable to get latest workflow for action: {:?}", action);
+                    return;
+                }
+                let latest_workflow = latest_workflow_res.unwrap();
+
+                // 2. Get all of the groups for the workflow
+
+                let workflow_res = context
+                    .workflow_storage
+                    .get_workflow(workflow_name.clone(), Some(latest_workflow))
+                    .await;
+                if workflow_res.is_err() {
+                    error!(
+                        "unable to get workflow version: {:?} {:?}",
+                        action, latest_workflow
+                    );
+                    return;
+                }
+                let workflow = workflow_res.unwrap();
 
-                        let supressions = parse_supressions(workflow.spec.supression);
-                        info!("supressions: {:?}", supressions);
-                        workflow_supressions.insert(workflow_name.clone(), supressions);
+                let supressions = parse_supressions(workflow.spec.suppressions);
+                info!("supressions: {:?}", supressions);
+                self.workflow_supressions
+                    .insert(workflow_name.clone(), supressions);
 
-                        workflow_max_in_flight.insert(workflow_name.clone(), workflow.spec.parallel.unwrap_or(1) as u8);
+                self.workflow_max_in_flight.insert(
+                    workflow_name.clone(),
+                    workflow.spec.parallel.unwrap_or(1) as u8,
+                );
 
-                        if version_changed {
-                            let now = Utc::now();
-                            let after = now + Duration::seconds(workflow.spec.debounce.unwrap_or(15) as i64);
+                if version_changed {
+                    let now = context.clock.now();
+                    let after =
+                        now + Duration::seconds(workflow.spec.debounce.unwrap_or(15) as i64);
+
+                    // 3. Replace the queued jobs of the workflow with a job for each group,
+                    // jobs that are in flight are left to finish
+                    self.queue.enqueue(
+                        &workflow_name,
+                        latest_workflow,
+                        &workflow.spec.namespaces,
+                        after,
+                    );
+
+                    publish_workflow_event(
+                        context,
+                        &context.client,
+                        &workflow_name,
+                        EventType::Normal,
+                        "Queued",
+                        format!(
+                            "Queued {} groups for version {} after {}",
+                            workflow.spec.namespaces.len(),
+                            workflow.spec.version,
+                            after.to_rfc3339()
+                        ),
+                    );
+                    context.notifier.notify(
+                        Notification::new(
+                            NotificationEvent::WorkflowStarted,
+                            &workflow_name,
+                            format!(
+                                "Queued {} groups for version {}",
+                                workflow.spec.namespaces.len(),
+                                workflow.spec.version
+                            ),
+                            context.clock.now(),
+                        )
+                        .with_version(&workflow.spec.version),
+                    );
+                }
 
-                            // 3. Remove any items from the queue that are not in-flight and have the same workflow name and have a different workflow checksum
-                            workflow_queue.retain(|x| x.should_retain(&workflow_name));
+                // Groups that stopped at a checkpoint of this version resume
+                // as soon as the workflow is loaded or applied.
+                self.resume_checkpoints(context, &workflow_name).await;
+            }
+            Action::ReconcileWorkflow(workflow_name) => {
+                context
+                    .metrics
+                    .count_with_tags("action_loop.event", 1)
+                    .with_tag("event", "reconcile_workflow")
+                    .with_tag("workflow_name", workflow_name.as_str())
+                    .send();
 
-                            // 4. Add all of the groups to the queue
-                            workflow.spec.namespaces.iter().for_each(|namespace| {
-                                workflow_queue.insert(WorkflowJob {
-                                    workflow: workflow_name.clone(),
-                                    checksum: latest_workflow,
-                                    group: namespace.clone(),
-                                    after,
-                                    in_flight: false,
-                                });
-                            });
-                        }
-                    }
-                    Action::ReconcileWorkflow(workflow_name) => {
-                        context
-                            .metrics
-                            .count_with_tags("action_loop.event", 1)
-                            .with_tag("event", "reconcile_workflow")
-                            .with_tag("workflow_name", workflow_name.as_str())
-                            .send();
+                let workflow_res = context
+                    .workflow_storage
+                    .get_workflow(workflow_name.clone(), None)
+                    .await;
+                if workflow_res.is_err() {
+                    error!("unable to get latest workflow: {:?}", action);
+                    return;
+                }
+                let workflow = workflow_res.unwrap();
 
-                        let workflow_res = context.workflow_storage.get_workflow(workflow_name.clone(), None).await;
-                        if workflow_res.is_err() {
-                            error!("unable to get latest workflow: {:?}", val);
-                            continue 'outer;
-                        }
-                        let workflow = workflow_res.unwrap();
+                let supressions = parse_supressions(workflow.spec.suppressions);
+                info!("supressions: {:?}", supressions);
+                self.workflow_supressions
+                    .insert(workflow_name.clone(), supressions);
 
-                        let supressions = parse_supressions(workflow.spec.supression);
-                        info!("supressions: {:?}", supressions);
-                        workflow_supressions.insert(workflow_name.clone(), supressions);
+                self.workflow_max_in_flight.insert(
+                    workflow_name.clone(),
+                    workflow.spec.parallel.unwrap_or(1) as u8,
+                );
 
-                        workflow_max_in_flight.insert(workflow_name.clone(), workflow.spec.parallel.unwrap_or(1) as u8);
+                self.resume_checkpoints(context, &workflow_name).await;
 
-                        // If there are any queued jobs, either in flight or waiting, for the workflow then don't do anything.
-                        let queued_workflow_jobs = workflow_queue.iter().filter(|x| x.workflow == workflow_name).count();
-                        if queued_workflow_jobs == 0 {
-                            debug!("ReconcileWorkflow not implemented");
-                        }
-                    }
+                // If there are any queued jobs, either in flight or waiting, for the workflow then don't do anything.
+                if self.queue.is_idle(&workflow_name) {
+                    debug!("ReconcileWorkflow not implemented");
                 }
             }
         }
+    }
 
-        let now = Utc::now();
-
-        let workflow_names_res = context.workflow_storage.get_workflow_names();
-        if workflow_names_res.is_err() {
-            error!("unable to get workflow names: {:?}", workflow_names_res);
-            continue 'outer;
+    // Queues the jobs that stopped at a checkpoint when the controller last
+    // shut down. Checkpoints of an older version of the workflow are dropped,
+    // the new version has been queued in their place.
+    async fn resume_checkpoints(&mut self, context: &Context, workflow_name: &str) {
+        let checkpoints = match context
+            .workflow_storage
+            .take_checkpoints(workflow_name.to_string())
+            .await
+        {
+            Ok(checkpoints) => checkpoints,
+            Err(err) => {
+                error!("unable to get checkpoints for {}: {}", workflow_name, err);
+                return;
+            }
+        };
+        if checkpoints.is_empty() {
+            return;
         }
-        let workflow_names = workflow_names_res.unwrap();
+        let latest_workflow = context
+            .workflow_storage
+            .lastest_workflow(workflow_name.to_string())
+            .await
+            .ok();
+
+        for checkpoint in checkpoints {
+            if Some(checkpoint.checksum) != latest_workflow {
+                warn!(
+                    "Dropping checkpoint of {} in {} for an older version",
+                    workflow_name, checkpoint.namespace
+                );
+                continue;
+            }
+            info!(
+                "Resuming {} in {} after {} actions",
+                workflow_name, checkpoint.namespace, checkpoint.completed
+            );
+            self.queue.resume(WorkflowJob {
+                workflow: checkpoint.workflow,
+                checksum: checkpoint.checksum,
+                group: checkpoint.namespace,
+                after: context.clock.now(),
+                completed: checkpoint.completed,
+                changes: checkpoint.changes,
+            });
+        }
+    }
 
-        'workflow_names: for workflow_name in workflow_names {
-            let work_to_do = workflow_queue
-                .iter()
-                .any(|x| x.workflow == workflow_name && !x.in_flight);
-            if !work_to_do {
+    // Marks the jobs that can start now as in flight and returns them.
+    pub(crate) fn dispatch(&mut self, context: &Context) -> Vec<WorkflowJob> {
+        let mut dispatched = vec![];
+
+        let now = context.clock.now();
+
+        'workflow_names: for workflow_name in self.queue.workflows() {
+            if self.queue.queued(&workflow_name) == 0 {
                 continue 'workflow_names;
             }
 
-            let supressions = workflow_supressions
+            let supressions = self
+                .workflow_supressions
                 .get(&workflow_name)
                 .cloned()
                 .unwrap_or(vec![]);
@@ -231,43 +576,53 @@ pub(crate) async fn action_loop(
                         .with_tag("workflow_name", &workflow_name)
                         .send();
 
+                    if self.supressed_workflows.insert(workflow_name.clone()) {
+                        publish_workflow_event(
+                            context,
+                            &context.client,
+                            &workflow_name,
+                            EventType::Normal,
+                            "Suppressed",
+                            format!("Dispatching is suppressed by {:?}", supression),
+                        );
+                        context.notifier.notify(Notification::new(
+                            NotificationEvent::SuppressionEntered,
+                            &workflow_name,
+                            format!("Dispatching is suppressed by {:?}", supression),
+                            context.clock.now(),
+                        ));
+                    }
+
                     continue 'workflow_names;
                 }
             }
+            self.supressed_workflows.remove(&workflow_name);
 
             // TODO: Get this from workflow config.
-            let max_in_flight = workflow_max_in_flight
+            let max_in_flight = self
+                .workflow_max_in_flight
                 .get(&workflow_name)
                 .map(|v| *v as usize)
                 .unwrap_or(1);
 
-            let mut in_flight_count = max_in_flight
-                - workflow_queue
-                    .clone()
-                    .iter()
-                    .filter(|x| x.workflow == workflow_name && x.in_flight)
-                    .count();
-
-            'dispatch_queue: while in_flight_count > 0 {
-                in_flight_count -= 1;
-
-                let next_job_maybe = workflow_queue
-                    .clone()
-                    .into_iter()
-                    .find(|x| x.workflow == workflow_name && !x.in_flight && x.after < now);
-                if next_job_maybe.is_none() {
-                    break 'dispatch_queue;
-                }
-                let next_job = next_job_maybe.unwrap();
-                workflow_queue.borrow_mut().remove(&next_job);
+            let mut available = max_in_flight.saturating_sub(self.queue.in_flight(&workflow_name));
+
+            'dispatch_queue: while available > 0 {
+                available -= 1;
+
+                let next_job = match self.queue.next(&workflow_name, now) {
+                    Some(next_job) => next_job,
+                    None => break 'dispatch_queue,
+                };
 
-                workflow_queue.borrow_mut().insert(WorkflowJob {
-                    workflow: next_job.workflow.clone(),
-                    checksum: next_job.checksum,
-                    group: next_job.group.clone(),
-                    after: next_job.after,
-                    in_flight: true,
-                });
+                update_workflow_status(
+                    &context.client,
+                    &workflow_name,
+                    WorkflowStatus {
+                        in_flight: Some(self.queue.in_flight(&workflow_name) as u32),
+                        ..Default::default()
+                    },
+                );
 
                 info!(
                     "dispatching job: {} {} {}",
@@ -280,25 +635,191 @@ pub(crate) async fn action_loop(
                     .count_with_tags("action_loop.dispatch", 1)
                     .with_tag("workflow_name", next_job.workflow.as_str())
                     .send();
+                publish_workflow_event(
+                    context,
+                    &context.client,
+                    &next_job.workflow,
+                    EventType::Normal,
+                    "Dispatched",
+                    format!("Dispatched group {}", next_job.group),
+                );
+
+                dispatched.push(next_job);
+            }
+        }
+
+        dispatched
+    }
+}
+
+// Builds the list of actions that a workflow job performs in a group.
+pub(crate) fn build_work_queue(workflow: &Workflow, group: &str) -> Vec<WorkflowAction> {
+    let mut work_queue: Vec<WorkflowAction> = vec![WorkflowAction::Started()];
 
-                {
-                    let context = context.clone();
-                    let next_job = next_job.clone();
-                    tokio::spawn(async move {
-                        if let Err(err) = action_workflow_updated(context, next_job).await {
-                            error!(cause = ?err, "action_workflow_updated error");
+    // Nick: My thinking is that it's easier to create a big list of everything
+    // that needs to be done for a workflow in the context of a group
+    // (namespace) up front. The alternative would be to parse the workflow
+    // spec every loop to see what's next. The added bonus of doing it this way
+    // is that I can also populate history as each thing is completed.
+    let template_values = [
+        ("version", workflow.spec.version.as_str()),
+        ("namespace", group),
+    ];
+    for step in &workflow.spec.steps {
+        for action in &step.actions {
+            match action {
+                WorkflowStepAction::UpdateDeployment { targets } => {
+                    for target in targets {
+                        work_queue.push(WorkflowAction::UpdateDeployment(
+                            target.name.clone(),
+                            target
+                                .containers
+                                .iter()
+                                .map(|container| (container.clone(), workflow.spec.version.clone()))
+                                .collect(),
+                        ));
+                    }
+                    for target in targets {
+                        work_queue.push(WorkflowAction::WaitDeploymentReady(target.name.clone()));
+                    }
+                }
+                WorkflowStepAction::UpdateEnv { targets } => {
+                    for target in targets {
+                        work_queue.push(WorkflowAction::UpdateEnv(
+                            target.name.clone(),
+                            target.containers.clone(),
+                            target
+                                .env
+                                .iter()
+                                .map(|var| {
+                                    (
+                                        var.name.clone(),
+                                        var.value
+                                            .as_ref()
+                                            .map(|value| render(value, &template_values)),
+                                    )
+                                })
+                                .collect(),
+                        ));
+                    }
+                    for target in targets {
+                        work_queue.push(WorkflowAction::WaitDeploymentReady(target.name.clone()));
+                    }
+                }
+                WorkflowStepAction::UpdateConfig { targets } => {
+                    for target in targets {
+                        let data: BTreeMap<String, String> = target
+                            .data
+                            .iter()
+                            .map(|(key, value)| (key.clone(), render(value, &template_values)))
+                            .collect();
+
+                        // The checksum of the rendered data is used as the
+                        // annotation value so that deployments are only
+                        // restarted when the config actually changes.
+                        let mut hasher = FnvHasher::default();
+                        for (key, value) in data.iter() {
+                            hasher.write(format!("{key}={value}").as_bytes());
                         }
-                    })
-                };
+                        let checksum = hasher.finish().to_string();
+
+                        work_queue.push(WorkflowAction::UpdateConfig(target.name.clone(), data));
+                        for deployment in target.deployments.iter() {
+                            work_queue.push(WorkflowAction::AnnotateDeployment(
+                                deployment.clone(),
+                                format!("config.workflow-deploy.ngerakines.me/{}", target.name),
+                                checksum.clone(),
+                            ));
+                        }
+                    }
+                    for target in targets {
+                        for deployment in target.deployments.iter() {
+                            work_queue
+                                .push(WorkflowAction::WaitDeploymentReady(deployment.clone()));
+                        }
+                    }
+                }
+                WorkflowStepAction::RestartDeployment { targets } => {
+                    for target in targets {
+                        work_queue.push(WorkflowAction::RestartDeployment(target.name.clone()));
+                    }
+                    for target in targets {
+                        work_queue.push(WorkflowAction::WaitDeploymentReady(target.name.clone()));
+                    }
+                }
+                WorkflowStepAction::Scale { targets } => {
+                    for target in targets {
+                        work_queue.push(WorkflowAction::ScaleDeployment(
+                            target.name.clone(),
+                            target.replicas.clone(),
+                        ));
+                    }
+                    for target in targets {
+                        work_queue.push(WorkflowAction::WaitDeploymentScaled(target.name.clone()));
+                    }
+                }
+                WorkflowStepAction::RunJob { targets } => {
+                    for target in targets {
+                        work_queue.push(WorkflowAction::RunJob(JobTarget {
+                            name: target.name.clone(),
+                            spec: target
+                                .job
+                                .as_ref()
+                                .map(|job| render(&job.to_string(), &template_values)),
+                            template: target.template.clone(),
+                            containers: target
+                                .containers
+                                .iter()
+                                .map(|container| (container.clone(), workflow.spec.version.clone()))
+                                .collect(),
+                        }));
+                        work_queue.push(WorkflowAction::WaitJobFinished(
+                            target.name.clone(),
+                            target.timeout_seconds.unwrap_or(600),
+                            target.cleanup.unwrap_or(false),
+                        ));
+                    }
+                }
+                WorkflowStepAction::HttpCheck { targets } => {
+                    for target in targets {
+                        work_queue.push(WorkflowAction::HttpCheck(HttpCheckTarget {
+                            name: target.name.clone(),
+                            url: render(&target.url, &template_values),
+                            status: target.status.clone().unwrap_or(vec![200]),
+                            body: target.body.clone(),
+                            interval_seconds: target.interval_seconds.unwrap_or(5),
+                            timeout_seconds: target.timeout_seconds.unwrap_or(120),
+                            successes: target.successes.unwrap_or(1),
+                        }));
+                    }
+                }
+                WorkflowStepAction::Unknown { action, .. } => {
+                    warn!("skipping unknown action {}", action);
+                }
             }
         }
+        for analysis in step.analysis.iter() {
+            work_queue.push(WorkflowAction::Analysis(AnalysisTarget {
+                name: analysis.name.clone(),
+                query: render(&analysis.query, &template_values),
+                min: analysis.min,
+                max: analysis.max,
+                window_seconds: analysis.window_seconds.unwrap_or(300),
+                interval_seconds: analysis.interval_seconds.unwrap_or(30),
+            }));
+        }
     }
 
-    info!("action loop ended");
-    Ok(())
+    work_queue
 }
 
-async fn action_workflow_updated(context: Context, workflow_job: WorkflowJob) -> Result<()> {
+// Runs the job of a group. It returns none when the job was stopped by
+// shutdown before it finished, after recording where it stopped.
+async fn action_workflow_updated(
+    context: Context,
+    workflow_job: WorkflowJob,
+    stop: StopSignal,
+) -> Result<Option<JobOutcome>> {
     info!("action_workflow_updated started");
     info!(
         "processing job: {} {} {}",
@@ -311,38 +832,32 @@ async fn action_workflow_updated(context: Context, workflow_job: WorkflowJob) ->
         .workflow_storage
         .get_workflow(workflow_job.workflow.clone(), Some(workflow_job.checksum))
         .await?;
+    let triggered_by = triggered_by(&workflow);
+
+    // In dry-run mode every change is sent as a server-side dry run so that it
+    // is validated without being persisted, waits are skipped, and the changes
+    // are recorded as a plan.
+    let dry_run = context.settings.dry_run
+        || annotation_true(
+            workflow.annotations(),
+            "workflow-deploy.ngerakines.me/dry-run",
+        );
+    let patch_params = if dry_run {
+        PatchParams::default().dry_run()
+    } else {
+        PatchParams::default()
+    };
+    let post_params = PostParams {
+        dry_run,
+        ..Default::default()
+    };
+    let mut plan: Vec<PlanStep> = vec![];
 
     let one_second = Duration::seconds(1).to_std().unwrap();
 
-    let sleeper = sleep(one_second);
-    tokio::pin!(sleeper);
-
-    let mut work_queue: Vec<WorkflowAction> = vec![WorkflowAction::Started()];
+    let mut sleeper = context.clock.sleep(one_second);
 
-    // Nick: My thinking is that it's easier to create a big list of everything
-    // that needs to be done for a workflow in the context of a group
-    // (namespace) up front. The alternative would be to parse the workflow
-    // spec every loop to see what's next. The added bonus of doing it this way
-    // is that I can also populate history as each thing is completed.
-    for step in workflow.spec.steps {
-        for action in step.actions {
-            if action.action == *"update_deployment" {
-                for target in &action.targets {
-                    work_queue.push(WorkflowAction::UpdateDeployment(
-                        target.name.clone(),
-                        target
-                            .containers
-                            .iter()
-                            .map(|container| (container.clone(), workflow.spec.version.clone()))
-                            .collect(),
-                    ));
-                }
-                for target in &action.targets {
-                    work_queue.push(WorkflowAction::WaitDeploymentReady(target.name.clone()));
-                }
-            }
-        }
-    }
+    let mut work_queue = build_work_queue(&workflow, &workflow_job.group);
 
     context
         .metrics
@@ -350,23 +865,101 @@ async fn action_workflow_updated(context: Context, workflow_job: WorkflowJob) ->
         .with_tag("workflow_name", workflow_job.workflow.as_str())
         .send();
 
+    let started_at = context.clock.now();
     let mut history: Vec<(WorkflowAction, DateTime<Utc>)> =
-        vec![(WorkflowAction::Started(), Utc::now())];
+        vec![(WorkflowAction::Started(), started_at)];
+
+    // A job that resumes from a checkpoint skips the actions that it already
+    // completed. They are added to the history as if they had just completed,
+    // so that waits are measured from when the job resumed.
+    let resumed_from = workflow_job.completed.min(work_queue.len());
+    history.extend(
+        work_queue
+            .drain(..resumed_from)
+            .filter(|action| *action != WorkflowAction::Started())
+            .map(|action| (action, started_at)),
+    );
+    let remaining = work_queue.len();
 
-    let client = Client::try_default()
-        .await
-        .map_err(anyhow::Error::msg)
-        .unwrap();
+    let client = context.client.clone();
 
     let deployment_client: Api<Deployment> =
         Api::namespaced(client.clone(), &workflow_job.group.clone());
+    let config_map_client: Api<ConfigMap> =
+        Api::namespaced(client.clone(), &workflow_job.group.clone());
+    let job_client: Api<Job> = Api::namespaced(client.clone(), &workflow_job.group.clone());
+    let cron_job_client: Api<CronJob> =
+        Api::namespaced(client.clone(), &workflow_job.group.clone());
 
     info!("Starting work loop with queue: {:?}", work_queue);
 
     let mut everything_ok = true;
 
+    // What the job changed before it last stopped.
+    let JobChanges {
+        mut previous_replicas,
+        mut created_jobs,
+        mut generations,
+        mut images,
+        mut revisions,
+    } = workflow_job.changes.clone();
+
+    let mut http_checks: HashMap<String, HttpCheckProgress> = HashMap::new();
+    let mut analyses: HashMap<String, AnalysisProgress> = HashMap::new();
+
+    // Pods of deployments that are being waited on are watched so that crash
+    // loops and image pull errors fail the group without waiting for the
+    // readiness timeout.
+    let mut pod_watches: HashMap<String, PodFailureWatch> = HashMap::new();
+
+    let mut failure_reason: Option<String> = None;
+
+    // What the job has changed so far.
+    macro_rules! changes {
+        () => {
+            JobChanges {
+                previous_replicas: previous_replicas.clone(),
+                created_jobs: created_jobs.clone(),
+                generations: generations.clone(),
+                images: images.clone(),
+                revisions: revisions.clone(),
+            }
+        };
+    }
+    let mut completed = resumed_from;
+
     'working: loop {
+        // The supervisor checkpoints the job from its last completed action if
+        // the job has to be aborted on shutdown.
+        if resumed_from + remaining - work_queue.len() != completed {
+            completed = resumed_from + remaining - work_queue.len();
+            stop.progress(completed, changes!());
+        }
+
         tokio::select! {
+            () = stop.stopped() => {
+                // The job stops between actions, and resumes from the action it
+                // stopped at when the controller starts again.
+                let checkpoint = JobCheckpoint {
+                    workflow: workflow_job.workflow.clone(),
+                    checksum: workflow_job.checksum,
+                    namespace: workflow_job.group.clone(),
+                    completed: resumed_from + remaining - work_queue.len(),
+                    changes: changes!(),
+                    stopped_at: context.clock.now(),
+                };
+                info!("Stopping job for {} in {} at {:?}", workflow_job.workflow, workflow_job.group, work_queue.first());
+                record_checkpoint(&context, checkpoint).await;
+                return Ok(None);
+            }
+            () = stop.expired() => {
+                // A group that runs past its deadline fails like any other, so
+                // that its changes are rolled back and it is recorded.
+                error!("{} job for {} did not finish within the group deadline", workflow_job.workflow, workflow_job.group);
+                failure_reason = Some(format!("group did not finish within {} seconds", context.settings.jobs.deadline_seconds));
+                everything_ok = false;
+                break 'working;
+            }
             () = &mut sleeper => {
 
                 if work_queue.is_empty() {
@@ -379,7 +972,7 @@ async fn action_workflow_updated(context: Context, workflow_job: WorkflowJob) ->
                     break 'working;
                 }
 
-                let now = Utc::now();
+                let now = context.clock.now();
 
                 context
                     .metrics
@@ -387,6 +980,14 @@ async fn action_workflow_updated(context: Context, workflow_job: WorkflowJob) ->
                     .with_tag("workflow_name", workflow_job.workflow.as_str())
                     .send();
 
+                if dry_run && work_queue[0].observes() {
+                    info!("action_workflow_updated dry run skipping {} {}", work_queue[0].name(), work_queue[0].target());
+                    plan.push(plan_step::<()>(&work_queue[0], None));
+                    work_queue.remove(0);
+                    sleeper = context.clock.sleep(one_second);
+                    continue 'working;
+                }
+
                 match work_queue[0] {
                     WorkflowAction::Started() => {
                         context
@@ -408,21 +1009,179 @@ async fn action_workflow_updated(context: Context, workflow_job: WorkflowJob) ->
 
                         info!("action_workflow_updated UpdateDeployment: {}", name);
 
-                        // TODO: Update the deployment.
-                        let deployment = deployment_client.get_opt(name).await;
-                        if let Err(err) = deployment {
+                        let deployment = match get_deployment(&context, &deployment_client, &workflow_job, name).await {
+                            Ok(deployment) => deployment,
+                            Err(reason) => {
+                                failure_reason = Some(reason);
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        };
+
+                        let json_patch = image_patch(&deployment, containers);
+                        plan.push(plan_step(&work_queue[0], Some(&json_patch)));
+                        match patch_deployment(&context, &deployment_client, &workflow_job, name, &patch_params, &Patch::Json::<()>(json_patch)).await {
+                            Ok(patched) => {
+                                generations.insert(name.clone(), patched.metadata.generation.unwrap_or_default());
+                                revisions.entry(name.clone()).or_insert_with(|| deployment_revision(&patched).unwrap_or_default());
+                            }
+                            Err(reason) => {
+                                failure_reason = Some(reason);
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        }
+                        let changes = image_changes(&deployment, containers);
+                        images.insert(name.clone(), (
+                            changes.iter().map(|x| x.1.clone()).collect::<Vec<String>>().join(","),
+                            changes.iter().map(|x| x.2.clone()).collect::<Vec<String>>().join(","),
+                        ));
+                        if !dry_run {
+                            publish_deployment_event(&client, &workflow_job.group, name, EventType::Normal, "ImageUpdated", format!("Image updated by workflow {} version {}", workflow_job.workflow, workflow.spec.version));
+                        }
+
+                        history.push((work_queue[0].clone(), now));
+                        work_queue.remove(0);
+                    }
+                    WorkflowAction::UpdateEnv(ref name, ref containers, ref env) => {
+                        context
+                            .metrics
+                            .count_with_tags("workflow_loop.event", 1)
+                            .with_tag("workflow_name", workflow_job.workflow.as_str())
+                            .with_tag("event_name", "update_env")
+                            .send();
+
+                        info!("action_workflow_updated UpdateEnv: {}", name);
+
+                        let deployment = match get_deployment(&context, &deployment_client, &workflow_job, name).await {
+                            Ok(deployment) => deployment,
+                            Err(reason) => {
+                                failure_reason = Some(reason);
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        };
+
+                        let json_patch = env_patch(&deployment, containers, env);
+                        plan.push(plan_step(&work_queue[0], Some(&json_patch)));
+                        match patch_deployment(&context, &deployment_client, &workflow_job, name, &patch_params, &Patch::Json::<()>(json_patch)).await {
+                            Ok(patched) => {
+                                generations.insert(name.clone(), patched.metadata.generation.unwrap_or_default());
+                                revisions.entry(name.clone()).or_insert_with(|| deployment_revision(&patched).unwrap_or_default());
+                            }
+                            Err(reason) => {
+                                failure_reason = Some(reason);
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        }
+
+                        history.push((work_queue[0].clone(), now));
+                        work_queue.remove(0);
+                    }
+                    WorkflowAction::UpdateConfig(ref name, ref data) => {
+                        context
+                            .metrics
+                            .count_with_tags("workflow_loop.event", 1)
+                            .with_tag("workflow_name", workflow_job.workflow.as_str())
+                            .with_tag("event_name", "update_config")
+                            .send();
+
+                        info!("action_workflow_updated UpdateConfig: {}", name);
+
+                        let config_patch = config_map_data_patch(data);
+                        plan.push(plan_step(&work_queue[0], Some(&config_patch)));
+                        let patch_res = config_map_client
+                            .patch(
+                                name,
+                                &patch_params,
+                                &Patch::Merge(config_patch),
+                            )
+                            .await;
+                        if let Err(err) = patch_res {
                             context
                                 .metrics
-                                .count_with_tags("workflow_loop.deployment_not_found", 1)
+                                .count_with_tags("workflow_loop.config_map_patch_failed", 1)
                                 .with_tag("workflow_name", workflow_job.workflow.as_str())
-                                .with_tag("deployment_name", name)
+                                .with_tag("config_map_name", name)
                                 .send();
-                            error!("UpdateDeployment unable to get deployment {}: {}", name, err);
+
+                            error!("UpdateConfig patching config map {} failed: {}", name, err);
+                            failure_reason = Some(format!("config map {}: patch failed: {}", name, err));
                             everything_ok = false;
                             break 'working;
                         }
-                        let deployment = deployment.unwrap();
-                        if deployment.is_none() {
+
+                        history.push((work_queue[0].clone(), now));
+                        work_queue.remove(0);
+                    }
+                    WorkflowAction::AnnotateDeployment(ref name, ref key, ref value) => {
+                        context
+                            .metrics
+                            .count_with_tags("workflow_loop.event", 1)
+                            .with_tag("workflow_name", workflow_job.workflow.as_str())
+                            .with_tag("event_name", "annotate_deployment")
+                            .send();
+
+                        info!("action_workflow_updated AnnotateDeployment: {} {}={}", name, key, value);
+
+                        let annotation_patch = pod_template_annotation_patch(key, value);
+                        plan.push(plan_step(&work_queue[0], Some(&annotation_patch)));
+                        match patch_deployment(&context, &deployment_client, &workflow_job, name, &patch_params, &Patch::Merge(annotation_patch)).await {
+                            Ok(patched) => {
+                                generations.insert(name.clone(), patched.metadata.generation.unwrap_or_default());
+                                revisions.entry(name.clone()).or_insert_with(|| deployment_revision(&patched).unwrap_or_default());
+                            }
+                            Err(reason) => {
+                                failure_reason = Some(reason);
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        }
+
+                        history.push((work_queue[0].clone(), now));
+                        work_queue.remove(0);
+                    }
+                    WorkflowAction::RestartDeployment(ref name) => {
+                        context
+                            .metrics
+                            .count_with_tags("workflow_loop.event", 1)
+                            .with_tag("workflow_name", workflow_job.workflow.as_str())
+                            .with_tag("event_name", "restart_deployment")
+                            .send();
+
+                        info!("action_workflow_updated RestartDeployment: {}", name);
+
+                        // This is the same annotation that `kubectl rollout restart` sets.
+                        let restart_patch = pod_template_annotation_patch("kubectl.kubernetes.io/restartedAt", &now.to_rfc3339());
+                        plan.push(plan_step(&work_queue[0], Some(&restart_patch)));
+                        match patch_deployment(&context, &deployment_client, &workflow_job, name, &patch_params, &Patch::Merge(restart_patch)).await {
+                            Ok(patched) => {
+                                generations.insert(name.clone(), patched.metadata.generation.unwrap_or_default());
+                                revisions.entry(name.clone()).or_insert_with(|| deployment_revision(&patched).unwrap_or_default());
+                            }
+                            Err(reason) => {
+                                failure_reason = Some(reason);
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        }
+
+                        history.push((work_queue[0].clone(), now));
+                        work_queue.remove(0);
+                    }
+                    WorkflowAction::WaitDeploymentReady(ref name) => {
+                        context
+                            .metrics
+                            .count_with_tags("workflow_loop.event", 1)
+                            .with_tag("workflow_name", workflow_job.workflow.as_str())
+                            .with_tag("event_name", "wait_deployment_ready")
+                            .send();
+
+                        info!("action_workflow_updated WaitDeploymentReady: {}", name);
+
+                        let last_deployed_at = history.iter().rev().find(|x| x.0.updated_deployment() == Some(name)).map(|x| x.1);
+                        if last_deployed_at.is_none() {
                             context
                                 .metrics
                                 .count_with_tags("workflow_loop.deployment_not_found", 1)
@@ -430,105 +1189,375 @@ async fn action_workflow_updated(context: Context, workflow_job: WorkflowJob) ->
                                 .with_tag("deployment_name", name)
                                 .send();
 
-                            error!("UpdateDeployment unable to get deployment {}: not found", name);
+                            error!("WaitDeploymentReady failed: No deployment found for {}", name);
+                            failure_reason = Some(format!("deployment {}: not updated before waiting for it", name));
                             everything_ok = false;
                             break 'working;
                         }
+                        let last_deployed_at = last_deployed_at.unwrap();
 
-                        let mut json_patch = json_patch::Patch(vec![]);
-                        for (index, container) in deployment.unwrap_or_default().spec.unwrap_or_default().template.spec.unwrap_or_default().containers.iter().enumerate() {
-                            info!("container: {}", container.name);
-                            if let Some(version) = containers.iter().find(|x| x.0 == container.name).map(|x| x.1.clone()) {
+                        // 1. Fail early if pods of the new revision are failing
+                        if !pod_watches.contains_key(name) {
+                            if let Ok(Some(deployment)) = deployment_client.get_opt(name).await {
+                                // Until the deployment controller has created the replica set of the
+                                // new revision, the selector would match the pods of the old one.
+                                let rolling_out = match (generations.get(name), revisions.get(name)) {
+                                    (Some(generation), Some(revision)) => is_new_revision(&deployment, *generation, revision),
+                                    _ => false,
+                                };
+                                let selector = if rolling_out {
+                                    revision_selector(client.clone(), &deployment).await
+                                } else {
+                                    Err(anyhow!("the new revision has not been observed"))
+                                };
+                                match selector {
+                                    Ok(selector) => {
+                                        pod_watches.insert(name.clone(), PodFailureWatch::start(client.clone(), &workflow_job.group, &selector));
+                                    }
+                                    Err(err) => debug!("Unable to watch pods of deployment {} yet: {}", name, err),
+                                }
+                            }
+                        }
+                        if let Some(pod_failure) = pod_watches.get(name).and_then(|pod_watch| pod_watch.failure()) {
+                            context
+                                .metrics
+                                .count_with_tags("workflow_loop.pod_failure", 1)
+                                .with_tag("workflow_name", workflow_job.workflow.as_str())
+                                .with_tag("deployment_name", name)
+                                .with_tag("reason", pod_failure.reason.as_str())
+                                .send();
 
-                                let container_image = replace_last(container.image.clone(), ':', &version);
+                            error!("WaitDeploymentReady failed: Deployment {} has failing pods: {}", name, pod_failure.message);
+                            publish_deployment_event(&client, &workflow_job.group, name, EventType::Warning, "PodFailure", format!("Workflow {} found failing pods: {}", workflow_job.workflow, pod_failure.message));
+                            failure_reason = Some(format!("deployment {}: {}", name, pod_failure.message));
+                            everything_ok = false;
+                            break 'working;
+                        }
 
-                                if container_image.is_some() {
-                                    json_patch.0.push(json_patch::PatchOperation::Replace(
-                                        json_patch::ReplaceOperation{
-                                            path: format!("/spec/template/spec/containers/{index}/image"),
-                                            value:serde_json::to_value(container_image.unwrap()).unwrap()
-                                        },
-                                    ));
+                        // 2. Get the state of the deployment. It is only ready once the
+                        // generation created by this job has rolled out.
+                        let mut changes = context.readiness.subscribe(&workflow_job.group, name);
+                        let generation = generations.get(name).copied().unwrap_or_default();
+                        let deployment_is_ready = changes.borrow_and_update().map(|state| state.ready_at(generation)).unwrap_or_default();
+
+                        // 3. Wait for the deployment to change if it is not ready and we have not reached the max wait time
+                        let deadline = last_deployed_at + Duration::seconds(90);
+                        if !deployment_is_ready && now < deadline {
+                            info!("Waiting for deployment {} to become ready", &name);
+                            let timeout = context.clock.sleep((deadline - now).to_std().unwrap_or_default());
+                            // Until the pods can be watched, check again every few seconds.
+                            let pod_failed: BoxFuture<'static, ()> = match pod_watches.get(name) {
+                                Some(pod_watch) => Box::pin(pod_watch.failed()),
+                                None => context.clock.sleep(one_second * 5),
+                            };
+                            sleeper = Box::pin(async move {
+                                tokio::select! {
+                                    _ = changes.changed() => {},
+                                    () = pod_failed => {},
+                                    () = timeout => {},
                                 }
-                            }
+                            });
+                            continue 'working;
                         }
 
-                        let patch_res = deployment_client
-                        .patch(
-                            name,
-                            &PatchParams::default(),
-                            &Patch::Json::<()>(json_patch),
-                        )
-                        .await;
-                        if let Err(err) = patch_res {
+                        // 4. Error if the status is not ready and we have passed the max wait time
+                        if !deployment_is_ready {
                             context
                                 .metrics
-                                .count_with_tags("workflow_loop.deployment_patch_failed", 1)
+                                .count_with_tags("workflow_loop.deployment_timeout", 1)
                                 .with_tag("workflow_name", workflow_job.workflow.as_str())
                                 .with_tag("deployment_name", name)
                                 .send();
 
-                            error!("UpdateDeployment patching deployment {} failed: {}", name, err);
+                            error!("WaitDeploymentReady failed: Deployment {} did not become ready within wait period", name);
+                            publish_deployment_event(&client, &workflow_job.group, name, EventType::Warning, "ReadinessTimeout", format!("Deployment did not become ready for workflow {} version {} within wait period", workflow_job.workflow, workflow.spec.version));
+                            failure_reason = Some(format!("deployment {}: not ready within wait period", name));
                             everything_ok = false;
                             break 'working;
                         }
 
+                        pod_watches.remove(name);
                         history.push((work_queue[0].clone(), now));
                         work_queue.remove(0);
                     }
-                    WorkflowAction::WaitDeploymentReady(ref name) => {
+                    WorkflowAction::ScaleDeployment(ref name, ref replicas) => {
                         context
                             .metrics
                             .count_with_tags("workflow_loop.event", 1)
                             .with_tag("workflow_name", workflow_job.workflow.as_str())
-                            .with_tag("event_name", "wait_deployment_ready")
+                            .with_tag("event_name", "scale_deployment")
                             .send();
 
-                        info!("action_workflow_updated WaitDeploymentReady: {}", name);
+                        let replicas = match ReplicaChange::parse(replicas) {
+                            Some(replicas) => replicas,
+                            None => {
+                                error!("ScaleDeployment failed: invalid replicas {:?} for {}", replicas, name);
+                                failure_reason = Some(format!("deployment {}: invalid replicas {:?}", name, replicas));
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        };
+
+                        let deployment = match get_deployment(&context, &deployment_client, &workflow_job, name).await {
+                            Ok(deployment) => deployment,
+                            Err(reason) => {
+                                failure_reason = Some(reason);
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        };
+
+                        let current = deployment.spec.and_then(|spec| spec.replicas).unwrap_or(1);
+                        let desired = replicas.apply(current);
+                        info!("action_workflow_updated ScaleDeployment: {} {} -> {}", name, current, desired);
+
+                        plan.push(plan_step(&work_queue[0], Some(&scale_patch(desired))));
+                        if let Err(reason) = patch_deployment(&context, &deployment_client, &workflow_job, name, &patch_params, &Patch::Merge(scale_patch(desired))).await {
+                            failure_reason = Some(reason);
+                            everything_ok = false;
+                            break 'working;
+                        }
+                        previous_replicas.entry(name.clone()).or_insert(current);
+
+                        history.push((work_queue[0].clone(), now));
+                        work_queue.remove(0);
+                    }
+                    WorkflowAction::WaitDeploymentScaled(ref name) => {
+                        context
+                            .metrics
+                            .count_with_tags("workflow_loop.event", 1)
+                            .with_tag("workflow_name", workflow_job.workflow.as_str())
+                            .with_tag("event_name", "wait_deployment_scaled")
+                            .send();
+
+                        info!("action_workflow_updated WaitDeploymentScaled: {}", name);
+
+                        let last_scaled_at = history.iter().rev().find(|x| matches!(x.0, WorkflowAction::ScaleDeployment(ref scaled_name, _) if scaled_name == name)).map(|x| x.1);
+                        let last_scaled_at = match last_scaled_at {
+                            Some(last_scaled_at) => last_scaled_at,
+                            None => {
+                                error!("WaitDeploymentScaled failed: No scale found for {}", name);
+                                failure_reason = Some(format!("deployment {}: not scaled before waiting for it", name));
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        };
+
+                        let deployment = match get_deployment(&context, &deployment_client, &workflow_job, name).await {
+                            Ok(deployment) => deployment,
+                            Err(reason) => {
+                                failure_reason = Some(reason);
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        };
+
+                        if !deployment_scaled(&deployment) {
+                            if now < last_scaled_at + Duration::seconds(90) {
+                                info!("Waiting for deployment {} to finish scaling", &name);
+                                sleeper = context.clock.sleep(one_second);
+                                continue 'working;
+                            }
 
-                        let last_deployed_at = history.iter().rev().find(|x| match x.0 { WorkflowAction::UpdateDeployment(ref update_deployment_name, _) => update_deployment_name == name, _ => false }).map(|x| x.1);
-                        if last_deployed_at.is_none() {
                             context
                                 .metrics
-                                .count_with_tags("workflow_loop.deployment_not_found", 1)
+                                .count_with_tags("workflow_loop.deployment_timeout", 1)
                                 .with_tag("workflow_name", workflow_job.workflow.as_str())
                                 .with_tag("deployment_name", name)
                                 .send();
 
-                            error!("WaitDeploymentReady failed: No deployment found for {}", name);
+                            error!("WaitDeploymentScaled failed: Deployment {} did not finish scaling within wait period", name);
+                            failure_reason = Some(format!("deployment {}: not scaled within wait period", name));
                             everything_ok = false;
                             break 'working;
                         }
-                        let last_deployed_at = last_deployed_at.unwrap();
 
-                        // 1. If we aren't ready to wait yet then continue
-                        if now < last_deployed_at + Duration::seconds(5) {
-                            info!("Waiting for more time to pass after updating deployment {}", &name);
-                            sleeper.as_mut().reset(Instant::now() + one_second);
-                            continue 'working;
+                        history.push((work_queue[0].clone(), now));
+                        work_queue.remove(0);
+                    }
+                    WorkflowAction::RunJob(ref target) => {
+                        context
+                            .metrics
+                            .count_with_tags("workflow_loop.event", 1)
+                            .with_tag("workflow_name", workflow_job.workflow.as_str())
+                            .with_tag("event_name", "run_job")
+                            .send();
+
+                        info!("action_workflow_updated RunJob: {}", target.name);
+
+                        let job_spec = match get_job_spec(&cron_job_client, target).await {
+                            Ok(job_spec) => job_spec,
+                            Err(err) => {
+                                context
+                                    .metrics
+                                    .count_with_tags("workflow_loop.job_failed", 1)
+                                    .with_tag("workflow_name", workflow_job.workflow.as_str())
+                                    .with_tag("job_name", target.name.as_str())
+                                    .send();
+
+                                error!("RunJob unable to get job spec for {}: {}", target.name, err);
+                                failure_reason = Some(format!("job {}: unable to get job spec: {}", target.name, err));
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        };
+
+                        let job = build_job(&target.name, &workflow_job.workflow, job_spec, &target.containers, &workflow.spec.version);
+                        plan.push(plan_step(&work_queue[0], Some(&job)));
+                        let created_job = match job_client.create(&post_params, &job).await {
+                            Ok(created_job) => created_job,
+                            Err(err) => {
+                                context
+                                    .metrics
+                                    .count_with_tags("workflow_loop.job_failed", 1)
+                                    .with_tag("workflow_name", workflow_job.workflow.as_str())
+                                    .with_tag("job_name", target.name.as_str())
+                                    .send();
+
+                                error!("RunJob creating job {} failed: {}", target.name, err);
+                                failure_reason = Some(format!("job {}: unable to create: {}", target.name, err));
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        };
+                        info!("created job {} for {}", created_job.name_any(), target.name);
+                        created_jobs.insert(target.name.clone(), created_job.name_any());
+
+                        history.push((work_queue[0].clone(), now));
+                        work_queue.remove(0);
+                    }
+                    WorkflowAction::WaitJobFinished(ref name, timeout_seconds, cleanup) => {
+                        context
+                            .metrics
+                            .count_with_tags("workflow_loop.event", 1)
+                            .with_tag("workflow_name", workflow_job.workflow.as_str())
+                            .with_tag("event_name", "wait_job_finished")
+                            .send();
+
+                        info!("action_workflow_updated WaitJobFinished: {}", name);
+
+                        let started_at = history.iter().rev().find(|x| matches!(x.0, WorkflowAction::RunJob(ref target) if &target.name == name)).map(|x| x.1);
+                        let (job_name, started_at) = match (created_jobs.get(name), started_at) {
+                            (Some(job_name), Some(started_at)) => (job_name.clone(), started_at),
+                            _ => {
+                                error!("WaitJobFinished failed: No job found for {}", name);
+                                failure_reason = Some(format!("job {}: not created before waiting for it", name));
+                                everything_ok = false;
+                                break 'working;
+                            }
+                        };
+
+                        // The job watcher publishes whether the job completed or failed once it has finished.
+                        let mut changes = context.finished_jobs.subscribe(&workflow_job.group, &job_name);
+                        let finished = *changes.borrow_and_update();
+
+                        match finished {
+                            Some(true) => {
+                                if cleanup {
+                                    if let Err(err) = job_client.delete(&job_name, &DeleteParams::background()).await {
+                                        warn!("WaitJobFinished unable to delete job {}: {}", job_name, err);
+                                    }
+                                }
+                            }
+                            Some(false) => {
+                                context
+                                    .metrics
+                                    .count_with_tags("workflow_loop.job_failed", 1)
+                                    .with_tag("workflow_name", workflow_job.workflow.as_str())
+                                    .with_tag("job_name", name.as_str())
+                                    .send();
+
+                                error!("WaitJobFinished failed: Job {} failed", job_name);
+                                failure_reason = Some(format!("job {}: failed", name));
+                                everything_ok = false;
+                                break 'working;
+                            }
+                            None if now < started_at + Duration::seconds(timeout_seconds as i64) => {
+                                info!("Waiting for job {} to finish", &job_name);
+                                let deadline = started_at + Duration::seconds(timeout_seconds as i64);
+                                let timeout = context.clock.sleep((deadline - now).to_std().unwrap_or_default());
+                                sleeper = Box::pin(async move {
+                                    tokio::select! {
+                                        _ = changes.changed() => {},
+                                        () = timeout => {},
+                                    }
+                                });
+                                continue 'working;
+                            }
+                            None => {
+                                context
+                                    .metrics
+                                    .count_with_tags("workflow_loop.job_timeout", 1)
+                                    .with_tag("workflow_name", workflow_job.workflow.as_str())
+                                    .with_tag("job_name", name.as_str())
+                                    .send();
+
+                                error!("WaitJobFinished failed: Job {} did not finish within {} seconds", job_name, timeout_seconds);
+                                failure_reason = Some(format!("job {}: not finished within {} seconds", name, timeout_seconds));
+                                everything_ok = false;
+                                break 'working;
+                            }
                         }
 
-                        // 2. Get the status of the deployment
-                        let deployment_is_ready = context.workflow_storage.is_resource_ready(workflow_job.workflow.clone(), "apps/v1;Deployment".to_string(), name.to_string());
+                        history.push((work_queue[0].clone(), now));
+                        work_queue.remove(0);
+                    }
+                    WorkflowAction::HttpCheck(ref target) => {
+                        context
+                            .metrics
+                            .count_with_tags("workflow_loop.event", 1)
+                            .with_tag("workflow_name", workflow_job.workflow.as_str())
+                            .with_tag("event_name", "http_check")
+                            .send();
 
-                        // 3. Continue if the status is not ready and we have not reached the max wait time
-                        if !deployment_is_ready && now < last_deployed_at + Duration::seconds(90) {
-                            info!("Waiting for more time to pass after updating deployment {}", &name);
-                            sleeper.as_mut().reset(Instant::now() + one_second);
-                            continue 'working;
+                        // A check without a url can't pass, and skipping it would let the group through without its health gate.
+                        if target.url.is_empty() {
+                            error!("HttpCheck failed: {} has no url", target.name);
+                            failure_reason = Some(format!("http_check {}: no url", target.name));
+                            everything_ok = false;
+                            break 'working;
                         }
 
-                        // 4. Error if the status is not ready and we have passed the max wait time
-                        if !deployment_is_ready && now > last_deployed_at + Duration::seconds(30) {
+                        let progress = http_checks.entry(target.name.clone()).or_insert(HttpCheckProgress {
+                            started_at: now,
+                            last_attempt_at: None,
+                            successes: 0,
+                        });
+
+                        if let Some(last_attempt_at) = progress.last_attempt_at {
+                            if now < last_attempt_at + Duration::seconds(target.interval_seconds as i64) {
+                                sleeper = context.clock.sleep(one_second);
+                                continue 'working;
+                            }
+                        }
+                        progress.last_attempt_at = Some(now);
+
+                        info!("action_workflow_updated HttpCheck: {} {}", target.name, target.url);
+
+                        let request_timeout = Duration::seconds(target.interval_seconds.max(1) as i64).to_std().unwrap();
+                        match http_check(&context.http_client, &target.url, &target.status, target.body.as_deref(), request_timeout).await {
+                            Ok(()) => progress.successes += 1,
+                            Err(err) => {
+                                info!("http check {} failed: {}", target.name, err);
+                                progress.successes = 0;
+                            }
+                        }
+
+                        if progress.successes < target.successes {
+                            if now < progress.started_at + Duration::seconds(target.timeout_seconds as i64) {
+                                sleeper = context.clock.sleep(one_second);
+                                continue 'working;
+                            }
+
                             context
                                 .metrics
-                                .count_with_tags("workflow_loop.deployment_timeout", 1)
+                                .count_with_tags("workflow_loop.http_check_timeout", 1)
                                 .with_tag("workflow_name", workflow_job.workflow.as_str())
-                                .with_tag("deployment_name", name)
+                                .with_tag("check_name", target.name.as_str())
                                 .send();
 
-                            error!("WaitDeploymentReady failed: Deployment {} did not become ready within wait period", name);
-                            sleeper.as_mut().reset(Instant::now() + one_second);
+                            error!("HttpCheck failed: {} did not succeed {} consecutive times within {} seconds", target.url, target.successes, target.timeout_seconds);
+                            failure_reason = Some(format!("http_check {}: did not succeed {} consecutive times within {} seconds", target.name, target.successes, target.timeout_seconds));
                             everything_ok = false;
                             break 'working;
                         }
@@ -536,31 +1565,793 @@ async fn action_workflow_updated(context: Context, workflow_job: WorkflowJob) ->
                         history.push((work_queue[0].clone(), now));
                         work_queue.remove(0);
                     }
+                    WorkflowAction::Analysis(ref target) => {
+                        context
+                            .metrics
+                            .count_with_tags("workflow_loop.event", 1)
+                            .with_tag("workflow_name", workflow_job.workflow.as_str())
+                            .with_tag("event_name", "analysis")
+                            .send();
+
+                        let progress = analyses.entry(target.name.clone()).or_insert(AnalysisProgress {
+                            started_at: now,
+                            last_attempt_at: None,
+                            errors: 0,
+                            values: vec![],
+                        });
+
+                        if let Some(last_attempt_at) = progress.last_attempt_at {
+                            if now < last_attempt_at + Duration::seconds(target.interval_seconds as i64) {
+                                sleeper = context.clock.sleep(one_second);
+                                continue 'working;
+                            }
+                        }
+                        progress.last_attempt_at = Some(now);
+
+                        info!("action_workflow_updated Analysis: {} {}", target.name, target.query);
+
+                        let query_timeout = Duration::seconds(target.interval_seconds.max(1) as i64).to_std().unwrap();
+                        let outcome = match query(&context.http_client, &context.settings.analysis.provider_url, &target.query, query_timeout).await {
+                            Ok(values) => {
+                                progress.errors = 0;
+                                let outcome = check_thresholds(&values, target.min, target.max);
+                                progress.values.extend(values);
+                                outcome
+                            }
+                            Err(err) => {
+                                progress.errors += 1;
+                                warn!("analysis {} query failed ({} of {}): {}", target.name, progress.errors, context.settings.analysis.retries, err);
+                                if progress.errors > context.settings.analysis.retries {
+                                    Err(err)
+                                } else {
+                                    Ok(())
+                                }
+                            }
+                        };
+
+                        if let Err(err) = outcome {
+                            context
+                                .metrics
+                                .count_with_tags("workflow_loop.analysis_failed", 1)
+                                .with_tag("workflow_name", workflow_job.workflow.as_str())
+                                .with_tag("analysis_name", target.name.as_str())
+                                .send();
+
+                            error!("Analysis {} failed: {}", target.name, err);
+                            failure_reason = Some(format!("analysis {}: {}", target.name, err));
+                            history.push((WorkflowAction::AnalysisResult(target.name.clone(), false, progress.values.clone()), now));
+                            everything_ok = false;
+                            break 'working;
+                        }
+
+                        // The analysis passes once the window has elapsed with at least one value observed and none outside of the thresholds.
+                        if progress.values.is_empty() || now < progress.started_at + Duration::seconds(target.window_seconds as i64) {
+                            sleeper = context.clock.sleep(one_second);
+                            continue 'working;
+                        }
+
+                        history.push((WorkflowAction::AnalysisResult(target.name.clone(), true, progress.values.clone()), now));
+                        work_queue.remove(0);
+                    }
+                    WorkflowAction::AnalysisResult(_, _, _) => {
+                        work_queue.remove(0);
+                    }
                 }
 
-                sleeper.as_mut().reset(Instant::now() + one_second);
+                sleeper = context.clock.sleep(one_second);
                 trace!("action_workflow_updated tick");
             }
         }
     }
 
+    if !everything_ok && !dry_run {
+        if !previous_replicas.is_empty() {
+            context.notifier.notify(
+                Notification::new(
+                    NotificationEvent::Rollback,
+                    &workflow_job.workflow,
+                    format!(
+                        "Restoring replicas of {} deployments",
+                        previous_replicas.len()
+                    ),
+                    context.clock.now(),
+                )
+                .with_group(&workflow_job.group)
+                .with_version(&workflow.spec.version),
+            );
+        }
+        for (name, replicas) in previous_replicas.iter() {
+            warn!("Restoring deployment {} to {} replicas", name, replicas);
+            context
+                .metrics
+                .count_with_tags("workflow_loop.scale_rollback", 1)
+                .with_tag("workflow_name", workflow_job.workflow.as_str())
+                .with_tag("deployment_name", name)
+                .send();
+
+            // A failed restore is logged, the group has failed either way.
+            let _ = patch_deployment(
+                &context,
+                &deployment_client,
+                &workflow_job,
+                name,
+                &patch_params,
+                &Patch::Merge(scale_patch(*replicas)),
+            )
+            .await;
+        }
+    }
+
     info!("Concluded work queue with history: {:?}", history);
 
-    if let Err(err) = context
-        .action_tx
-        .send(Action::WorkflowJobFinished(
+    if dry_run {
+        info!(
+            "Dry run plan for {} in {}: {:?}",
+            workflow_job.workflow, workflow_job.group, plan
+        );
+        let workflow_plan = WorkflowPlan {
+            workflow: workflow_job.workflow.clone(),
+            checksum: workflow_job.checksum,
+            namespace: workflow_job.group.clone(),
+            version: workflow.spec.version.clone(),
+            planned_at: context.clock.now(),
+            ok: everything_ok,
+            reason: failure_reason.clone(),
+            steps: plan,
+        };
+        if let Err(err) = context.workflow_storage.set_plan(workflow_plan).await {
+            error!("Failed to record workflow plan: {}", err);
+        }
+    } else {
+        let from_version = deployed_version(&context, &workflow_job).await;
+        let record = HistoryRecord {
+            workflow: workflow_job.workflow.clone(),
+            checksum: workflow_job.checksum,
+            namespace: workflow_job.group.clone(),
+            started_at,
+            finished_at: context.clock.now(),
+            succeeded: everything_ok,
+            reason: failure_reason.clone(),
+            from_version,
+            to_version: workflow.spec.version.clone(),
+            triggered_by,
+            entries: history
+                .iter()
+                .filter_map(|(action, at)| history_entry(action, *at, &images))
+                .collect(),
+        };
+        if let Err(err) = context.workflow_storage.add_history(record).await {
+            error!("Failed to record workflow history: {}", err);
+        }
+    }
+
+    info!("action_workflow_updated ended");
+    Ok(Some(JobOutcome {
+        ok: everything_ok,
+        reason: failure_reason,
+    }))
+}
+
+// Updates the status of a workflow in the background. Fields that aren't set
+// are left unchanged.
+fn update_workflow_status(client: &Client, workflow_name: &str, status: WorkflowStatus) {
+    let api: Api<Workflow> = Api::all(client.clone());
+    let workflow_name = workflow_name.to_string();
+    tokio::spawn(async move {
+        let patch = Patch::Merge(serde_json::json!({ "status": status }));
+        if let Err(err) = api
+            .patch_status(&workflow_name, &PatchParams::default(), &patch)
+            .await
+        {
+            warn!(
+                "Failed to update status of workflow {}: {}",
+                workflow_name, err
+            );
+        }
+    });
+}
+
+// The version that the group was last deployed at successfully.
+pub(crate) async fn deployed_version(
+    context: &Context,
+    workflow_job: &WorkflowJob,
+) -> Option<String> {
+    context
+        .workflow_storage
+        .get_history(
             workflow_job.workflow.clone(),
-            workflow_job.group.clone(),
-            everything_ok,
-        ))
+            Some(workflow_job.group.clone()),
+        )
         .await
+        .ok()
+        .and_then(|records| {
+            records
+                .into_iter()
+                .rev()
+                .find(|record| record.succeeded)
+                .map(|record| record.to_version)
+        })
+}
+
+// Who triggered a workflow version: the triggered-by annotation when set,
+// otherwise the field manager that most recently changed the workflow.
+pub(crate) fn triggered_by(workflow: &Workflow) -> Option<String> {
+    if let Some(triggered_by) = workflow
+        .annotations()
+        .get("workflow-deploy.ngerakines.me/triggered-by")
     {
-        error!(
-            "Failed to notify that workflow updated job concluded: {}",
-            err
+        return Some(triggered_by.clone());
+    }
+    workflow
+        .managed_fields()
+        .iter()
+        .filter(|entry| entry.manager.is_some())
+        .max_by_key(|entry| entry.time.as_ref().map(|time| time.0))
+        .and_then(|entry| entry.manager.clone())
+}
+
+// Converts a completed action into a history entry. Started and wait actions
+// don't change anything and aren't recorded.
+fn history_entry(
+    action: &WorkflowAction,
+    at: DateTime<Utc>,
+    images: &BTreeMap<String, (String, String)>,
+) -> Option<HistoryEntry> {
+    let (before, after) = match action {
+        WorkflowAction::Started() => return None,
+        _ if action.observes() => return None,
+        WorkflowAction::UpdateDeployment(name, _) => images.get(name).cloned().unzip(),
+        WorkflowAction::AnalysisResult(_, passed, _) => (
+            None,
+            Some(if *passed { "passed" } else { "failed" }.to_string()),
+        ),
+        _ => (None, None),
+    };
+    Some(HistoryEntry {
+        action: action.name().to_string(),
+        target: Some(action.target().to_string()),
+        at,
+        before,
+        after,
+    })
+}
+
+// A plan step for an action, with the patch or object it would send.
+fn plan_step<T: Serialize>(action: &WorkflowAction, patch: Option<&T>) -> PlanStep {
+    PlanStep {
+        action: action.name().to_string(),
+        target: action.target().to_string(),
+        patch: patch.and_then(|patch| serde_json::to_value(patch).ok()),
+    }
+}
+
+// Fetches a deployment that is the target of an action, recording a metric and
+// logging when it can't be found.
+async fn get_deployment(
+    context: &Context,
+    deployment_client: &Api<Deployment>,
+    workflow_job: &WorkflowJob,
+    name: &str,
+) -> Result<Deployment, String> {
+    let reason = match deployment_client.get_opt(name).await {
+        Ok(Some(deployment)) => return Ok(deployment),
+        Ok(None) => "not found".to_string(),
+        Err(err) => format!("unable to get: {}", err),
+    };
+    context
+        .metrics
+        .count_with_tags("workflow_loop.deployment_not_found", 1)
+        .with_tag("workflow_name", workflow_job.workflow.as_str())
+        .with_tag("deployment_name", name)
+        .send();
+    error!("getting deployment {} failed: {}", name, reason);
+    Err(format!("deployment {}: {}", name, reason))
+}
+
+// Patches a deployment, recording a metric and logging when the patch fails.
+// The error is the reason the group fails with.
+async fn patch_deployment<P: Serialize + Debug>(
+    context: &Context,
+    deployment_client: &Api<Deployment>,
+    workflow_job: &WorkflowJob,
+    name: &str,
+    patch_params: &PatchParams,
+    patch: &Patch<P>,
+) -> Result<Deployment, String> {
+    match deployment_client.patch(name, patch_params, patch).await {
+        Ok(deployment) => Ok(deployment),
+        Err(err) => {
+            context
+                .metrics
+                .count_with_tags("workflow_loop.deployment_patch_failed", 1)
+                .with_tag("workflow_name", workflow_job.workflow.as_str())
+                .with_tag("deployment_name", name)
+                .send();
+
+            error!("patching deployment {} failed: {}", name, err);
+            Err(format!("deployment {}: patch failed: {}", name, err))
+        }
+    }
+}
+
+// Gets the job spec for a job target, either from the inline spec or from the
+// job template of the referenced cron job.
+async fn get_job_spec(cron_job_client: &Api<CronJob>, target: &JobTarget) -> Result<JobSpec> {
+    if let Some(spec) = &target.spec {
+        return Ok(serde_json::from_str(spec)?);
+    }
+    let template = target
+        .template
+        .as_ref()
+        .ok_or_else(|| anyhow!("no job or template set"))?;
+    cron_job_client
+        .get(template)
+        .await?
+        .spec
+        .and_then(|spec| spec.job_template.spec)
+        .ok_or_else(|| anyhow!("cron job {} has no job template", template))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration as StdDuration};
+
+    use chrono::Duration;
+
+    use crate::{
+        clock::Clock,
+        config::{Settings, ShutdownPolicy},
+        configmap_storage::ConfigMapWorkflowStorager,
+        crd_storage::WorkflowStorage,
+        fake_api::FakeApi,
+        test_util::{http_stand_in, test_deployment, test_namespace, test_workflow, Harness},
+    };
+
+    const SECOND: StdDuration = StdDuration::from_secs(1);
+    const HOUR: StdDuration = StdDuration::from_secs(3600);
+
+    fn events(api: &FakeApi, reason: &str) -> usize {
+        api.list("events")
+            .iter()
+            .filter(|event| event["reason"] == reason)
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_rollout() {
+        let api = FakeApi::default();
+        let namespaces = ["alpha", "beta", "gamma"];
+        for name in namespaces {
+            api.apply(test_namespace(name));
+            api.apply(test_deployment(name, "app", "app:1.0.0"));
+        }
+        let harness =
+            Harness::start_with_workflow(api.clone(), Settings::new().unwrap(), &namespaces, 2)
+                .await;
+
+        api.apply(test_workflow(&namespaces, "1.0.1", 2, &[]));
+        harness
+            .run_until(SECOND, 120 * SECOND, || async {
+                harness.history().await.len() == 3
+            })
+            .await;
+
+        let records = harness.history().await;
+        assert!(records.iter().all(|record| record.succeeded));
+        for name in namespaces {
+            let deployment = api.get("deployments", name, "app").unwrap();
+            assert_eq!(
+                deployment["spec"]["template"]["spec"]["containers"][0]["image"],
+                "app:1.0.1"
+            );
+        }
+        harness
+            .run_until(SECOND, 10 * SECOND, || async {
+                api.get("workflows", "", "tenants").unwrap()["status"]["last_result"] == "Succeeded"
+            })
+            .await;
+        harness.stop();
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_rollout() {
+        let api = FakeApi::default();
+        api.apply(test_namespace("alpha"));
+        api.apply(test_deployment("alpha", "app", "app:1.0.0"));
+        let harness =
+            Harness::start_with_workflow(api.clone(), Settings::new().unwrap(), &["alpha"], 1)
+                .await;
+
+        // The group waits while the new revision is rolling out.
+        api.hold("alpha", "app");
+        api.apply(test_workflow(&["alpha"], "1.0.1", 1, &[]));
+        let held_until = harness.clock.now() + Duration::seconds(60);
+        harness
+            .run_until(SECOND, 120 * SECOND, || async {
+                harness.clock.now() >= held_until
+            })
+            .await;
+        assert!(harness.history().await.is_empty());
+
+        // It finishes as soon as the rollout does.
+        api.release("alpha", "app");
+        harness
+            .run_until(SECOND, 5 * SECOND, || async {
+                harness.history().await.len() == 1
+            })
+            .await;
+        assert!(harness.history().await[0].succeeded);
+        harness.stop();
+    }
+
+    #[tokio::test]
+    async fn test_readiness_timeout() {
+        let api = FakeApi::default();
+        api.apply(test_namespace("alpha"));
+        api.apply(test_deployment("alpha", "app", "app:1.0.0"));
+        let harness =
+            Harness::start_with_workflow(api.clone(), Settings::new().unwrap(), &["alpha"], 1)
+                .await;
+
+        api.hold("alpha", "app");
+        api.apply(test_workflow(&["alpha"], "1.0.1", 1, &[]));
+        harness
+            .run_until(SECOND, 120 * SECOND, || async {
+                harness.history().await.len() == 1
+            })
+            .await;
+
+        let records = harness.history().await;
+        assert!(!records[0].succeeded);
+        assert!(records[0].finished_at - records[0].started_at >= Duration::seconds(90));
+        assert_eq!(events(&api, "ReadinessTimeout"), 1);
+        harness.stop();
+    }
+
+    #[tokio::test]
+    async fn test_purge_on_failure() {
+        let api = FakeApi::default();
+        let namespaces = ["alpha", "beta", "gamma"];
+        for name in namespaces {
+            api.apply(test_namespace(name));
+        }
+        let harness =
+            Harness::start_with_workflow(api.clone(), Settings::new().unwrap(), &namespaces, 1)
+                .await;
+
+        // None of the namespaces have the deployment, so the first group fails
+        // and the other two are purged before they are dispatched.
+        api.apply(test_workflow(&namespaces, "1.0.1", 1, &[]));
+        harness
+            .run_until(SECOND, 60 * SECOND, || async {
+                events(&api, "Purged") == 1 && events(&api, "Finished") == 1
+            })
+            .await;
+
+        let records = harness.history().await;
+        assert_eq!(records.len(), 1);
+        assert!(!records[0].succeeded);
+        assert_eq!(
+            records[0].reason.as_deref(),
+            Some("deployment app: not found")
         );
+        harness.stop();
     }
 
-    info!("action_workflow_updated ended");
-    Ok(())
+    #[tokio::test]
+    async fn test_suppression() {
+        let api = FakeApi::default();
+        let namespaces = ["alpha", "beta"];
+        for name in namespaces {
+            api.apply(test_namespace(name));
+            api.apply(test_deployment(name, "app", "app:1.0.0"));
+        }
+        let harness =
+            Harness::start_with_workflow(api.clone(), Settings::new().unwrap(), &namespaces, 2)
+                .await;
+
+        // Nothing is dispatched during a week long suppression window.
+        let now = harness.clock.now();
+        let window_end = now + Duration::days(7);
+        let suppression = format!("{} {}", now.to_rfc3339(), window_end.to_rfc3339());
+        api.apply(test_workflow(&namespaces, "1.0.1", 2, &[suppression]));
+        harness
+            .run_until(HOUR, 24 * 8 * HOUR, || async {
+                harness.clock.now() > window_end
+            })
+            .await;
+        assert!(harness.history().await.is_empty());
+        harness
+            .run_until(SECOND, 120 * SECOND, || async {
+                harness.history().await.len() == 2
+            })
+            .await;
+
+        let records = harness.history().await;
+        assert!(records.iter().all(|record| record.succeeded));
+        assert!(records.iter().all(|record| record.started_at > window_end));
+        assert_eq!(events(&api, "Suppressed"), 1);
+        harness.stop();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_replicas() {
+        let api = FakeApi::default();
+        api.apply(test_namespace("alpha"));
+        api.apply(test_deployment("alpha", "app", "app:1.0.0"));
+        let harness =
+            Harness::start_with_workflow(api.clone(), Settings::new().unwrap(), &["alpha"], 1)
+                .await;
+
+        // Replicas that match the schema but don't fit in a replica count fail
+        // the group instead of skipping the scale.
+        let mut workflow = test_workflow(&["alpha"], "1.0.1", 1, &[]);
+        workflow["spec"]["steps"] = serde_json::json!([{"actions": [
+            {"scale": {"targets": [{"name": "app", "replicas": "99999999999"}]}}
+        ]}]);
+        api.apply(workflow);
+        harness
+            .run_until(SECOND, 120 * SECOND, || async {
+                harness.history().await.len() == 1
+            })
+            .await;
+
+        let records = harness.history().await;
+        assert!(!records[0].succeeded);
+        assert_eq!(
+            records[0].reason.as_deref(),
+            Some("deployment app: invalid replicas \"99999999999\"")
+        );
+        harness.stop();
+    }
+
+    #[tokio::test]
+    async fn test_http_check_without_url() {
+        let api = FakeApi::default();
+        api.apply(test_namespace("alpha"));
+        api.apply(test_deployment("alpha", "app", "app:1.0.0"));
+        let harness =
+            Harness::start_with_workflow(api.clone(), Settings::new().unwrap(), &["alpha"], 1)
+                .await;
+
+        // A check without a url fails the group instead of letting it through
+        // without its health gate.
+        let mut workflow = test_workflow(&["alpha"], "1.0.1", 1, &[]);
+        workflow["spec"]["steps"] = serde_json::json!([{"actions": [
+            {"http_check": {"targets": [{"name": "health", "url": ""}]}}
+        ]}]);
+        api.apply(workflow);
+        harness
+            .run_until(SECOND, 30 * SECOND, || async {
+                harness.history().await.len() == 1
+            })
+            .await;
+
+        let records = harness.history().await;
+        assert!(!records[0].succeeded);
+        assert_eq!(
+            records[0].reason.as_deref(),
+            Some("http_check health: no url")
+        );
+        harness.stop();
+    }
+
+    #[tokio::test]
+    async fn test_http_check_timeout() {
+        let api = FakeApi::default();
+        api.apply(test_namespace("alpha"));
+        api.apply(test_deployment("alpha", "app", "app:1.0.0"));
+        let url = http_stand_in(|_| (503, String::new())).await;
+        let harness =
+            Harness::start_with_workflow(api.clone(), Settings::new().unwrap(), &["alpha"], 1)
+                .await;
+
+        let mut workflow = test_workflow(&["alpha"], "1.0.1", 1, &[]);
+        workflow["spec"]["steps"] = serde_json::json!([{"actions": [
+            {"http_check": {"targets": [{
+                "name": "health",
+                "url": format!("{url}/health"),
+                "interval_seconds": 1,
+                "timeout_seconds": 10
+            }]}}
+        ]}]);
+        api.apply(workflow);
+        harness
+            .run_until(SECOND, 60 * SECOND, || async {
+                harness.history().await.len() == 1
+            })
+            .await;
+
+        let records = harness.history().await;
+        assert!(!records[0].succeeded);
+        assert_eq!(
+            records[0].reason.as_deref(),
+            Some("http_check health: did not succeed 1 consecutive times within 10 seconds")
+        );
+        harness.stop();
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_job() {
+        let api = FakeApi::default();
+        api.apply(test_namespace("alpha"));
+        api.apply(test_deployment("alpha", "app", "app:1.0.0"));
+        let harness =
+            Harness::start_with_workflow(api.clone(), Settings::new().unwrap(), &["alpha"], 1)
+                .await;
+
+        let run_job = |version: &str| {
+            let mut workflow = test_workflow(&["alpha"], version, 1, &[]);
+            workflow["spec"]["steps"] = serde_json::json!([{"actions": [
+                {"run_job": {"targets": [{"name": "migrate", "job": {"template": {"spec": {
+                    "restartPolicy": "Never",
+                    "containers": [{"name": "migrate", "image": "app:1.0.0"}]
+                }}}}]}}
+            ]}]);
+            workflow
+        };
+        // Finishes the job of the latest version once it has been created.
+        let finish = |condition: &str| {
+            let mut jobs = api.list("jobs");
+            jobs.sort_by_key(|job| job["metadata"]["name"].as_str().unwrap().to_string());
+            let mut job = match jobs.pop() {
+                Some(job) if job["status"].is_null() => job,
+                _ => return,
+            };
+            job["status"] =
+                serde_json::json!({"conditions": [{"type": condition, "status": "True"}]});
+            api.apply(job);
+        };
+
+        // The group waits on the job watcher, so it finishes as soon as the job
+        // does and a failed job fails it without waiting for the timeout.
+        api.apply(run_job("1.0.1"));
+        harness
+            .run_until(SECOND, 30 * SECOND, || async {
+                finish("Complete");
+                harness.history().await.len() == 1
+            })
+            .await;
+        api.apply(run_job("1.0.2"));
+        harness
+            .run_until(SECOND, 30 * SECOND, || async {
+                finish("Failed");
+                harness.history().await.len() == 2
+            })
+            .await;
+
+        let records = harness.history().await;
+        let succeeded: Vec<(&str, bool, Option<&str>)> = records
+            .iter()
+            .map(|record| {
+                (
+                    record.to_version.as_str(),
+                    record.succeeded,
+                    record.reason.as_deref(),
+                )
+            })
+            .collect();
+        assert!(succeeded.contains(&("1.0.1", true, None)));
+        assert!(succeeded.contains(&("1.0.2", false, Some("job migrate: failed"))));
+        harness.stop();
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_and_resume() {
+        let api = FakeApi::default();
+        api.apply(test_namespace("alpha"));
+        api.apply(test_deployment("alpha", "app", "app:1.0.0"));
+        let mut settings = Settings::new().unwrap();
+        settings.shutdown.policy = ShutdownPolicy::Checkpoint;
+        settings.storage.namespace = "workflow-deploy".to_string();
+        // Groups resume when their workflow is loaded, without waiting for the
+        // first reconcile.
+        settings.reconciler.initial_delay_seconds = 3600;
+        let load = || ConfigMapWorkflowStorager::load(api.client(), &settings);
+        let saved_checkpoints = || {
+            api.get(
+                "configmaps",
+                "workflow-deploy",
+                "workflow-deploy-state-tenants",
+            )
+            .and_then(|config_map| config_map["data"]["checkpoints"].as_str().map(String::from))
+            .unwrap_or_default()
+        };
+
+        api.apply(test_workflow(&["alpha"], "1.0.0", 1, &[]));
+        let harness = Harness::start_with_storage(
+            api.clone(),
+            settings.clone(),
+            Box::new(load().await.unwrap()),
+        );
+        harness
+            .run_until(SECOND, 10 * SECOND, || async {
+                harness
+                    .context
+                    .workflow_storage
+                    .current_version("tenants".to_string())
+                    .await
+                    .is_some()
+            })
+            .await;
+
+        // The group updates and scales the deployment and runs a job, and is
+        // stopped while it waits for the job. It would then fail on a scale
+        // with invalid replicas.
+        let mut workflow = test_workflow(&["alpha"], "1.0.1", 1, &[]);
+        workflow["spec"]["steps"] = serde_json::json!([
+            {"actions": [{"update_deployment": {"targets": [{"name": "app", "containers": ["app"]}]}}]},
+            {"actions": [{"scale": {"targets": [{"name": "app", "replicas": "+1"}]}}]},
+            {"actions": [{"run_job": {"targets": [{"name": "migrate", "job": {"template": {"spec": {
+                "restartPolicy": "Never",
+                "containers": [{"name": "migrate", "image": "app:1.0.0"}]
+            }}}}]}}]},
+            {"actions": [{"scale": {"targets": [{"name": "app", "replicas": "99999999999"}]}}]}
+        ]);
+        api.apply(workflow);
+        harness
+            .run_until(SECOND, 60 * SECOND, || async {
+                !api.list("jobs").is_empty()
+            })
+            .await;
+        // The action loop has recorded the checkpoint once it has shut down.
+        let context = harness.context.clone();
+        harness.shutdown().await;
+        assert!(!matches!(saved_checkpoints().as_str(), "" | "[]"));
+        assert!(context
+            .workflow_storage
+            .get_history("tenants".to_string(), None)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // The checkpoint is read back from the ConfigMap with what the group
+        // changed before it stopped.
+        let storage = load().await.unwrap();
+        let checkpoints = storage.get_checkpoints().await.unwrap();
+        assert_eq!(checkpoints.len(), 1);
+        assert_eq!(checkpoints[0].completed, 6);
+        let changes = &checkpoints[0].changes;
+        assert_eq!(
+            changes.previous_replicas,
+            BTreeMap::from([("app".to_string(), 1)])
+        );
+        assert_eq!(changes.created_jobs.keys().collect::<Vec<_>>(), ["migrate"]);
+        assert!(changes.generations.contains_key("app"));
+        assert!(changes.images.contains_key("app"));
+
+        // The next controller resumes the group once the workflow is first
+        // reconciled. It waits on the job that was already created, and
+        // restores the replicas that were scaled before it stopped when the
+        // group fails.
+        let harness = Harness::start_with_storage(api.clone(), settings, Box::new(storage));
+        let finish = || {
+            let mut job = match api.list("jobs").pop() {
+                Some(job) if job["status"].is_null() => job,
+                _ => return,
+            };
+            job["status"] =
+                serde_json::json!({"conditions": [{"type": "Complete", "status": "True"}]});
+            api.apply(job);
+        };
+        harness
+            .run_until(SECOND, 240 * SECOND, || async {
+                finish();
+                harness.history().await.len() == 1
+            })
+            .await;
+        let records = harness.history().await;
+        assert!(!records[0].succeeded);
+        assert_eq!(
+            records[0].reason.as_deref(),
+            Some("deployment app: invalid replicas \"99999999999\"")
+        );
+        assert_eq!(api.list("jobs").len(), 1);
+        assert_eq!(
+            api.get("deployments", "alpha", "app").unwrap()["spec"]["replicas"],
+            1
+        );
+        assert_eq!(saved_checkpoints(), "[]");
+        harness.stop();
+    }
 }
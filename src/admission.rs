@@ -0,0 +1,206 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use anyhow::{anyhow, Result};
+use kube::core::{
+    admission::{AdmissionRequest, AdmissionResponse, AdmissionReview},
+    DynamicObject,
+};
+use tokio::sync::broadcast::Receiver;
+use tracing::{info, warn};
+use warp::{Filter, Rejection, Reply};
+
+use crate::{context::Context, conversion::canonical_workflow, validate::validate_workflow};
+
+// Serves the validating admission webhook for workflows over TLS. The
+// certificate is expected to be mounted from a secret.
+pub(crate) async fn admission_loop(context: Context, shutdown: &mut Receiver<bool>) -> Result<()> {
+    let settings = &context.settings.admission;
+    let listen: SocketAddr = settings.listen.parse()?;
+    let cert = std::fs::read(&settings.cert_path)
+        .map_err(|err| anyhow!("unable to read {}: {}", settings.cert_path, err))?;
+    let key = std::fs::read(&settings.key_path)
+        .map_err(|err| anyhow!("unable to read {}: {}", settings.key_path, err))?;
+    let mut shutdown = shutdown.resubscribe();
+
+    let (address, server) = warp::serve(routes(context))
+        .tls()
+        .cert(cert)
+        .key(key)
+        .try_bind_with_graceful_shutdown(listen, async move {
+            let _ = shutdown.recv().await;
+        })?;
+    info!("admission loop started on {}", address);
+
+    server.await;
+
+    info!("admission loop ended");
+    Ok(())
+}
+
+fn routes(context: Context) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let with_context = warp::any().map(move || context.clone());
+
+    warp::post()
+        .and(warp::path!("validate" / "workflows"))
+        .and(warp::body::json())
+        .and(with_context)
+        .and_then(validate)
+}
+
+async fn validate(
+    review: AdmissionReview<DynamicObject>,
+    context: Context,
+) -> Result<impl Reply, Infallible> {
+    let request: AdmissionRequest<DynamicObject> = match review.try_into() {
+        Ok(request) => request,
+        Err(err) => {
+            warn!("invalid admission review: {}", err);
+            return Ok(warp::reply::json(
+                &AdmissionResponse::invalid(err.to_string()).into_review(),
+            ));
+        }
+    };
+
+    let mut response = AdmissionResponse::from(&request);
+    if let Some(object) = request.object {
+        let name = object.metadata.name.clone().unwrap_or_default();
+        // Workflows of any version are validated as the current version.
+        let problems = match serde_json::to_value(object)
+            .map_err(anyhow::Error::from)
+            .and_then(canonical_workflow)
+        {
+            Ok(workflow) => validate_workflow(&workflow),
+            Err(err) => vec![format!("invalid workflow: {err}")],
+        };
+
+        context
+            .metrics
+            .count_with_tags("admission.review", 1)
+            .with_tag("workflow_name", name.as_str())
+            .with_tag("allowed", problems.is_empty().to_string().as_str())
+            .send();
+
+        if !problems.is_empty() {
+            info!("rejecting workflow {}: {:?}", name, problems);
+            response = response.deny(problems.join("; "));
+        }
+    }
+
+    Ok(warp::reply::json(&response.into_review()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_context;
+    use serde_json::json;
+
+    fn review(version: &str, spec: serde_json::Value) -> serde_json::Value {
+        json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "kind": {"group": "workflow-deploy.ngerakines.me", "version": version, "kind": "Workflow"},
+                "resource": {"group": "workflow-deploy.ngerakines.me", "version": version, "resource": "workflows"},
+                "name": "tenants",
+                "operation": "CREATE",
+                "userInfo": {"username": "nick"},
+                "object": {
+                    "apiVersion": format!("workflow-deploy.ngerakines.me/{version}"),
+                    "kind": "Workflow",
+                    "metadata": {"name": "tenants"},
+                    "spec": spec
+                },
+                "dryRun": false
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let routes = routes(test_context());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/validate/workflows")
+            .json(&review("v1alpha", json!({
+                "namespaces": ["foo", "bar"],
+                "version": "1.0.1",
+                "supression": [],
+                "steps": [{"actions": [{"action": "restart_deployment", "targets": [{"resource": "Deployment", "name": "app"}]}]}]
+            })))
+            .reply(&routes)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body["response"]["uid"],
+            "705ab4f5-6393-11e8-b7cc-42010a800002"
+        );
+        assert_eq!(body["response"]["allowed"], true);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/validate/workflows")
+            .json(&review(
+                "v1alpha",
+                json!({
+                    "namespaces": ["foo", "foo"],
+                    "version": "1.0.1",
+                    "supression": [],
+                    "steps": [{"actions": [{"action": "deploy", "targets": []}]}]
+                }),
+            ))
+            .reply(&routes)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["response"]["allowed"], false);
+        assert_eq!(
+            body["response"]["status"]["message"],
+            "namespace foo is listed more than once; steps[0].actions[0] has no targets; steps[0].actions[0] has unknown action \"deploy\""
+        );
+
+        // An http_check without a url is denied rather than converted to a
+        // check that can't run.
+        let response = warp::test::request()
+            .method("POST")
+            .path("/validate/workflows")
+            .json(&review(
+                "v1alpha",
+                json!({
+                    "namespaces": ["foo"],
+                    "version": "1.0.1",
+                    "supression": [],
+                    "steps": [{"actions": [{"action": "http_check", "targets": [{"resource": "Deployment", "name": "health"}]}]}]
+                }),
+            ))
+            .reply(&routes)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["response"]["allowed"], false);
+        assert_eq!(
+            body["response"]["status"]["message"],
+            "steps[0].actions[0] target health has no url"
+        );
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/validate/workflows")
+            .json(&review(
+                "v1beta1",
+                json!({
+                    "namespaces": ["foo", "foo"],
+                    "version": "1.0.1",
+                    "steps": [{"actions": [{"restart_deployment": {"targets": []}}]}]
+                }),
+            ))
+            .reply(&routes)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["response"]["allowed"], false);
+        assert_eq!(
+            body["response"]["status"]["message"],
+            "namespace foo is listed more than once; steps[0].actions[0] has no targets"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct QueryResponse {
+    status: String,
+    data: Option<QueryData>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "resultType", content = "result", rename_all = "lowercase")]
+enum QueryData {
+    Vector(Vec<VectorSample>),
+    Scalar((f64, String)),
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Deserialize)]
+struct VectorSample {
+    value: (f64, String),
+}
+
+// Runs an instant query against a Prometheus-compatible HTTP API and returns
+// the value of each sample in the result. A query that returns no samples is
+// an error because the analysis can't be evaluated.
+pub(crate) async fn query(
+    client: &reqwest::Client,
+    provider_url: &str,
+    query: &str,
+    timeout: Duration,
+) -> Result<Vec<f64>> {
+    let response: QueryResponse = client
+        .get(format!(
+            "{}/api/v1/query",
+            provider_url.trim_end_matches('/')
+        ))
+        .query(&[("query", query)])
+        .timeout(timeout)
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    if response.status != "success" {
+        return Err(anyhow!(
+            "query failed: {}",
+            response.error.unwrap_or(response.status)
+        ));
+    }
+
+    let values: Vec<String> = match response.data {
+        Some(QueryData::Vector(samples)) => {
+            samples.into_iter().map(|sample| sample.value.1).collect()
+        }
+        Some(QueryData::Scalar((_, value))) => vec![value],
+        _ => return Err(anyhow!("unsupported query result")),
+    };
+    if values.is_empty() {
+        return Err(anyhow!("query returned no data"));
+    }
+
+    values
+        .iter()
+        .map(|value| value.parse::<f64>().map_err(anyhow::Error::from))
+        .collect()
+}
+
+// Returns an error describing the first value that is outside of the thresholds.
+pub(crate) fn check_thresholds(values: &[f64], min: Option<f64>, max: Option<f64>) -> Result<()> {
+    for value in values {
+        if value.is_nan() {
+            return Err(anyhow!("value is not a number"));
+        }
+        if let Some(min) = min {
+            if *value < min {
+                return Err(anyhow!("{} is less than {}", value, min));
+            }
+        }
+        if let Some(max) = max {
+            if *value > max {
+                return Err(anyhow!("{} is greater than {}", value, max));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::http_stand_in;
+
+    #[tokio::test]
+    async fn test_query() {
+        let provider_url = http_stand_in(|target| {
+            if target.contains("error_rate") {
+                return (
+                    200,
+                    r#"{"status":"success","data":{"resultType":"vector","result":[{"metric":{"namespace":"foo"},"value":[1683900000.0,"0.02"]}]}}"#.to_string(),
+                );
+            }
+            if target.contains("scalar") {
+                return (
+                    200,
+                    r#"{"status":"success","data":{"resultType":"scalar","result":[1683900000.0,"7"]}}"#.to_string(),
+                );
+            }
+            if target.contains("empty") {
+                return (
+                    200,
+                    r#"{"status":"success","data":{"resultType":"vector","result":[]}}"#.to_string(),
+                );
+            }
+            (
+                400,
+                r#"{"status":"error","errorType":"bad_data","error":"parse error"}"#.to_string(),
+            )
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let timeout = Duration::from_secs(5);
+
+        assert_eq!(
+            query(
+                &client,
+                &provider_url,
+                "error_rate{namespace=\"foo\"}",
+                timeout
+            )
+            .await
+            .unwrap(),
+            vec![0.02]
+        );
+        assert_eq!(
+            query(&client, &provider_url, "scalar(1)", timeout)
+                .await
+                .unwrap(),
+            vec![7.0]
+        );
+        assert!(query(&client, &provider_url, "empty", timeout)
+            .await
+            .is_err());
+        assert!(query(&client, &provider_url, "invalid(", timeout)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_check_thresholds() {
+        assert!(check_thresholds(&[0.01, 0.02], None, Some(0.05)).is_ok());
+        assert!(check_thresholds(&[0.01, 0.07], None, Some(0.05)).is_err());
+        assert!(check_thresholds(&[10.0], Some(5.0), None).is_ok());
+        assert!(check_thresholds(&[1.0], Some(5.0), None).is_err());
+        assert!(check_thresholds(&[f64::NAN], None, None).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use anyhow::Result;
+use serde::Deserialize;
+use tokio::sync::broadcast::Receiver;
+use tracing::{error, info};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use crate::context::Context;
+
+#[derive(Debug, Deserialize)]
+struct NamespaceQuery {
+    namespace: Option<String>,
+}
+
+pub(crate) async fn api_loop(context: Context, shutdown: &mut Receiver<bool>) -> Result<()> {
+    let listen: SocketAddr = context.settings.api.listen.parse()?;
+    let mut shutdown = shutdown.resubscribe();
+
+    let (address, server) =
+        warp::serve(routes(context)).try_bind_with_graceful_shutdown(listen, async move {
+            let _ = shutdown.recv().await;
+        })?;
+    info!("api loop started on {}", address);
+
+    server.await;
+
+    info!("api loop ended");
+    Ok(())
+}
+
+fn routes(context: Context) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let with_context = warp::any().map(move || context.clone());
+
+    let history = warp::get()
+        .and(warp::path!("workflows" / String / "history"))
+        .and(warp::query::<NamespaceQuery>())
+        .and(with_context.clone())
+        .and_then(get_history);
+
+    let plan = warp::get()
+        .and(warp::path!("workflows" / String / "plan"))
+        .and(warp::query::<NamespaceQuery>())
+        .and(with_context)
+        .and_then(get_plan);
+
+    history.or(plan)
+}
+
+async fn get_history(
+    workflow: String,
+    query: NamespaceQuery,
+    context: Context,
+) -> Result<Box<dyn Reply>, Infallible> {
+    context
+        .metrics
+        .count_with_tags("api.request", 1)
+        .with_tag("route", "history")
+        .with_tag("workflow_name", workflow.as_str())
+        .send();
+
+    match context
+        .workflow_storage
+        .get_history(workflow.clone(), query.namespace)
+        .await
+    {
+        Ok(records) => Ok(Box::new(warp::reply::json(&records))),
+        Err(err) => {
+            error!("unable to get history for {}: {}", workflow, err);
+            Ok(Box::new(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+async fn get_plan(
+    workflow: String,
+    query: NamespaceQuery,
+    context: Context,
+) -> Result<Box<dyn Reply>, Infallible> {
+    context
+        .metrics
+        .count_with_tags("api.request", 1)
+        .with_tag("route", "plan")
+        .with_tag("workflow_name", workflow.as_str())
+        .send();
+
+    match context
+        .workflow_storage
+        .get_plans(workflow.clone(), query.namespace)
+        .await
+    {
+        Ok(plans) => Ok(Box::new(warp::reply::json(&plans))),
+        Err(err) => {
+            error!("unable to get plans for {}: {}", workflow, err);
+            Ok(Box::new(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crd_storage::{HistoryRecord, PlanStep, WorkflowPlan};
+    use crate::test_util::test_context;
+
+    #[tokio::test]
+    async fn test_get_history() {
+        let context = test_context();
+        for (namespace, version) in [("foo", "1.4.1"), ("bar", "1.4.1"), ("foo", "1.4.2")] {
+            let now = context.clock.now();
+            context
+                .workflow_storage
+                .add_history(HistoryRecord {
+                    workflow: "tenants".to_string(),
+                    checksum: 1,
+                    namespace: namespace.to_string(),
+                    started_at: now,
+                    finished_at: now,
+                    succeeded: true,
+                    reason: None,
+                    from_version: None,
+                    to_version: version.to_string(),
+                    triggered_by: Some("nick".to_string()),
+                    entries: vec![],
+                })
+                .await
+                .unwrap();
+        }
+
+        let response = warp::test::request()
+            .path("/workflows/tenants/history?namespace=foo")
+            .reply(&routes(context.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let records: Vec<HistoryRecord> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| record.to_version.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["1.4.1", "1.4.2"]
+        );
+
+        let response = warp::test::request()
+            .path("/workflows/other/history")
+            .reply(&routes(context))
+            .await;
+        assert_eq!(response.body().as_ref(), b"[]");
+    }
+
+    #[tokio::test]
+    async fn test_get_plan() {
+        let context = test_context();
+        for version in ["1.4.1", "1.4.2"] {
+            context
+                .workflow_storage
+                .set_plan(WorkflowPlan {
+                    workflow: "tenants".to_string(),
+                    checksum: 1,
+                    namespace: "foo".to_string(),
+                    version: version.to_string(),
+                    planned_at: context.clock.now(),
+                    ok: true,
+                    reason: None,
+                    steps: vec![PlanStep {
+                        action: "scale".to_string(),
+                        target: "api".to_string(),
+                        patch: Some(serde_json::json!({"spec": {"replicas": 3}})),
+                    }],
+                })
+                .await
+                .unwrap();
+        }
+
+        let response = warp::test::request()
+            .path("/workflows/tenants/plan?namespace=foo")
+            .reply(&routes(context))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let plans: Vec<WorkflowPlan> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0].version, "1.4.2");
+        assert_eq!(
+            plans[0].steps[0].patch,
+            Some(serde_json::json!({"spec": {"replicas": 3}}))
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+
+// The source of time for the loops that make decisions based on it, so that
+// tests can control it.
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    // Completes once the duration has passed according to this clock.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+// A clock that only moves when it is advanced, used by tests and to replay
+// recordings. Sleeps complete when the clock is advanced past their deadline.
+pub(crate) struct ManualClock {
+    now: tokio::sync::watch::Sender<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub(crate) fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: tokio::sync::watch::channel(now).0,
+        }
+    }
+
+    // Moves the clock forward to the given time. It never moves back.
+    pub(crate) fn advance_to(&self, at: DateTime<Utc>) {
+        self.now.send_if_modified(|now| {
+            let modified = at > *now;
+            if modified {
+                *now = at;
+            }
+            modified
+        });
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let deadline = self.now() + chrono::Duration::from_std(duration).unwrap();
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            while *now.borrow_and_update() < deadline {
+                if now.changed().await.is_err() {
+                    // The clock is gone and will never reach the deadline.
+                    std::future::pending::<()>().await;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn test_clock() {
+        let start = Utc::now();
+        let clock = ManualClock::new(start);
+
+        let mut sleeper = clock.sleep(Duration::from_secs(60));
+        assert!((&mut sleeper).now_or_never().is_none());
+
+        clock.advance_to(start + chrono::Duration::seconds(59));
+        assert!((&mut sleeper).now_or_never().is_none());
+
+        clock.advance_to(start + chrono::Duration::seconds(60));
+        assert!(sleeper.now_or_never().is_some());
+        assert_eq!(clock.now(), start + chrono::Duration::seconds(60));
+    }
+}
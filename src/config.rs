@@ -1,5 +1,5 @@
 use anyhow::{anyhow, Result};
-use std::{collections::HashMap, env};
+use std::{collections::HashMap, env, net::SocketAddr};
 
 use config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
@@ -18,10 +18,121 @@ pub struct Stats {
     pub global_tags: HashMap<String, String>,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct Analysis {
+    pub provider_url: String,
+    pub retries: u32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Webhook {
+    pub url: String,
+    // The notification events sent to the webhook. All events are sent when empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+    // The request body template. The notification is sent as JSON when not set.
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Notifications {
+    pub queue_size: usize,
+    pub retries: u32,
+    pub webhooks: Vec<Webhook>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct History {
+    pub retention_seconds: u64,
+    // The number of records kept for each namespace of a workflow when history is kept in ConfigMaps.
+    pub max_records: usize,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Storage {
+    // The namespace of the ConfigMaps that history is kept in. History is only kept in memory when empty.
+    pub namespace: String,
+    // The number of ConfigMaps that the history of each workflow is split across.
+    pub history_shards: u32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Api {
+    pub enabled: bool,
+    pub listen: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Admission {
+    pub enabled: bool,
+    pub listen: String,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+// The conversion webhook for workflows is served on its own, as it is needed
+// whenever more than one workflow version is served.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Conversion {
+    pub enabled: bool,
+    pub listen: String,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Recorder {
+    // The file that watcher events and actions are appended to. Nothing is recorded when empty.
+    pub path: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Watchers {
+    // The number of times in a row that a watcher can fail before the controller shuts down.
+    pub max_failures: u32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Jobs {
+    // How long a group can run before it fails.
+    pub deadline_seconds: u64,
+    // How long a group can run before it is counted as stuck.
+    pub stuck_seconds: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownPolicy {
+    // Wait for groups in flight to finish, checkpointing those that don't finish in time.
+    Drain,
+    // Stop groups in flight at their next step and checkpoint them.
+    Checkpoint,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Shutdown {
+    pub policy: ShutdownPolicy,
+    // How long to wait for groups in flight to finish or stop.
+    pub drain_seconds: u64,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
     pub stats: Stats,
     pub reconciler: Reconciler,
+    pub analysis: Analysis,
+    pub notifications: Notifications,
+    pub history: History,
+    pub storage: Storage,
+    pub api: Api,
+    pub admission: Admission,
+    pub conversion: Conversion,
+    pub recorder: Recorder,
+    pub watchers: Watchers,
+    pub jobs: Jobs,
+    pub shutdown: Shutdown,
+    // When set, workflow jobs send every change as a server-side dry run and record the plan instead of changing anything.
+    pub dry_run: bool,
 }
 
 impl Settings {
@@ -50,6 +161,42 @@ impl Settings {
         if self.reconciler.delay_seconds < 60 {
             return Err(anyhow!("reconciler.delay_seconds must at least 60 seconds"));
         }
+        if self.notifications.queue_size == 0 {
+            return Err(anyhow!("notifications.queue_size must be at least 1"));
+        }
+        if self.watchers.max_failures == 0 {
+            return Err(anyhow!("watchers.max_failures must be at least 1"));
+        }
+        if self.jobs.deadline_seconds < 60 {
+            return Err(anyhow!("jobs.deadline_seconds must be at least 60 seconds"));
+        }
+        if self.jobs.stuck_seconds == 0 || self.jobs.stuck_seconds > self.jobs.deadline_seconds {
+            return Err(anyhow!(
+                "jobs.stuck_seconds must be between 1 and jobs.deadline_seconds"
+            ));
+        }
+        if self.history.max_records == 0 {
+            return Err(anyhow!("history.max_records must be at least 1"));
+        }
+        if self.storage.history_shards == 0 {
+            return Err(anyhow!("storage.history_shards must be at least 1"));
+        }
+        // Checkpoints are kept with the history, and would be lost with the
+        // controller otherwise.
+        if self.shutdown.policy == ShutdownPolicy::Checkpoint && self.storage.namespace.is_empty() {
+            return Err(anyhow!(
+                "storage.namespace must be set with the checkpoint policy"
+            ));
+        }
+        if self.api.enabled && self.api.listen.parse::<SocketAddr>().is_err() {
+            return Err(anyhow!("api.listen must be a socket address"));
+        }
+        if self.admission.enabled && self.admission.listen.parse::<SocketAddr>().is_err() {
+            return Err(anyhow!("admission.listen must be a socket address"));
+        }
+        if self.conversion.enabled && self.conversion.listen.parse::<SocketAddr>().is_err() {
+            return Err(anyhow!("conversion.listen must be a socket address"));
+        }
 
         Ok(())
     }
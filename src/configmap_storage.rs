@@ -0,0 +1,820 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    hash::Hasher,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use fnv::FnvHasher;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{
+    api::{DeleteParams, ListParams, ObjectMeta, Patch, PatchParams},
+    Api, Client,
+};
+use parking_lot::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    config::Settings,
+    crd::Workflow,
+    crd_storage::{
+        HistoryRecord, JobCheckpoint, KnownResource, MemoryWorkflowStorager, WorkflowPlan,
+        WorkflowStorage,
+    },
+};
+
+const FIELD_MANAGER: &str = "workflow-deploy";
+// The label that marks the ConfigMaps kept by the controller, set to a hash of
+// the name of the workflow they are for. Label values are limited to 63
+// characters, so the name itself is kept in an annotation.
+const STATE_LABEL: &str = "workflow-deploy.ngerakines.me/state";
+const WORKFLOW_ANNOTATION: &str = "workflow-deploy.ngerakines.me/workflow";
+// The label that marks the ConfigMaps that hold a shard of the history of a
+// workflow, set to the number of the shard.
+const SHARD_LABEL: &str = "workflow-deploy.ngerakines.me/history-shard";
+const HISTORY_KEY: &str = "history";
+const CHECKPOINTS_KEY: &str = "checkpoints";
+// ConfigMaps hold at most 1 MiB. The oldest records of a shard are left out
+// when it grows past this, so that writes keep succeeding.
+const HISTORY_BYTES: usize = 900 * 1024;
+
+fn config_map_name(workflow: &str) -> String {
+    format!("workflow-deploy-state-{workflow}")
+}
+
+fn history_config_map_name(workflow: &str, shard: u32) -> String {
+    format!("workflow-deploy-history-{workflow}-{shard}")
+}
+
+fn workflow_label(workflow: &str) -> String {
+    let mut hasher = FnvHasher::default();
+    hasher.write(workflow.as_bytes());
+    format!("{:016x}", hasher.finish())
+}
+
+// Keeps everything in memory like `MemoryWorkflowStorager`, and writes the
+// history and job checkpoints of each workflow through to ConfigMaps so that
+// they survive the controller restarting. The checkpoints of a workflow are
+// kept in one ConfigMap, and its history is split across ConfigMaps by
+// namespace so that each write only touches the shard of one namespace. The
+// ConfigMaps are read back when the storage is loaded.
+pub(crate) struct ConfigMapWorkflowStorager {
+    memory: MemoryWorkflowStorager,
+    api: Api<ConfigMap>,
+    shards: u32,
+    // The number of history records kept for each namespace of a workflow.
+    max_records: usize,
+    // The workflows that have history.
+    persisted: Mutex<BTreeSet<String>>,
+    // Writes of a snapshot are serialized so that an older snapshot never
+    // replaces a newer one.
+    writes: tokio::sync::Mutex<()>,
+}
+
+impl ConfigMapWorkflowStorager {
+    pub(crate) async fn load(client: Client, settings: &Settings) -> Result<Self> {
+        let storage = Self {
+            memory: MemoryWorkflowStorager::default(),
+            api: Api::namespaced(client, &settings.storage.namespace),
+            shards: settings.storage.history_shards,
+            max_records: settings.history.max_records,
+            persisted: Mutex::default(),
+            writes: tokio::sync::Mutex::default(),
+        };
+
+        // The shards of the history of each workflow that have a ConfigMap.
+        let mut shards: BTreeMap<String, BTreeSet<u32>> = BTreeMap::new();
+        // Workflows whose history has to be split into shards again, because
+        // it was kept in a single ConfigMap before it was split or the number
+        // of shards has changed.
+        let mut unsplit: BTreeMap<String, bool> = BTreeMap::new();
+        let config_maps = storage
+            .api
+            .list(&ListParams::default().labels(STATE_LABEL))
+            .await?;
+        for config_map in config_maps {
+            let labels = config_map.metadata.labels.unwrap_or_default();
+            // ConfigMaps written before the name was moved to an annotation
+            // have it in the label.
+            let workflow = config_map
+                .metadata
+                .annotations
+                .unwrap_or_default()
+                .get(WORKFLOW_ANNOTATION)
+                .or_else(|| labels.get(STATE_LABEL))
+                .cloned()
+                .unwrap_or_default();
+            let shard = labels
+                .get(SHARD_LABEL)
+                .and_then(|shard| shard.parse::<u32>().ok());
+            let data = config_map.data.unwrap_or_default();
+            let history: Vec<HistoryRecord> = match data.get(HISTORY_KEY) {
+                Some(history) => serde_json::from_str(history)
+                    .map_err(|err| anyhow!("history of workflow {}: {}", workflow, err))?,
+                None => vec![],
+            };
+            let checkpoints: Vec<JobCheckpoint> = match data.get(CHECKPOINTS_KEY) {
+                Some(checkpoints) => serde_json::from_str(checkpoints)
+                    .map_err(|err| anyhow!("checkpoints of workflow {}: {}", workflow, err))?,
+                None => vec![],
+            };
+            info!(
+                "loaded {} history records and {} job checkpoints of workflow {}",
+                history.len(),
+                checkpoints.len(),
+                workflow
+            );
+            if let Some(shard) = shard {
+                shards.entry(workflow.clone()).or_default().insert(shard);
+            }
+            if !history.is_empty() {
+                match shard {
+                    None => {
+                        unsplit.insert(workflow.clone(), true);
+                    }
+                    Some(shard) => {
+                        if history
+                            .iter()
+                            .any(|record| storage.shard(&record.namespace) != shard)
+                        {
+                            unsplit.entry(workflow.clone()).or_default();
+                        }
+                    }
+                }
+                storage.persisted.lock().insert(workflow.clone());
+            }
+            let namespaces: BTreeSet<String> = history
+                .iter()
+                .map(|record| record.namespace.clone())
+                .collect();
+            for record in history {
+                storage.memory.add_history(record).await?;
+            }
+            // The number of records kept may have been lowered since the
+            // history was written.
+            for namespace in namespaces {
+                storage
+                    .memory
+                    .limit_history(&workflow, &namespace, storage.max_records);
+            }
+            for checkpoint in checkpoints {
+                storage.memory.set_checkpoint(checkpoint).await?;
+            }
+        }
+
+        for (workflow, unsharded) in unsplit {
+            info!("splitting the history of workflow {} into shards", workflow);
+            let existing = shards.remove(&workflow).unwrap_or_default();
+            let mut rewrite: BTreeSet<u32> = storage
+                .memory
+                .filter_history(&workflow, |_| true)
+                .iter()
+                .map(|record| storage.shard(&record.namespace))
+                .collect();
+            rewrite.extend(existing.iter().filter(|shard| **shard < storage.shards));
+            for shard in rewrite {
+                storage.persist_history(&workflow, shard).await?;
+            }
+            for shard in existing.iter().filter(|shard| **shard >= storage.shards) {
+                storage
+                    .api
+                    .delete(
+                        &history_config_map_name(&workflow, *shard),
+                        &DeleteParams::default(),
+                    )
+                    .await?;
+            }
+            if unsharded {
+                storage
+                    .api
+                    .patch(
+                        &config_map_name(&workflow),
+                        &PatchParams::default(),
+                        &Patch::Merge(serde_json::json!({"data": {HISTORY_KEY: null}})),
+                    )
+                    .await?;
+            }
+        }
+        Ok(storage)
+    }
+
+    // The shard of the history that the records of a namespace are kept in.
+    fn shard(&self, namespace: &str) -> u32 {
+        let mut hasher = FnvHasher::default();
+        hasher.write(namespace.as_bytes());
+        (hasher.finish() % self.shards as u64) as u32
+    }
+
+    // Writes the records of a shard of the history of a workflow that is kept
+    // in memory to its ConfigMap.
+    async fn persist_history(&self, workflow: &str, shard: u32) -> Result<()> {
+        let _write = self.writes.lock().await;
+        // Only the latest records of each namespace are kept. The records that
+        // are left out are forgotten in memory too, so that the history that
+        // is served doesn't change when the controller restarts.
+        let mut kept: BTreeMap<String, usize> = BTreeMap::new();
+        let mut history: Vec<HistoryRecord> = vec![];
+        let mut dropped: Vec<HistoryRecord> = vec![];
+        for record in self
+            .memory
+            .filter_history(workflow, |record| self.shard(&record.namespace) == shard)
+            .into_iter()
+            .rev()
+        {
+            let count = kept.entry(record.namespace.clone()).or_default();
+            *count += 1;
+            if *count <= self.max_records {
+                history.push(record);
+            } else {
+                dropped.push(record);
+            }
+        }
+        history.reverse();
+
+        let mut serialized = serde_json::to_string(&history)?;
+        if serialized.len() > HISTORY_BYTES {
+            // Records are kept in the order they finished, so the oldest are
+            // left out first.
+            let mut size = serialized.len();
+            let mut skip = 0;
+            while size > HISTORY_BYTES && skip < history.len() {
+                size -= serde_json::to_string(&history[skip])?.len() + 1;
+                skip += 1;
+            }
+            warn!(
+                "history shard {} of workflow {} is too large, leaving out its {} oldest records",
+                shard, workflow, skip
+            );
+            dropped.extend(history.drain(..skip));
+            serialized = serde_json::to_string(&history)?;
+        }
+
+        let name = history_config_map_name(workflow, shard);
+        let data = BTreeMap::from([(HISTORY_KEY.to_string(), serialized)]);
+        let labels = BTreeMap::from([
+            (STATE_LABEL.to_string(), workflow_label(workflow)),
+            (SHARD_LABEL.to_string(), shard.to_string()),
+        ]);
+        self.apply(&name, workflow, labels, data).await?;
+        self.memory.forget_history(workflow, &dropped);
+        self.persisted.lock().insert(workflow.to_string());
+        Ok(())
+    }
+
+    // Writes the job checkpoints of a workflow that are kept in memory to its
+    // ConfigMap.
+    async fn persist_checkpoints(&self, workflow: &str) -> Result<()> {
+        let _write = self.writes.lock().await;
+        let checkpoints: Vec<JobCheckpoint> = self
+            .memory
+            .get_checkpoints()
+            .await?
+            .into_iter()
+            .filter(|checkpoint| checkpoint.workflow == workflow)
+            .collect();
+
+        let data = BTreeMap::from([(
+            CHECKPOINTS_KEY.to_string(),
+            serde_json::to_string(&checkpoints)?,
+        )]);
+        let labels = BTreeMap::from([(STATE_LABEL.to_string(), workflow_label(workflow))]);
+        self.apply(&config_map_name(workflow), workflow, labels, data)
+            .await
+    }
+
+    async fn apply(
+        &self,
+        name: &str,
+        workflow: &str,
+        labels: BTreeMap<String, String>,
+        data: BTreeMap<String, String>,
+    ) -> Result<()> {
+        let config_map = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                labels: Some(labels),
+                annotations: Some(BTreeMap::from([(
+                    WORKFLOW_ANNOTATION.to_string(),
+                    workflow.to_string(),
+                )])),
+                ..Default::default()
+            },
+            data: Some(data),
+            ..Default::default()
+        };
+        self.api
+            .patch(
+                name,
+                &PatchParams::apply(FIELD_MANAGER).force(),
+                &Patch::Apply(&config_map),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl WorkflowStorage for ConfigMapWorkflowStorager {
+    async fn add_workflow(&self, workflow: Workflow) -> Result<()> {
+        self.memory.add_workflow(workflow).await
+    }
+
+    async fn lastest_workflow(&self, name: String) -> Result<u64> {
+        self.memory.lastest_workflow(name).await
+    }
+
+    async fn get_workflow(&self, name: String, checksum: Option<u64>) -> Result<Workflow> {
+        self.memory.get_workflow(name, checksum).await
+    }
+
+    async fn get_latest_workflows(&self) -> Result<Vec<Workflow>> {
+        self.memory.get_latest_workflows().await
+    }
+
+    async fn add_resource(
+        &self,
+        namespace: String,
+        kind: String,
+        name: String,
+        workflow: String,
+        annotations: BTreeMap<String, String>,
+        ready: bool,
+    ) -> Result<()> {
+        self.memory
+            .add_resource(namespace, kind, name, workflow, annotations, ready)
+            .await
+    }
+
+    async fn remove_resource(&self, namespace: String, kind: String, name: String) -> Result<()> {
+        self.memory.remove_resource(namespace, kind, name).await
+    }
+
+    async fn workflow_resources(&self, workflow: String) -> Result<Vec<KnownResource>> {
+        self.memory.workflow_resources(workflow).await
+    }
+
+    async fn namespace_resources(&self, namespace: String) -> Result<Vec<KnownResource>> {
+        self.memory.namespace_resources(namespace).await
+    }
+
+    async fn kind_resources(&self, kind: String) -> Result<Vec<KnownResource>> {
+        self.memory.kind_resources(kind).await
+    }
+
+    async fn enable_namespace(&self, name: String) -> Result<()> {
+        self.memory.enable_namespace(name).await
+    }
+
+    async fn disable_namespace(&self, name: String) -> Result<()> {
+        self.memory.disable_namespace(name).await
+    }
+
+    async fn namespace_enabled(&self, name: String) -> Result<bool> {
+        self.memory.namespace_enabled(name).await
+    }
+
+    async fn enabled_namespaces(&self) -> Result<Vec<String>> {
+        self.memory.enabled_namespaces().await
+    }
+
+    fn is_resource_ready(&self, namespace: String, kind: String, name: String) -> bool {
+        self.memory.is_resource_ready(namespace, kind, name)
+    }
+
+    async fn current_version(&self, workspace_name: String) -> Option<String> {
+        self.memory.current_version(workspace_name).await
+    }
+
+    async fn add_history(&self, record: HistoryRecord) -> Result<()> {
+        let workflow = record.workflow.clone();
+        let namespace = record.namespace.clone();
+        self.memory.add_history(record).await?;
+        self.memory
+            .limit_history(&workflow, &namespace, self.max_records);
+        self.persist_history(&workflow, self.shard(&namespace))
+            .await
+    }
+
+    async fn get_history(
+        &self,
+        workflow: String,
+        namespace: Option<String>,
+    ) -> Result<Vec<HistoryRecord>> {
+        self.memory.get_history(workflow, namespace).await
+    }
+
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<usize> {
+        // The shards that records are pruned from are written again.
+        let workflows: Vec<String> = self.persisted.lock().iter().cloned().collect();
+        let mut pruned_shards = BTreeSet::new();
+        for workflow in &workflows {
+            for record in self
+                .memory
+                .filter_history(workflow, |record| record.finished_at < before)
+            {
+                pruned_shards.insert((workflow.clone(), self.shard(&record.namespace)));
+            }
+        }
+
+        let pruned = self.memory.prune_history(before).await?;
+        for (workflow, shard) in pruned_shards {
+            self.persist_history(&workflow, shard).await?;
+        }
+        Ok(pruned)
+    }
+
+    async fn set_plan(&self, plan: WorkflowPlan) -> Result<()> {
+        self.memory.set_plan(plan).await
+    }
+
+    async fn get_plans(
+        &self,
+        workflow: String,
+        namespace: Option<String>,
+    ) -> Result<Vec<WorkflowPlan>> {
+        self.memory.get_plans(workflow, namespace).await
+    }
+
+    async fn set_checkpoint(&self, checkpoint: JobCheckpoint) -> Result<()> {
+        let workflow = checkpoint.workflow.clone();
+        self.memory.set_checkpoint(checkpoint).await?;
+        self.persist_checkpoints(&workflow).await
+    }
+
+    async fn get_checkpoints(&self) -> Result<Vec<JobCheckpoint>> {
+        self.memory.get_checkpoints().await
+    }
+
+    async fn take_checkpoints(&self, workflow: String) -> Result<Vec<JobCheckpoint>> {
+        let checkpoints = self.memory.take_checkpoints(workflow.clone()).await?;
+        if !checkpoints.is_empty() {
+            self.persist_checkpoints(&workflow).await?;
+        }
+        Ok(checkpoints)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::{crd_storage::HistoryEntry, fake_api::FakeApi};
+
+    fn settings() -> Settings {
+        let mut settings = Settings::new().unwrap();
+        settings.storage.namespace = "workflow-deploy".to_string();
+        settings
+    }
+
+    fn record(
+        workflow: &str,
+        namespace: &str,
+        version: &str,
+        finished_at: DateTime<Utc>,
+    ) -> HistoryRecord {
+        HistoryRecord {
+            workflow: workflow.to_string(),
+            checksum: 1,
+            namespace: namespace.to_string(),
+            started_at: finished_at,
+            finished_at,
+            succeeded: true,
+            reason: None,
+            from_version: None,
+            to_version: version.to_string(),
+            triggered_by: None,
+            entries: vec![],
+        }
+    }
+
+    // The state ConfigMaps of a workflow, by name.
+    fn state_config_maps(api: &FakeApi, workflow: &str) -> BTreeMap<String, serde_json::Value> {
+        api.list("configmaps")
+            .into_iter()
+            .filter(|config_map| {
+                config_map["metadata"]["labels"][STATE_LABEL] == workflow_label(workflow)
+                    || config_map["metadata"]["labels"][STATE_LABEL] == workflow
+            })
+            .map(|config_map| {
+                (
+                    config_map["metadata"]["name"].as_str().unwrap().to_string(),
+                    config_map,
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_history_survives_reload() {
+        let api = FakeApi::default();
+        let now = Utc::now();
+        let storage = ConfigMapWorkflowStorager::load(api.client(), &settings())
+            .await
+            .unwrap();
+        storage
+            .add_history(record("tenants", "foo", "1.4.1", now - Duration::days(40)))
+            .await
+            .unwrap();
+        storage
+            .add_history(record("tenants", "foo", "1.4.2", now))
+            .await
+            .unwrap();
+        storage
+            .add_history(record("internal", "bar", "2.0.0", now))
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .prune_history(now - Duration::days(30))
+                .await
+                .unwrap(),
+            1
+        );
+        drop(storage);
+
+        // A new storage reads the history back from the ConfigMaps.
+        let storage = ConfigMapWorkflowStorager::load(api.client(), &settings())
+            .await
+            .unwrap();
+        let history = storage
+            .get_history("tenants".to_string(), Some("foo".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(history, [record("tenants", "foo", "1.4.2", now)]);
+        assert_eq!(
+            storage
+                .get_history("internal".to_string(), None)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(api
+            .get(
+                "configmaps",
+                "workflow-deploy",
+                &history_config_map_name("tenants", storage.shard("foo"))
+            )
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_unsharded_history_is_split() {
+        let api = FakeApi::default();
+        let now = Utc::now();
+        let history = vec![
+            record("tenants", "foo", "1.4.1", now),
+            record("tenants", "bar", "1.4.1", now),
+        ];
+        api.apply(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {
+                "name": "workflow-deploy-state-tenants",
+                "namespace": "workflow-deploy",
+                "labels": {STATE_LABEL: "tenants"}
+            },
+            "data": {
+                HISTORY_KEY: serde_json::to_string(&history).unwrap(),
+                CHECKPOINTS_KEY: "[]"
+            }
+        }));
+
+        let storage = ConfigMapWorkflowStorager::load(api.client(), &settings())
+            .await
+            .unwrap();
+        let config_map = api
+            .get(
+                "configmaps",
+                "workflow-deploy",
+                "workflow-deploy-state-tenants",
+            )
+            .unwrap();
+        assert!(config_map["data"][HISTORY_KEY].is_null());
+        assert_eq!(config_map["data"][CHECKPOINTS_KEY], "[]");
+        assert!(api
+            .get(
+                "configmaps",
+                "workflow-deploy",
+                &history_config_map_name("tenants", storage.shard("bar"))
+            )
+            .is_some());
+        drop(storage);
+
+        // The history isn't read twice after it has been split.
+        let storage = ConfigMapWorkflowStorager::load(api.client(), &settings())
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .get_history("tenants".to_string(), None)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        drop(storage);
+
+        // With fewer shards, the history is split again and the shards that
+        // are no longer used are deleted.
+        let mut settings = settings();
+        settings.storage.history_shards = 1;
+        ConfigMapWorkflowStorager::load(api.client(), &settings)
+            .await
+            .unwrap();
+        let storage = ConfigMapWorkflowStorager::load(api.client(), &settings)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .get_history("tenants".to_string(), None)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            state_config_maps(&api, "tenants")
+                .into_keys()
+                .collect::<Vec<String>>(),
+            [
+                "workflow-deploy-history-tenants-0",
+                "workflow-deploy-state-tenants"
+            ]
+        );
+    }
+
+    // Fills the history with a month of daily deploys to thousands of
+    // namespaces, without writing it to ConfigMaps. That is about 30 MiB of
+    // records, far more than a single ConfigMap holds.
+    async fn fill_history(storage: &ConfigMapWorkflowStorager, now: DateTime<Utc>) {
+        for day in (1..=30).rev() {
+            for namespace in 0..2_000 {
+                let finished_at = now - Duration::days(day);
+                let mut record = record(
+                    "tenants",
+                    &format!("tenant-{namespace}"),
+                    &format!("1.0.{}", 30 - day),
+                    finished_at,
+                );
+                record.from_version = Some(format!("1.0.{}", 29 - day));
+                record.entries = vec![
+                    HistoryEntry {
+                        action: "update_deployment".to_string(),
+                        target: Some("app".to_string()),
+                        at: finished_at,
+                        before: Some(format!("registry.local/tenants/app:1.0.{}", 29 - day)),
+                        after: Some(format!("registry.local/tenants/app:1.0.{}", 30 - day)),
+                    },
+                    HistoryEntry {
+                        action: "wait_deployment_ready".to_string(),
+                        target: Some("app".to_string()),
+                        at: finished_at,
+                        before: None,
+                        after: None,
+                    },
+                ];
+                storage.memory.add_history(record).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history_is_bounded() {
+        let api = FakeApi::default();
+        let now = Utc::now();
+        let storage = ConfigMapWorkflowStorager::load(api.client(), &settings())
+            .await
+            .unwrap();
+        fill_history(&storage, now).await;
+
+        // Every shard is written when the oldest day is pruned.
+        storage
+            .add_history(record("tenants", "tenant-0", "1.0.30", now))
+            .await
+            .unwrap();
+        storage
+            .prune_history(now - Duration::days(30) + Duration::hours(1))
+            .await
+            .unwrap();
+
+        let config_maps = state_config_maps(&api, "tenants");
+        assert_eq!(config_maps.len(), 64);
+        let mut records = 0;
+        for config_map in config_maps.values() {
+            let history = config_map["data"][HISTORY_KEY].as_str().unwrap();
+            assert!(history.len() < HISTORY_BYTES);
+            records += serde_json::from_str::<Vec<HistoryRecord>>(history)
+                .unwrap()
+                .len();
+        }
+        assert_eq!(records, 2_000 * 10);
+        // The records that were left out are forgotten in memory too.
+        assert_eq!(
+            storage
+                .get_history("tenants".to_string(), None)
+                .await
+                .unwrap()
+                .len(),
+            2_000 * 10
+        );
+
+        // The namespace that was just deployed keeps its latest records.
+        let history = storage
+            .get_history("tenants".to_string(), Some("tenant-0".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 10);
+        assert_eq!(history.last().unwrap().to_version, "1.0.30");
+
+        let storage = ConfigMapWorkflowStorager::load(api.client(), &settings())
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .get_history("tenants".to_string(), None)
+                .await
+                .unwrap()
+                .len(),
+            2_000 * 10
+        );
+    }
+
+    #[tokio::test]
+    async fn test_large_shards_are_trimmed() {
+        let api = FakeApi::default();
+        let now = Utc::now();
+        let mut settings = settings();
+        settings.storage.history_shards = 1;
+        settings.history.max_records = 30;
+        let storage = ConfigMapWorkflowStorager::load(api.client(), &settings)
+            .await
+            .unwrap();
+        fill_history(&storage, now).await;
+
+        // The write succeeds with the newest records that fit.
+        storage
+            .add_history(record("tenants", "tenant-0", "1.0.30", now))
+            .await
+            .unwrap();
+        let config_map = api
+            .get(
+                "configmaps",
+                "workflow-deploy",
+                &history_config_map_name("tenants", 0),
+            )
+            .unwrap();
+        let history: Vec<HistoryRecord> =
+            serde_json::from_str(config_map["data"][HISTORY_KEY].as_str().unwrap()).unwrap();
+        assert!(history.len() < 2_000 * 30);
+        assert_eq!(history.last().unwrap().to_version, "1.0.30");
+        // The history that is served is what survives a restart.
+        assert_eq!(
+            storage
+                .get_history("tenants".to_string(), None)
+                .await
+                .unwrap(),
+            history
+        );
+    }
+
+    #[tokio::test]
+    async fn test_long_workflow_names() {
+        let api = FakeApi::default();
+        let now = Utc::now();
+        let workflow = "tenants-".repeat(12);
+        let storage = ConfigMapWorkflowStorager::load(api.client(), &settings())
+            .await
+            .unwrap();
+        storage
+            .add_history(record(&workflow, "foo", "1.0.0", now))
+            .await
+            .unwrap();
+
+        // Label values are limited to 63 characters, so the ConfigMaps are
+        // labelled with a hash of the name and annotated with the name.
+        let config_maps = state_config_maps(&api, &workflow);
+        assert_eq!(config_maps.len(), 1);
+        for config_map in config_maps.values() {
+            let label = config_map["metadata"]["labels"][STATE_LABEL]
+                .as_str()
+                .unwrap();
+            assert!(label.len() <= 63);
+            assert_eq!(
+                config_map["metadata"]["annotations"][WORKFLOW_ANNOTATION],
+                workflow.as_str()
+            );
+        }
+        drop(storage);
+
+        let storage = ConfigMapWorkflowStorager::load(api.client(), &settings())
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_history(workflow.clone(), None).await.unwrap(),
+            [record(&workflow, "foo", "1.0.0", now)]
+        );
+    }
+}
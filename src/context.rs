@@ -3,11 +3,17 @@ use std::panic::RefUnwindSafe;
 use std::sync::Arc;
 
 use cadence::MetricClient;
+use kube::Client;
 use tokio::sync::mpsc::Sender;
 
 use crate::action::Action;
+use crate::clock::Clock;
 use crate::config::Settings;
 use crate::crd_storage::WorkflowStorage;
+use crate::health::Health;
+use crate::notify::Notifier;
+use crate::readiness::{DeploymentState, Readiness};
+use crate::recorder::Recorder;
 
 #[derive(Clone)]
 pub(crate) struct Context(pub(crate) Arc<InnerContext>);
@@ -15,23 +21,45 @@ pub(crate) struct Context(pub(crate) Arc<InnerContext>);
 pub(crate) struct InnerContext {
     #[allow(unused)]
     pub(crate) settings: Settings,
+    pub(crate) client: Client,
     pub(crate) workflow_storage: Box<dyn WorkflowStorage>,
     pub(crate) action_tx: Sender<Action>,
     pub(crate) metrics: Arc<dyn MetricClient + Send + Sync + RefUnwindSafe>,
+    pub(crate) http_client: reqwest::Client,
+    pub(crate) notifier: Notifier,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) recorder: Recorder,
+    pub(crate) readiness: Readiness<DeploymentState>,
+    // Whether each job that has finished completed.
+    pub(crate) finished_jobs: Readiness<bool>,
+    pub(crate) health: Health,
 }
 
 impl InnerContext {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         settings: Settings,
+        client: Client,
         workflow_storage: Box<dyn WorkflowStorage>,
         action_tx: Sender<Action>,
         metrics: Arc<dyn MetricClient + Send + Sync + RefUnwindSafe>,
+        notifier: Notifier,
+        clock: Arc<dyn Clock>,
+        recorder: Recorder,
     ) -> Self {
         Self {
             settings,
+            client,
             workflow_storage,
             action_tx,
             metrics,
+            http_client: reqwest::Client::new(),
+            notifier,
+            clock,
+            recorder,
+            readiness: Readiness::default(),
+            finished_jobs: Readiness::default(),
+            health: Health::default(),
         }
     }
 }
@@ -0,0 +1,186 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use anyhow::{anyhow, Result};
+use kube::{
+    core::{
+        conversion::{ConversionRequest, ConversionResponse, ConversionReview},
+        Status,
+    },
+    Resource,
+};
+use serde_json::Value;
+use tokio::sync::broadcast::Receiver;
+use tracing::{info, warn};
+use warp::{Filter, Rejection, Reply};
+
+use crate::{context::Context, crd::Workflow, crd_v1alpha};
+
+// Reads a workflow of any served version as the current version.
+pub(crate) fn canonical_workflow(object: Value) -> Result<Workflow> {
+    let api_version = object["apiVersion"].as_str().unwrap_or_default();
+    if api_version == crd_v1alpha::Workflow::api_version(&()) {
+        let workflow: crd_v1alpha::Workflow = serde_json::from_value(object)?;
+        Ok(workflow.into())
+    } else if api_version == Workflow::api_version(&()) {
+        Ok(serde_json::from_value(object)?)
+    } else {
+        Err(anyhow!("unsupported apiVersion {:?}", api_version))
+    }
+}
+
+// Converts a workflow object of any served version to the given version.
+pub(crate) fn convert_workflow(object: Value, api_version: &str) -> Result<Value> {
+    if object["apiVersion"].as_str() == Some(api_version) {
+        return Ok(object);
+    }
+    let workflow = canonical_workflow(object)?;
+    let mut converted = if api_version == crd_v1alpha::Workflow::api_version(&()) {
+        serde_json::to_value(crd_v1alpha::Workflow::from(workflow))?
+    } else if api_version == Workflow::api_version(&()) {
+        serde_json::to_value(workflow)?
+    } else {
+        return Err(anyhow!("unsupported apiVersion {:?}", api_version));
+    };
+    converted["apiVersion"] = api_version.into();
+    Ok(converted)
+}
+
+// Serves the conversion webhook for workflows over TLS. The certificate is
+// expected to be mounted from a secret.
+pub(crate) async fn conversion_loop(context: Context, shutdown: &mut Receiver<bool>) -> Result<()> {
+    let settings = &context.settings.conversion;
+    let listen: SocketAddr = settings.listen.parse()?;
+    let cert = std::fs::read(&settings.cert_path)
+        .map_err(|err| anyhow!("unable to read {}: {}", settings.cert_path, err))?;
+    let key = std::fs::read(&settings.key_path)
+        .map_err(|err| anyhow!("unable to read {}: {}", settings.key_path, err))?;
+    let mut shutdown = shutdown.resubscribe();
+
+    let (address, server) = warp::serve(routes(context))
+        .tls()
+        .cert(cert)
+        .key(key)
+        .try_bind_with_graceful_shutdown(listen, async move {
+            let _ = shutdown.recv().await;
+        })?;
+    info!("conversion loop started on {}", address);
+
+    server.await;
+
+    info!("conversion loop ended");
+    Ok(())
+}
+
+pub(crate) fn routes(
+    context: Context,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let with_context = warp::any().map(move || context.clone());
+
+    warp::post()
+        .and(warp::path!("convert" / "workflows"))
+        .and(warp::body::json())
+        .and(with_context)
+        .and_then(convert)
+}
+
+async fn convert(review: ConversionReview, context: Context) -> Result<impl Reply, Infallible> {
+    let request = match ConversionRequest::from_review(review) {
+        Ok(request) => request,
+        Err(err) => {
+            warn!("invalid conversion review: {}", err);
+            return Ok(warp::reply::json(
+                &ConversionResponse::invalid(Status::failure(&err.to_string(), "InvalidRequest"))
+                    .into_review(),
+            ));
+        }
+    };
+
+    let api_version = request.desired_api_version.clone();
+    let objects = request.objects.clone();
+    let response = ConversionResponse::for_request(request);
+
+    // Objects are converted all or nothing.
+    let converted: Result<Vec<Value>> = objects
+        .into_iter()
+        .map(|object| convert_workflow(object, &api_version))
+        .collect();
+
+    context
+        .metrics
+        .count_with_tags("conversion.review", 1)
+        .with_tag("api_version", api_version.as_str())
+        .with_tag("succeeded", converted.is_ok().to_string().as_str())
+        .send();
+
+    let response = match converted {
+        Ok(converted) => response.success(converted),
+        Err(err) => {
+            info!("unable to convert workflows to {}: {}", api_version, err);
+            response.failure(Status::failure(&err.to_string(), "ConversionFailed"))
+        }
+    };
+
+    Ok(warp::reply::json(&response.into_review()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_context;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_convert() {
+        let routes = routes(test_context());
+        let v1alpha = json!({
+            "apiVersion": "workflow-deploy.ngerakines.me/v1alpha",
+            "kind": "Workflow",
+            "metadata": {"name": "tenants"},
+            "spec": {
+                "namespaces": ["foo"],
+                "version": "1.0.1",
+                "supression": [],
+                "steps": [{"actions": [{"action": "restart_deployment", "targets": [{"resource": "Deployment", "name": "app"}]}]}]
+            }
+        });
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/convert/workflows")
+            .json(&json!({
+                "apiVersion": "apiextensions.k8s.io/v1",
+                "kind": "ConversionReview",
+                "request": {
+                    "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                    "desiredAPIVersion": "workflow-deploy.ngerakines.me/v1beta1",
+                    "objects": [v1alpha]
+                }
+            }))
+            .reply(&routes)
+            .await;
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body["response"]["uid"],
+            "705ab4f5-6393-11e8-b7cc-42010a800002"
+        );
+        assert_eq!(body["response"]["result"]["status"], "Success");
+        let converted = &body["response"]["convertedObjects"][0];
+        assert_eq!(
+            converted["apiVersion"],
+            "workflow-deploy.ngerakines.me/v1beta1"
+        );
+        assert_eq!(
+            converted["spec"]["steps"][0]["actions"][0],
+            json!({"restart_deployment": {"targets": [{"name": "app"}]}})
+        );
+
+        let round_trip =
+            convert_workflow(converted.clone(), "workflow-deploy.ngerakines.me/v1alpha").unwrap();
+        assert_eq!(round_trip["apiVersion"], v1alpha["apiVersion"]);
+        assert_eq!(
+            round_trip["spec"]["steps"][0]["actions"][0]["targets"][0]["resource"],
+            "Deployment"
+        );
+        assert!(convert_workflow(v1alpha, "workflow-deploy.ngerakines.me/v1").is_err());
+    }
+}
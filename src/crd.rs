@@ -1,101 +1,355 @@
-use fnv::FnvHasher;
-use kube::CustomResource;
-use schemars::JsonSchema;
+use anyhow::{anyhow, Result};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::{core::crd::merge_crds, CustomResource, CustomResourceExt, Resource};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::{Deserialize, Serialize};
-use std::hash::Hasher;
+use std::collections::BTreeMap;
+
+use crate::crd_v1alpha;
+
+// Allows arbitrary objects, such as inline Kubernetes resource specs, that
+// are validated by the API server when they are used.
+pub(crate) fn preserve_unknown_fields(_: &mut SchemaGenerator) -> Schema {
+    serde_json::from_value(serde_json::json!({
+        "type": "object",
+        "nullable": true,
+        "x-kubernetes-preserve-unknown-fields": true
+    }))
+    .expect("schema is valid")
+}
+
+// Adds CEL rules that the API server evaluates against an object.
+fn with_validations(mut schema: Schema, rules: &[(&str, &str)]) -> Schema {
+    if let Schema::Object(object) = &mut schema {
+        object.extensions.insert(
+            "x-kubernetes-validations".to_string(),
+            rules
+                .iter()
+                .map(|(rule, message)| serde_json::json!({"rule": rule, "message": message}))
+                .collect(),
+        );
+    }
+    schema
+}
+
+// A list whose items are validated with CEL rules.
+pub(crate) fn validated_list<T: JsonSchema>(
+    gen: &mut SchemaGenerator,
+    min_items: Option<u32>,
+    rules: &[(&str, &str)],
+) -> Schema {
+    let mut schema = serde_json::json!({
+        "type": "array",
+        "items": with_validations(T::json_schema(gen), rules)
+    });
+    if let Some(min_items) = min_items {
+        schema["minItems"] = min_items.into();
+    }
+    serde_json::from_value(schema).expect("schema is valid")
+}
+
+pub(crate) fn step_analysis(gen: &mut SchemaGenerator) -> Schema {
+    validated_list::<WorkflowAnalysis>(
+        gen,
+        None,
+        &[(
+            "!has(self.min) || !has(self.max) || self.min <= self.max",
+            "min must not be greater than max",
+        )],
+    )
+}
+
+pub(crate) fn unique_strings(_: &mut SchemaGenerator) -> Schema {
+    serde_json::from_value(serde_json::json!({
+        "type": "array",
+        "minItems": 1,
+        "items": {"type": "string"},
+        "x-kubernetes-list-type": "set"
+    }))
+    .expect("schema is valid")
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowEnvVar {
+    pub(crate) name: String,
+    // When not set, the variable is removed from the container.
+    pub(crate) value: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowImageTarget {
+    pub(crate) name: String,
+    // The containers whose image tag is set to the workflow version.
+    #[schemars(length(min = 1))]
+    pub(crate) containers: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowEnvTarget {
+    pub(crate) name: String,
+    // When empty, every container is updated.
+    #[serde(default)]
+    pub(crate) containers: Vec<String>,
+    pub(crate) env: Vec<WorkflowEnvVar>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowConfigTarget {
+    pub(crate) name: String,
+    pub(crate) data: BTreeMap<String, String>,
+    // Deployments that consume the config map and are restarted when it changes.
+    #[serde(default)]
+    pub(crate) deployments: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowDeploymentTarget {
+    pub(crate) name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowScaleTarget {
+    pub(crate) name: String,
+    // Either an absolute count like "3" or a relative change like "+2" or "-1".
+    #[schemars(regex(pattern = r"^[+-]?[0-9]+$"))]
+    pub(crate) replicas: String,
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
-pub(crate) struct WorkflowStepActionTarget {
-    pub(crate) resource: String,
+pub(crate) struct WorkflowJobTarget {
     pub(crate) name: String,
+    // An inline job spec used to create the job.
+    #[serde(default)]
+    #[schemars(schema_with = "preserve_unknown_fields")]
+    pub(crate) job: Option<serde_json::Value>,
+    // The name of a cron job in the namespace whose job template is used to
+    // create the job.
+    pub(crate) template: Option<String>,
+    #[serde(default)]
     pub(crate) containers: Vec<String>,
+    // Defaults to 600.
+    #[schemars(range(min = 1))]
+    pub(crate) timeout_seconds: Option<u32>,
+    // Deletes the job after it completes.
+    pub(crate) cleanup: Option<bool>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
-pub(crate) struct WorkflowStepAction {
-    pub(crate) action: String,
-    pub(crate) targets: Vec<WorkflowStepActionTarget>,
+pub(crate) struct WorkflowHttpCheckTarget {
+    pub(crate) name: String,
+    pub(crate) url: String,
+    // The accepted response status codes. Defaults to 200.
+    #[schemars(inner(range(min = 100, max = 599)))]
+    pub(crate) status: Option<Vec<u16>>,
+    // Text that the response body must contain.
+    pub(crate) body: Option<String>,
+    // Seconds between requests. Defaults to 5.
+    #[schemars(range(min = 1))]
+    pub(crate) interval_seconds: Option<u32>,
+    // Defaults to 120.
+    #[schemars(range(min = 1))]
+    pub(crate) timeout_seconds: Option<u32>,
+    // Consecutive successful requests required. Defaults to 1.
+    #[schemars(range(min = 1))]
+    pub(crate) successes: Option<u32>,
+}
+
+fn job_targets(gen: &mut SchemaGenerator) -> Schema {
+    validated_list::<WorkflowJobTarget>(
+        gen,
+        Some(1),
+        &[(
+            "has(self.job) || has(self.template)",
+            "run_job targets must set job or template",
+        )],
+    )
+}
+
+fn unknown_targets(gen: &mut SchemaGenerator) -> Schema {
+    serde_json::from_value(serde_json::json!({
+        "type": "array",
+        "items": preserve_unknown_fields(gen)
+    }))
+    .expect("schema is valid")
+}
+
+// Each action is keyed by its name, for example `{"scale": {"targets": [...]}}`.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WorkflowStepAction {
+    UpdateDeployment {
+        #[schemars(length(min = 1))]
+        targets: Vec<WorkflowImageTarget>,
+    },
+    UpdateEnv {
+        #[schemars(length(min = 1))]
+        targets: Vec<WorkflowEnvTarget>,
+    },
+    UpdateConfig {
+        #[schemars(length(min = 1))]
+        targets: Vec<WorkflowConfigTarget>,
+    },
+    RestartDeployment {
+        #[schemars(length(min = 1))]
+        targets: Vec<WorkflowDeploymentTarget>,
+    },
+    Scale {
+        #[schemars(length(min = 1))]
+        targets: Vec<WorkflowScaleTarget>,
+    },
+    RunJob {
+        #[schemars(schema_with = "job_targets")]
+        targets: Vec<WorkflowJobTarget>,
+    },
+    HttpCheck {
+        #[schemars(length(min = 1))]
+        targets: Vec<WorkflowHttpCheckTarget>,
+    },
+    // An action that this version doesn't know, kept as it was so that
+    // workflows converted from v1alpha can still be read. It is skipped by the
+    // controller and reported by validation.
+    Unknown {
+        action: String,
+        #[serde(default)]
+        #[schemars(schema_with = "unknown_targets")]
+        targets: Vec<serde_json::Value>,
+    },
+}
+
+impl WorkflowStepAction {
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            WorkflowStepAction::UpdateDeployment { .. } => "update_deployment",
+            WorkflowStepAction::UpdateEnv { .. } => "update_env",
+            WorkflowStepAction::UpdateConfig { .. } => "update_config",
+            WorkflowStepAction::RestartDeployment { .. } => "restart_deployment",
+            WorkflowStepAction::Scale { .. } => "scale",
+            WorkflowStepAction::RunJob { .. } => "run_job",
+            WorkflowStepAction::HttpCheck { .. } => "http_check",
+            WorkflowStepAction::Unknown { action, .. } => action,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            WorkflowStepAction::UpdateDeployment { targets } => targets.is_empty(),
+            WorkflowStepAction::UpdateEnv { targets } => targets.is_empty(),
+            WorkflowStepAction::UpdateConfig { targets } => targets.is_empty(),
+            WorkflowStepAction::RestartDeployment { targets } => targets.is_empty(),
+            WorkflowStepAction::Scale { targets } => targets.is_empty(),
+            WorkflowStepAction::RunJob { targets } => targets.is_empty(),
+            WorkflowStepAction::HttpCheck { targets } => targets.is_empty(),
+            WorkflowStepAction::Unknown { targets, .. } => targets.is_empty(),
+        }
+    }
+}
+
+// An analysis queries a Prometheus-compatible API after the actions of a step
+// have completed, failing the group if any value is outside of the thresholds.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowAnalysis {
+    pub(crate) name: String,
+    // The query can reference `{{namespace}}` and `{{version}}`.
+    pub(crate) query: String,
+    pub(crate) min: Option<f64>,
+    pub(crate) max: Option<f64>,
+    // How long to keep evaluating the query. Defaults to 300 seconds.
+    #[schemars(range(min = 1))]
+    pub(crate) window_seconds: Option<u32>,
+    // Seconds between queries. Defaults to 30.
+    #[schemars(range(min = 1))]
+    pub(crate) interval_seconds: Option<u32>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub(crate) struct WorkflowStep {
+    #[schemars(length(min = 1))]
     pub(crate) actions: Vec<WorkflowStepAction>,
+    #[serde(default)]
+    #[schemars(schema_with = "step_analysis")]
+    pub(crate) analysis: Vec<WorkflowAnalysis>,
+}
+
+// A webhook that receives notifications for the workflow in addition to the
+// webhooks configured for the controller.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowNotification {
+    pub(crate) url: String,
+    // When empty, every event is sent.
+    #[serde(default)]
+    pub(crate) events: Vec<String>,
+    pub(crate) template: Option<String>,
+}
+
+// Set by the controller as groups are dispatched and finish.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowStatus {
+    // The number of groups in flight.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) in_flight: Option<u32>,
+    // "Succeeded" or "Failed", for the group that finished last.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) last_result: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) last_group: Option<String>,
 }
 
+// The current version of the workflow CRD. This is the version that is
+// stored and the one that the controller works with.
 #[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
 #[kube(
     group = "workflow-deploy.ngerakines.me",
-    version = "v1alpha",
+    version = "v1beta1",
     kind = "Workflow",
-    plural = "workflows"
+    plural = "workflows",
+    shortname = "wf",
+    status = "WorkflowStatus",
+    printcolumn = r#"{"name": "Version", "type": "string", "jsonPath": ".spec.version"}"#,
+    printcolumn = r#"{"name": "Parallel", "type": "integer", "jsonPath": ".spec.parallel"}"#,
+    printcolumn = r#"{"name": "In-Flight", "type": "integer", "jsonPath": ".status.in_flight"}"#,
+    printcolumn = r#"{"name": "Last-Result", "type": "string", "jsonPath": ".status.last_result"}"#,
+    printcolumn = r#"{"name": "Age", "type": "date", "jsonPath": ".metadata.creationTimestamp"}"#
 )]
 pub(crate) struct WorkflowSpec {
+    #[schemars(schema_with = "unique_strings")]
     pub(crate) namespaces: Vec<String>,
+    #[schemars(length(min = 1))]
     pub(crate) version: String,
+    #[schemars(range(max = 86400))]
     pub(crate) debounce: Option<u32>,
+    #[schemars(range(min = 1, max = 255))]
     pub(crate) parallel: Option<u32>,
-    pub(crate) supression: Vec<String>,
+    #[serde(default)]
+    pub(crate) suppressions: Vec<String>,
     pub(crate) steps: Vec<WorkflowStep>,
+    #[serde(default)]
+    pub(crate) notifications: Vec<WorkflowNotification>,
 }
 
 impl Workflow {
+    // Workflows are hashed in their v1alpha form, so that the checksums of
+    // workflows created before v1beta1 don't change and they aren't deployed
+    // again.
     pub(crate) fn checksum(&self) -> u64 {
-        let mut hasher = FnvHasher::default();
-        hasher.write(format!("version={}", self.spec.version).as_bytes());
-
-        let mut namespaces = self.spec.namespaces.clone();
-        namespaces.sort();
-        for namespace in namespaces {
-            hasher.write(format!("namespace={namespace}").as_bytes());
-        }
-
-        hasher.write(format!("debounce={}", self.spec.debounce.unwrap_or_default()).as_bytes());
-
-        let mut supression = self.spec.supression.clone();
-        supression.sort();
-        for value in supression.iter() {
-            hasher.write(format!("supression={}", value).as_bytes());
-        }
-
-        for step in self.spec.steps.iter() {
-            hasher.write(format!("step={}", step.checksum()).as_bytes());
-        }
-
-        hasher.finish()
+        crd_v1alpha::WorkflowSpec::from_workflow(self).checksum()
     }
 }
 
-impl WorkflowStep {
-    pub(crate) fn checksum(&self) -> u64 {
-        let mut hasher = FnvHasher::default();
-        for action in self.actions.iter() {
-            hasher.write(format!("step={}", action.checksum()).as_bytes());
-        }
-        hasher.finish()
-    }
-}
-
-impl WorkflowStepAction {
-    pub(crate) fn checksum(&self) -> u64 {
-        let mut hasher = FnvHasher::default();
-        for target in self.targets.iter() {
-            hasher.write(format!("step={}", target.checksum()).as_bytes());
-        }
-        hasher.finish()
-    }
-}
-
-impl WorkflowStepActionTarget {
-    pub(crate) fn checksum(&self) -> u64 {
-        let mut hasher = FnvHasher::default();
-        hasher.write(format!("resource={} name={}", self.resource, self.name).as_bytes());
-        let mut containers = self.containers.clone();
-        containers.sort();
-        for container in containers {
-            hasher.write(format!("container={}", container).as_bytes());
+// The workflow CRD with every served version, limited to a single version
+// when one is given.
+pub(crate) fn workflow_crd(version: Option<&str>) -> Result<CustomResourceDefinition> {
+    let mut crd = merge_crds(
+        vec![crd_v1alpha::Workflow::crd(), Workflow::crd()],
+        &Workflow::version(&()),
+    )?;
+    if let Some(version) = version {
+        crd.spec.versions.retain(|x| x.name == version);
+        if crd.spec.versions.is_empty() {
+            return Err(anyhow!("unknown workflow version {}", version));
         }
-        hasher.finish()
+        crd.spec.versions[0].storage = true;
     }
+    Ok(crd)
 }
 
 #[cfg(test)]
@@ -106,15 +360,134 @@ mod tests {
     async fn test_workflow_checksum() {
         let workflow = Workflow {
             metadata: Default::default(),
+            status: None,
             spec: WorkflowSpec {
                 version: "v1".to_string(),
                 namespaces: vec!["default".to_string()],
                 parallel: None,
                 debounce: None,
-                supression: vec![],
+                suppressions: vec![],
                 steps: vec![],
+                notifications: vec![],
             },
         };
         assert_eq!(workflow.checksum(), 8856693534762849072);
     }
+
+    #[test]
+    fn test_workflow_checksum_with_steps() {
+        let workflow: crd_v1alpha::Workflow = serde_yaml::from_str(
+            r#"
+apiVersion: workflow-deploy.ngerakines.me/v1alpha
+kind: Workflow
+metadata:
+  name: tenants
+spec:
+  namespaces: ["default"]
+  version: "v1"
+  supression: []
+  steps:
+  - actions:
+    - action: update_deployment
+      targets:
+      - resource: Deployment
+        name: app
+        containers: ["sidecar", "app"]
+"#,
+        )
+        .unwrap();
+        // The checksum that the workflow had before there were other actions.
+        assert_eq!(workflow.spec.checksum(), 4446787353741151948);
+        assert_eq!(
+            Workflow::from(workflow.clone()).checksum(),
+            4446787353741151948
+        );
+
+        // Other actions with the same targets are hashed differently.
+        let mut restart = workflow;
+        restart.spec.steps[0].actions[0].action = "restart_deployment".to_string();
+        assert_ne!(restart.spec.checksum(), 4446787353741151948);
+    }
+
+    #[test]
+    fn test_workflow_crd() {
+        let crd = serde_json::to_value(workflow_crd(None).unwrap()).unwrap();
+        let version = &crd["spec"]["versions"][1];
+        assert_eq!(version["name"], "v1alpha");
+        assert_eq!(version["storage"], false);
+        assert_eq!(
+            crd["spec"]["names"]["shortNames"],
+            serde_json::json!(["wf"])
+        );
+        assert_eq!(version["subresources"]["status"], serde_json::json!({}));
+        assert_eq!(
+            version["additionalPrinterColumns"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|column| column["name"].as_str().unwrap())
+                .collect::<Vec<&str>>(),
+            vec!["Version", "Parallel", "In-Flight", "Last-Result", "Age"]
+        );
+
+        let spec = &version["schema"]["openAPIV3Schema"]["properties"]["spec"];
+        assert_eq!(spec["properties"]["parallel"]["minimum"], 1.0);
+        let step = &spec["properties"]["steps"]["items"];
+        assert_eq!(step["required"], serde_json::json!(["actions"]));
+        let action = &step["properties"]["actions"]["items"];
+        assert_eq!(
+            action["properties"]["action"]["enum"],
+            serde_json::json!(crd_v1alpha::ACTIONS)
+        );
+        assert_eq!(
+            action["x-kubernetes-validations"].as_array().unwrap().len(),
+            3
+        );
+        assert_eq!(
+            action["properties"]["targets"]["items"]["required"],
+            serde_json::json!(["name", "resource"])
+        );
+
+        let version = &crd["spec"]["versions"][0];
+        assert_eq!(version["name"], "v1beta1");
+        assert_eq!(version["storage"], true);
+        let action = &version["schema"]["openAPIV3Schema"]["properties"]["spec"]["properties"]
+            ["steps"]["items"]["properties"]["actions"]["items"];
+        assert_eq!(
+            action["oneOf"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|variant| variant["required"][0].as_str().unwrap())
+                .collect::<Vec<&str>>(),
+            [&crd_v1alpha::ACTIONS[..], &["unknown"]].concat()
+        );
+        assert_eq!(
+            action["properties"]["scale"]["properties"]["targets"]["items"]["required"],
+            serde_json::json!(["name", "replicas"])
+        );
+
+        let crd = workflow_crd(Some("v1alpha")).unwrap();
+        assert_eq!(crd.spec.versions.len(), 1);
+        assert!(crd.spec.versions[0].storage);
+        assert!(workflow_crd(Some("v1")).is_err());
+    }
+
+    #[test]
+    fn test_chart_crd() {
+        // The chart's CRD is the one printed by dump-crd with the conversion
+        // webhook added. Helm fills in the templated lines, so they are left out.
+        let chart = include_str!("../chart/templates/crd.tpl")
+            .lines()
+            .filter(|line| !line.contains("{{"))
+            .collect::<Vec<&str>>()
+            .join("\n");
+        let chart: serde_json::Value = serde_yaml::from_str(&chart).unwrap();
+        let mut spec = chart["spec"].clone();
+        assert_eq!(spec["conversion"]["strategy"], "Webhook");
+        spec.as_object_mut().unwrap().remove("conversion");
+
+        let crd = serde_json::to_value(workflow_crd(None).unwrap()).unwrap();
+        assert_eq!(spec, crd["spec"], "regenerate the chart CRD with dump-crd");
+    }
 }
@@ -1,8 +1,8 @@
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use parking_lot::Mutex;
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use tracing::info;
 
@@ -20,13 +20,97 @@ pub(crate) struct KnownResource {
     pub(crate) ready: bool,
 }
 
+// A change made to a single target while processing a workflow job.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct HistoryEntry {
+    pub(crate) action: String,
+    pub(crate) target: Option<String>,
+    pub(crate) at: DateTime<Utc>,
+    pub(crate) before: Option<String>,
+    pub(crate) after: Option<String>,
+}
+
+// The outcome of a workflow job for a group (namespace).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct HistoryRecord {
+    pub(crate) workflow: String,
+    pub(crate) checksum: u64,
+    pub(crate) namespace: String,
+    pub(crate) started_at: DateTime<Utc>,
+    pub(crate) finished_at: DateTime<Utc>,
+    pub(crate) succeeded: bool,
+    pub(crate) reason: Option<String>,
+    pub(crate) from_version: Option<String>,
+    pub(crate) to_version: String,
+    pub(crate) triggered_by: Option<String>,
+    pub(crate) entries: Vec<HistoryEntry>,
+}
+
+// A change that a workflow job would make to a target in dry-run mode.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct PlanStep {
+    pub(crate) action: String,
+    pub(crate) target: String,
+    // The patch or object that would be sent, or none for actions that only wait.
+    pub(crate) patch: Option<serde_json::Value>,
+}
+
+// The changes that a workflow job would make to a group (namespace).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct WorkflowPlan {
+    pub(crate) workflow: String,
+    pub(crate) checksum: u64,
+    pub(crate) namespace: String,
+    pub(crate) version: String,
+    pub(crate) planned_at: DateTime<Utc>,
+    pub(crate) ok: bool,
+    pub(crate) reason: Option<String>,
+    pub(crate) steps: Vec<PlanStep>,
+}
+
+// Where the job of a group stopped when the controller shut down, so that it
+// can resume from there when the controller starts again.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct JobCheckpoint {
+    pub(crate) workflow: String,
+    pub(crate) checksum: u64,
+    pub(crate) namespace: String,
+    // The number of actions of the job that were completed.
+    pub(crate) completed: usize,
+    #[serde(default)]
+    pub(crate) changes: JobChanges,
+    pub(crate) stopped_at: DateTime<Utc>,
+}
+
+// What a job has changed so far that its later actions depend on, such as the
+// jobs to wait on and the replica counts to restore if the group fails.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub(crate) struct JobChanges {
+    // The replica count of each deployment before it was first scaled by the
+    // job, used to restore capacity if the job fails.
+    #[serde(default)]
+    pub(crate) previous_replicas: BTreeMap<String, i32>,
+    // The names of the jobs created by the job, keyed by target name.
+    #[serde(default)]
+    pub(crate) created_jobs: BTreeMap<String, String>,
+    // The generation of each deployment after it was last changed by the job.
+    #[serde(default)]
+    pub(crate) generations: BTreeMap<String, i64>,
+    // The images of each updated deployment before and after the update.
+    #[serde(default)]
+    pub(crate) images: BTreeMap<String, (String, String)>,
+    // The revision of each deployment before it was first changed by the job,
+    // so that only the pods of the revision rolled out by the job are watched.
+    #[serde(default)]
+    pub(crate) revisions: BTreeMap<String, String>,
+}
+
 #[async_trait]
 pub(crate) trait WorkflowStorage: Sync + Send {
     async fn add_workflow(&self, workflow: Workflow) -> Result<()>;
     async fn lastest_workflow(&self, name: String) -> Result<u64>;
     async fn get_workflow(&self, name: String, checksum: Option<u64>) -> Result<Workflow>;
     async fn get_latest_workflows(&self) -> Result<Vec<Workflow>>;
-    fn get_workflow_names(&self) -> Result<Vec<String>>;
 
     // Add a resource to the list of known resources.
     async fn add_resource(
@@ -40,18 +124,53 @@ pub(crate) trait WorkflowStorage: Sync + Send {
     ) -> Result<()>;
     // Remove a resource from the list of known resources.
     async fn remove_resource(&self, namespace: String, kind: String, name: String) -> Result<()>;
+    #[allow(unused)]
     async fn workflow_resources(&self, workflow: String) -> Result<Vec<KnownResource>>;
+    #[allow(unused)]
+    async fn namespace_resources(&self, namespace: String) -> Result<Vec<KnownResource>>;
+    // All known resources of a kind, used to prune resources that were deleted while a watcher was disconnected.
+    async fn kind_resources(&self, kind: String) -> Result<Vec<KnownResource>>;
 
     // Add a namespace to the list of namespaces that are enabled.
     async fn enable_namespace(&self, name: String) -> Result<()>;
     // Remove a namespace from the list of namespaces that are enabled.
     async fn disable_namespace(&self, name: String) -> Result<()>;
     // Check if a namespace is enabled. This will be called whenever a known resource has an action.
+    #[allow(unused)]
     async fn namespace_enabled(&self, name: String) -> Result<bool>;
+    async fn enabled_namespaces(&self) -> Result<Vec<String>>;
 
+    #[allow(unused)]
     fn is_resource_ready(&self, namespace: String, kind: String, name: String) -> bool;
 
     async fn current_version(&self, workspace_name: String) -> Option<String>;
+
+    // Record the outcome of a workflow job.
+    async fn add_history(&self, record: HistoryRecord) -> Result<()>;
+    // Get the history of a workflow, oldest first, optionally for a single namespace.
+    async fn get_history(
+        &self,
+        workflow: String,
+        namespace: Option<String>,
+    ) -> Result<Vec<HistoryRecord>>;
+    // Remove history that finished before the given time, returning the number of records removed.
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<usize>;
+
+    // Store the plan for a group, replacing the previous plan for it.
+    async fn set_plan(&self, plan: WorkflowPlan) -> Result<()>;
+    // Get the latest plans of a workflow, optionally for a single namespace.
+    async fn get_plans(
+        &self,
+        workflow: String,
+        namespace: Option<String>,
+    ) -> Result<Vec<WorkflowPlan>>;
+
+    // Store where a job stopped, replacing the previous checkpoint for the group.
+    async fn set_checkpoint(&self, checkpoint: JobCheckpoint) -> Result<()>;
+    // Get all checkpoints.
+    async fn get_checkpoints(&self) -> Result<Vec<JobCheckpoint>>;
+    // Remove and return the checkpoints of a workflow.
+    async fn take_checkpoints(&self, workflow: String) -> Result<Vec<JobCheckpoint>>;
 }
 
 #[derive(Default)]
@@ -75,10 +194,6 @@ impl WorkflowStorage for NullWorkflowStorager {
         Ok(vec![])
     }
 
-    fn get_workflow_names(&self) -> Result<Vec<String>> {
-        Ok(vec![])
-    }
-
     async fn add_resource(
         &self,
         _namespace: String,
@@ -112,10 +227,22 @@ impl WorkflowStorage for NullWorkflowStorager {
         Ok(true)
     }
 
+    async fn enabled_namespaces(&self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
     async fn workflow_resources(&self, _workflow: String) -> Result<Vec<KnownResource>> {
         Ok(vec![])
     }
 
+    async fn namespace_resources(&self, _namespace: String) -> Result<Vec<KnownResource>> {
+        Ok(vec![])
+    }
+
+    async fn kind_resources(&self, _kind: String) -> Result<Vec<KnownResource>> {
+        Ok(vec![])
+    }
+
     fn is_resource_ready(&self, _namespace: String, _kind: String, _name: String) -> bool {
         false
     }
@@ -123,6 +250,128 @@ impl WorkflowStorage for NullWorkflowStorager {
     async fn current_version(&self, _workspace_name: String) -> Option<String> {
         None
     }
+
+    async fn add_history(&self, _record: HistoryRecord) -> Result<()> {
+        Ok(())
+    }
+
+    async fn get_history(
+        &self,
+        _workflow: String,
+        _namespace: Option<String>,
+    ) -> Result<Vec<HistoryRecord>> {
+        Ok(vec![])
+    }
+
+    async fn prune_history(&self, _before: DateTime<Utc>) -> Result<usize> {
+        Ok(0)
+    }
+
+    async fn set_plan(&self, _plan: WorkflowPlan) -> Result<()> {
+        Ok(())
+    }
+
+    async fn get_plans(
+        &self,
+        _workflow: String,
+        _namespace: Option<String>,
+    ) -> Result<Vec<WorkflowPlan>> {
+        Ok(vec![])
+    }
+
+    async fn set_checkpoint(&self, _checkpoint: JobCheckpoint) -> Result<()> {
+        Ok(())
+    }
+
+    async fn get_checkpoints(&self) -> Result<Vec<JobCheckpoint>> {
+        Ok(vec![])
+    }
+
+    async fn take_checkpoints(&self, _workflow: String) -> Result<Vec<JobCheckpoint>> {
+        Ok(vec![])
+    }
+}
+
+// Resources are keyed by namespace, kind and name.
+type ResourceKey = (String, String, String);
+
+// Known resources with indexes by workflow, namespace and kind, so that lookups
+// only touch the resources they return with thousands of namespaces.
+#[derive(Default)]
+struct Resources {
+    resources: HashMap<ResourceKey, KnownResource>,
+    by_workflow: HashMap<String, HashSet<ResourceKey>>,
+    by_namespace: HashMap<String, HashSet<ResourceKey>>,
+    by_kind: HashMap<String, HashSet<ResourceKey>>,
+}
+
+impl Resources {
+    // Adds a resource, replacing the resource with the same key.
+    fn insert(&mut self, resource: KnownResource) {
+        let key = (
+            resource.namespace.clone(),
+            resource.kind.clone(),
+            resource.name.clone(),
+        );
+        self.remove(&key);
+        self.by_workflow
+            .entry(resource.workflow.clone())
+            .or_default()
+            .insert(key.clone());
+        self.by_namespace
+            .entry(resource.namespace.clone())
+            .or_default()
+            .insert(key.clone());
+        self.by_kind
+            .entry(resource.kind.clone())
+            .or_default()
+            .insert(key.clone());
+        self.resources.insert(key, resource);
+    }
+
+    fn remove(&mut self, key: &ResourceKey) -> Option<KnownResource> {
+        let resource = self.resources.remove(key)?;
+        unindex(&mut self.by_workflow, &resource.workflow, key);
+        unindex(&mut self.by_namespace, &resource.namespace, key);
+        unindex(&mut self.by_kind, &resource.kind, key);
+        Some(resource)
+    }
+
+    fn get(&self, key: &ResourceKey) -> Option<&KnownResource> {
+        self.resources.get(key)
+    }
+
+    fn in_workflow(&self, workflow: &str) -> Vec<KnownResource> {
+        self.collect(self.by_workflow.get(workflow))
+    }
+
+    fn in_namespace(&self, namespace: &str) -> Vec<KnownResource> {
+        self.collect(self.by_namespace.get(namespace))
+    }
+
+    fn of_kind(&self, kind: &str) -> Vec<KnownResource> {
+        self.collect(self.by_kind.get(kind))
+    }
+
+    fn collect(&self, keys: Option<&HashSet<ResourceKey>>) -> Vec<KnownResource> {
+        let mut resources: Vec<KnownResource> = keys
+            .into_iter()
+            .flatten()
+            .filter_map(|key| self.resources.get(key))
+            .cloned()
+            .collect();
+        resources.sort();
+        resources
+    }
+}
+
+fn unindex(index: &mut HashMap<String, HashSet<ResourceKey>>, value: &str, key: &ResourceKey) {
+    if let Some(keys) = index.get_mut(value) {
+        keys.remove(key);
+        if keys.is_empty() {
+            index.remove(value);
+        }
+    }
 }
 
 #[derive(Default)]
@@ -130,20 +379,84 @@ struct InnerMemoryWorkflowStorager {
     workflows: HashMap<u64, Workflow>,
     latest: HashMap<String, u64>,
 
-    resources: HashSet<KnownResource>,
+    resources: Resources,
     namespaces: HashSet<String>,
+
+    // History keyed by workflow name and namespace, oldest first.
+    history: BTreeMap<(String, String), Vec<HistoryRecord>>,
+    // Plans keyed by workflow name and namespace.
+    plans: BTreeMap<(String, String), WorkflowPlan>,
+    // Checkpoints keyed by workflow name and namespace.
+    checkpoints: BTreeMap<(String, String), JobCheckpoint>,
 }
 
 #[derive(Default)]
 pub(crate) struct MemoryWorkflowStorager {
-    inner: Mutex<RefCell<InnerMemoryWorkflowStorager>>,
+    inner: RwLock<InnerMemoryWorkflowStorager>,
+}
+
+impl MemoryWorkflowStorager {
+    // The history records of a workflow that match a filter, oldest first,
+    // without copying the others.
+    pub(crate) fn filter_history(
+        &self,
+        workflow: &str,
+        filter: impl Fn(&HistoryRecord) -> bool,
+    ) -> Vec<HistoryRecord> {
+        let inner = self.inner.read();
+        let mut records: Vec<HistoryRecord> = workflow_history(&inner.history, workflow)
+            .filter(|r| filter(r))
+            .cloned()
+            .collect();
+        records.sort_by_key(|r| r.finished_at);
+        records
+    }
+
+    // Forgets the oldest history records of a namespace of a workflow, keeping
+    // at most the given number.
+    pub(crate) fn limit_history(&self, workflow: &str, namespace: &str, max: usize) {
+        let mut inner = self.inner.write();
+        if let Some(records) = inner
+            .history
+            .get_mut(&(workflow.to_string(), namespace.to_string()))
+        {
+            let excess = records.len().saturating_sub(max);
+            records.drain(..excess);
+        }
+    }
+
+    // Forgets the given history records of a workflow.
+    pub(crate) fn forget_history(&self, workflow: &str, records: &[HistoryRecord]) {
+        let mut inner = self.inner.write();
+        for record in records {
+            let key = (workflow.to_string(), record.namespace.clone());
+            if let Some(kept) = inner.history.get_mut(&key) {
+                if let Some(i) = kept.iter().position(|r| r == record) {
+                    kept.remove(i);
+                }
+                if kept.is_empty() {
+                    inner.history.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+// The history records of every namespace of a workflow.
+fn workflow_history<'a>(
+    history: &'a BTreeMap<(String, String), Vec<HistoryRecord>>,
+    workflow: &'a str,
+) -> impl Iterator<Item = &'a HistoryRecord> {
+    history
+        .range((workflow.to_string(), String::new())..)
+        .take_while(move |((name, _), _)| name == workflow)
+        .flat_map(|(_, records)| records)
 }
 
 #[async_trait]
 impl WorkflowStorage for MemoryWorkflowStorager {
     async fn add_workflow(&self, workflow: Workflow) -> Result<()> {
-        let inner_lock = self.inner.lock();
-        let mut inner = inner_lock.borrow_mut();
+        let mut inner = self.inner.write();
         if workflow.metadata.name.is_none() {
             return Err(anyhow!("workflow name is required"));
         }
@@ -155,8 +468,7 @@ impl WorkflowStorage for MemoryWorkflowStorager {
     }
 
     async fn lastest_workflow(&self, name: String) -> Result<u64> {
-        let inner_lock = self.inner.lock();
-        let inner = inner_lock.borrow_mut();
+        let inner = self.inner.read();
         if let Some(checksum) = inner.latest.get(&name) {
             return Ok(*checksum);
         }
@@ -164,8 +476,7 @@ impl WorkflowStorage for MemoryWorkflowStorager {
     }
 
     async fn get_workflow(&self, name: String, checksum: Option<u64>) -> Result<Workflow> {
-        let inner_lock = self.inner.lock();
-        let inner = inner_lock.borrow_mut();
+        let inner = self.inner.read();
         match checksum {
             Some(checksum) => {
                 if let Some(workflow) = inner.workflows.get(&checksum) {
@@ -185,8 +496,7 @@ impl WorkflowStorage for MemoryWorkflowStorager {
     }
 
     async fn get_latest_workflows(&self) -> Result<Vec<Workflow>> {
-        let inner_lock = self.inner.lock();
-        let inner = inner_lock.borrow_mut();
+        let inner = self.inner.read();
         Ok(inner
             .latest
             .values()
@@ -195,12 +505,6 @@ impl WorkflowStorage for MemoryWorkflowStorager {
             .collect())
     }
 
-    fn get_workflow_names(&self) -> Result<Vec<String>> {
-        let inner_lock = self.inner.lock();
-        let inner = inner_lock.borrow_mut();
-        Ok(inner.latest.keys().cloned().collect())
-    }
-
     async fn add_resource(
         &self,
         namespace: String,
@@ -210,8 +514,7 @@ impl WorkflowStorage for MemoryWorkflowStorager {
         annotations: BTreeMap<String, String>,
         ready: bool,
     ) -> Result<()> {
-        let inner_lock = self.inner.lock();
-        let mut inner = inner_lock.borrow_mut();
+        let mut inner = self.inner.write();
         info!("add resource: {} {} {} {}", namespace, kind, name, ready);
         inner.resources.insert(KnownResource {
             namespace,
@@ -225,57 +528,61 @@ impl WorkflowStorage for MemoryWorkflowStorager {
     }
 
     async fn remove_resource(&self, namespace: String, kind: String, name: String) -> Result<()> {
-        let inner_lock = self.inner.lock();
-        let mut inner = inner_lock.borrow_mut();
-        inner
-            .resources
-            .retain(|r| !(r.namespace == namespace && r.kind == kind && r.name == name));
+        let mut inner = self.inner.write();
+        inner.resources.remove(&(namespace, kind, name));
         Ok(())
     }
 
     async fn enable_namespace(&self, name: String) -> Result<()> {
-        let inner_lock = self.inner.lock();
-        let mut inner = inner_lock.borrow_mut();
+        let mut inner = self.inner.write();
         inner.namespaces.insert(name);
         Ok(())
     }
 
     async fn disable_namespace(&self, name: String) -> Result<()> {
-        let inner_lock = self.inner.lock();
-        let mut inner = inner_lock.borrow_mut();
+        let mut inner = self.inner.write();
         inner.namespaces.remove(&name);
         Ok(())
     }
 
     async fn namespace_enabled(&self, name: String) -> Result<bool> {
-        let inner_lock = self.inner.lock();
-        let inner = inner_lock.borrow_mut();
+        let inner = self.inner.read();
         Ok(inner.namespaces.contains(&name))
     }
 
+    async fn enabled_namespaces(&self) -> Result<Vec<String>> {
+        let inner = self.inner.read();
+        let mut namespaces: Vec<String> = inner.namespaces.iter().cloned().collect();
+        namespaces.sort();
+        Ok(namespaces)
+    }
+
     async fn workflow_resources(&self, workflow: String) -> Result<Vec<KnownResource>> {
-        let inner_lock = self.inner.lock();
-        let inner = inner_lock.borrow_mut();
-        Ok(inner
-            .resources
-            .iter()
-            .filter(|r| r.workflow == workflow)
-            .cloned()
-            .collect())
+        let inner = self.inner.read();
+        Ok(inner.resources.in_workflow(&workflow))
+    }
+
+    async fn namespace_resources(&self, namespace: String) -> Result<Vec<KnownResource>> {
+        let inner = self.inner.read();
+        Ok(inner.resources.in_namespace(&namespace))
+    }
+
+    async fn kind_resources(&self, kind: String) -> Result<Vec<KnownResource>> {
+        let inner = self.inner.read();
+        Ok(inner.resources.of_kind(&kind))
     }
 
     fn is_resource_ready(&self, namespace: String, kind: String, name: String) -> bool {
-        let inner_lock = self.inner.lock();
-        let inner = inner_lock.borrow_mut();
+        let inner = self.inner.read();
         inner
             .resources
-            .iter()
-            .any(|r| r.namespace == namespace && r.kind == kind && r.name == name && r.ready)
+            .get(&(namespace, kind, name))
+            .map(|r| r.ready)
+            .unwrap_or_default()
     }
 
     async fn current_version(&self, workspace_name: String) -> Option<String> {
-        let inner_lock = self.inner.lock();
-        let inner = inner_lock.borrow_mut();
+        let inner = self.inner.read();
 
         inner.latest.get(&workspace_name).and_then(|version| {
             inner
@@ -284,6 +591,97 @@ impl WorkflowStorage for MemoryWorkflowStorager {
                 .map(|workflow| workflow.spec.version.clone())
         })
     }
+
+    async fn add_history(&self, record: HistoryRecord) -> Result<()> {
+        let mut inner = self.inner.write();
+        inner
+            .history
+            .entry((record.workflow.clone(), record.namespace.clone()))
+            .or_default()
+            .push(record);
+        Ok(())
+    }
+
+    async fn get_history(
+        &self,
+        workflow: String,
+        namespace: Option<String>,
+    ) -> Result<Vec<HistoryRecord>> {
+        match namespace {
+            Some(namespace) => {
+                let inner = self.inner.read();
+                Ok(inner
+                    .history
+                    .get(&(workflow, namespace))
+                    .cloned()
+                    .unwrap_or_default())
+            }
+            None => Ok(self.filter_history(&workflow, |_| true)),
+        }
+    }
+
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<usize> {
+        let mut inner = self.inner.write();
+        let mut pruned = 0;
+        inner.history.retain(|_, records| {
+            let count = records.len();
+            records.retain(|r| r.finished_at >= before);
+            pruned += count - records.len();
+            !records.is_empty()
+        });
+        Ok(pruned)
+    }
+
+    async fn set_plan(&self, plan: WorkflowPlan) -> Result<()> {
+        let mut inner = self.inner.write();
+        inner
+            .plans
+            .insert((plan.workflow.clone(), plan.namespace.clone()), plan);
+        Ok(())
+    }
+
+    async fn get_plans(
+        &self,
+        workflow: String,
+        namespace: Option<String>,
+    ) -> Result<Vec<WorkflowPlan>> {
+        let inner = self.inner.read();
+        Ok(inner
+            .plans
+            .values()
+            .filter(|p| p.workflow == workflow)
+            .filter(|p| namespace.is_none() || namespace.as_ref() == Some(&p.namespace))
+            .cloned()
+            .collect())
+    }
+
+    async fn set_checkpoint(&self, checkpoint: JobCheckpoint) -> Result<()> {
+        let mut inner = self.inner.write();
+        inner.checkpoints.insert(
+            (checkpoint.workflow.clone(), checkpoint.namespace.clone()),
+            checkpoint,
+        );
+        Ok(())
+    }
+
+    async fn get_checkpoints(&self) -> Result<Vec<JobCheckpoint>> {
+        let inner = self.inner.read();
+        Ok(inner.checkpoints.values().cloned().collect())
+    }
+
+    async fn take_checkpoints(&self, workflow: String) -> Result<Vec<JobCheckpoint>> {
+        let mut inner = self.inner.write();
+        let keys: Vec<(String, String)> = inner
+            .checkpoints
+            .keys()
+            .filter(|(w, _)| *w == workflow)
+            .cloned()
+            .collect();
+        Ok(keys
+            .iter()
+            .filter_map(|key| inner.checkpoints.remove(key))
+            .collect())
+    }
 }
 
 pub(crate) fn get_workflow_storage(workflow_storage_type: &str) -> Box<dyn WorkflowStorage> {
@@ -296,4 +694,304 @@ pub(crate) fn get_workflow_storage(workflow_storage_type: &str) -> Box<dyn Workf
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    async fn add_deployment(
+        storage: &MemoryWorkflowStorager,
+        namespace: &str,
+        workflow: &str,
+        ready: bool,
+    ) {
+        storage
+            .add_resource(
+                namespace.to_string(),
+                "apps/v1;Deployment".to_string(),
+                "app".to_string(),
+                workflow.to_string(),
+                BTreeMap::from([(
+                    "workflow-deploy.ngerakines.me/workflow".to_string(),
+                    workflow.to_string(),
+                )]),
+                ready,
+            )
+            .await
+            .unwrap();
+    }
+
+    fn is_ready(storage: &MemoryWorkflowStorager, namespace: &str) -> bool {
+        storage.is_resource_ready(
+            namespace.to_string(),
+            "apps/v1;Deployment".to_string(),
+            "app".to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_resources() {
+        let storage = MemoryWorkflowStorager::default();
+        add_deployment(&storage, "foo", "tenants", true).await;
+        add_deployment(&storage, "bar", "tenants", true).await;
+
+        // Updating a resource replaces it rather than adding another.
+        add_deployment(&storage, "foo", "tenants", false).await;
+        assert!(!is_ready(&storage, "foo"));
+        let resources = storage
+            .workflow_resources("tenants".to_string())
+            .await
+            .unwrap();
+        assert_eq!(resources.len(), 2);
+
+        // Moving a resource to another workflow updates the index.
+        add_deployment(&storage, "foo", "internal", false).await;
+        let resources = storage
+            .workflow_resources("tenants".to_string())
+            .await
+            .unwrap();
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources[0].namespace, "bar");
+        let resources = storage
+            .namespace_resources("foo".to_string())
+            .await
+            .unwrap();
+        assert_eq!(resources[0].workflow, "internal");
+
+        storage
+            .remove_resource(
+                "foo".to_string(),
+                "apps/v1;Deployment".to_string(),
+                "app".to_string(),
+            )
+            .await
+            .unwrap();
+        assert!(storage
+            .namespace_resources("foo".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage
+            .workflow_resources("internal".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    // Lookups of a single namespace take about as long with 10k namespaces as
+    // with 100. Run with
+    // `cargo test --release --features bench bench_lookups -- --nocapture`.
+    #[cfg(feature = "bench")]
+    #[tokio::test]
+    async fn bench_lookups() {
+        use std::time::{Duration as StdDuration, Instant};
+
+        const LOOKUPS: usize = 100_000;
+        const RECORDS: usize = 10;
+
+        async fn time<F: std::future::Future<Output = ()>>(
+            mut lookup: impl FnMut(usize) -> F,
+        ) -> StdDuration {
+            let started = Instant::now();
+            for i in 0..LOOKUPS {
+                lookup(i).await;
+            }
+            started.elapsed() / LOOKUPS as u32
+        }
+
+        let mut timings: Vec<(usize, [StdDuration; 5])> = vec![];
+        for namespaces in [100, 1_000, 10_000] {
+            let storage = MemoryWorkflowStorager::default();
+            let names: Vec<String> = (0..namespaces).map(|i| format!("tenant-{i}")).collect();
+            let now = Utc::now();
+            for name in &names {
+                add_deployment(&storage, name, "tenants", true).await;
+                for _ in 0..RECORDS {
+                    storage.add_history(record(name, now)).await.unwrap();
+                }
+            }
+            let name = |i: usize| names[i % names.len()].clone();
+
+            let ready = time(|i| {
+                assert!(is_ready(&storage, &name(i)));
+                async {}
+            })
+            .await;
+            let resources = time(|i| {
+                let storage = &storage;
+                async move {
+                    let resources = storage.namespace_resources(name(i)).await.unwrap();
+                    assert_eq!(resources.len(), 1);
+                }
+            })
+            .await;
+            let update = time(|i| {
+                let storage = &storage;
+                async move { add_deployment(storage, &name(i), "tenants", i % 2 == 0).await }
+            })
+            .await;
+            let history = time(|i| {
+                let storage = &storage;
+                async move {
+                    let records = storage
+                        .get_history("tenants".to_string(), Some(name(i)))
+                        .await
+                        .unwrap();
+                    assert_eq!(records.len(), RECORDS);
+                }
+            })
+            .await;
+            let record_history = time(|i| {
+                let storage = &storage;
+                async move {
+                    storage.add_history(record(&name(i), now)).await.unwrap();
+                    storage.limit_history("tenants", &name(i), RECORDS);
+                }
+            })
+            .await;
+
+            println!(
+                "{namespaces} namespaces: is_resource_ready {ready:?}, namespace_resources {resources:?}, \
+                 add_resource {update:?}, get_history {history:?}, add_history {record_history:?}"
+            );
+            timings.push((
+                namespaces,
+                [ready, resources, update, history, record_history],
+            ));
+        }
+
+        // A lookup that scanned every namespace would take 100 times as long
+        // with 10k namespaces as with 100.
+        let (_, smallest) = timings[0];
+        let (_, largest) = timings[timings.len() - 1];
+        for (small, large) in smallest.iter().zip(largest) {
+            assert!(
+                large < (*small * 10).max(StdDuration::from_micros(1)),
+                "{large:?} with 10k namespaces against {small:?} with 100"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resource_indexes() {
+        let storage = MemoryWorkflowStorager::default();
+        let names: Vec<String> = (0..10_000).map(|i| format!("tenant-{i}")).collect();
+        for name in &names {
+            add_deployment(&storage, name, "tenants", true).await;
+        }
+        storage
+            .add_resource(
+                "tenant-0".to_string(),
+                "batch/v1;Job".to_string(),
+                "migrate".to_string(),
+                "tenants".to_string(),
+                BTreeMap::new(),
+                false,
+            )
+            .await
+            .unwrap();
+
+        // Every lookup is served from an index that holds exactly the keys it
+        // returns, so it doesn't scan the other resources.
+        {
+            let inner = storage.inner.read();
+            let resources = &inner.resources;
+            assert_eq!(resources.resources.len(), 10_001);
+            assert_eq!(resources.by_kind["batch/v1;Job"].len(), 1);
+            assert_eq!(resources.by_kind["apps/v1;Deployment"].len(), 10_000);
+            assert_eq!(resources.by_namespace["tenant-0"].len(), 2);
+            assert_eq!(resources.by_namespace["tenant-1"].len(), 1);
+            assert_eq!(resources.by_workflow["tenants"].len(), 10_001);
+        }
+        let jobs = storage
+            .kind_resources("batch/v1;Job".to_string())
+            .await
+            .unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].name, "migrate");
+
+        // Removing resources removes their keys, and drops indexes that are empty.
+        storage
+            .remove_resource(
+                "tenant-0".to_string(),
+                "batch/v1;Job".to_string(),
+                "migrate".to_string(),
+            )
+            .await
+            .unwrap();
+        for name in &names[1..] {
+            storage
+                .remove_resource(
+                    name.clone(),
+                    "apps/v1;Deployment".to_string(),
+                    "app".to_string(),
+                )
+                .await
+                .unwrap();
+        }
+        let inner = storage.inner.read();
+        let resources = &inner.resources;
+        assert_eq!(resources.resources.len(), 1);
+        assert_eq!(resources.by_kind.len(), 1);
+        assert_eq!(resources.by_namespace.len(), 1);
+        assert_eq!(resources.by_workflow["tenants"].len(), 1);
+    }
+
+    fn record(namespace: &str, finished_at: DateTime<Utc>) -> HistoryRecord {
+        HistoryRecord {
+            workflow: "tenants".to_string(),
+            checksum: 1,
+            namespace: namespace.to_string(),
+            started_at: finished_at,
+            finished_at,
+            succeeded: true,
+            reason: None,
+            from_version: Some("1.0.0".to_string()),
+            to_version: "1.0.1".to_string(),
+            triggered_by: None,
+            entries: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let storage = MemoryWorkflowStorager::default();
+        let now = Utc::now();
+        storage
+            .add_history(record("foo", now - Duration::days(45)))
+            .await
+            .unwrap();
+        storage.add_history(record("foo", now)).await.unwrap();
+        storage.add_history(record("bar", now)).await.unwrap();
+
+        assert_eq!(
+            storage
+                .get_history("tenants".to_string(), None)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+        assert_eq!(
+            storage
+                .get_history("tenants".to_string(), Some("foo".to_string()))
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            storage
+                .prune_history(now - Duration::days(30))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            storage
+                .get_history("tenants".to_string(), Some("foo".to_string()))
+                .await
+                .unwrap(),
+            vec![record("foo", now)]
+        );
+    }
+}
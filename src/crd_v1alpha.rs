@@ -0,0 +1,776 @@
+use fnv::FnvHasher;
+use kube::{api::ObjectMeta, CustomResource};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+
+use crate::crd::{
+    self, preserve_unknown_fields, step_analysis, unique_strings, validated_list, WorkflowAnalysis,
+    WorkflowEnvVar, WorkflowStatus,
+};
+
+// The original version of the workflow CRD. It is still served, and objects
+// are converted to and from the current version by the conversion webhook.
+
+// The actions that workflow steps can use.
+pub(crate) const ACTIONS: [&str; 7] = [
+    "update_deployment",
+    "update_env",
+    "update_config",
+    "restart_deployment",
+    "scale",
+    "run_job",
+    "http_check",
+];
+
+// Keeps the spec of a workflow converted from this version when converting
+// it back wouldn't give the same spec.
+pub(crate) const SPEC_ANNOTATION: &str = "workflow-deploy.ngerakines.me/v1alpha-spec";
+
+// The kinds of resources that actions target.
+const RESOURCES: [&str; 5] = ["Deployment", "ConfigMap", "Job", "CronJob", "Service"];
+
+fn string_enum(values: &[&str]) -> Schema {
+    serde_json::from_value(serde_json::json!({
+        "type": "string",
+        "enum": values
+    }))
+    .expect("schema is valid")
+}
+
+fn action_name(_: &mut SchemaGenerator) -> Schema {
+    string_enum(&ACTIONS)
+}
+
+fn resource_kind(_: &mut SchemaGenerator) -> Schema {
+    string_enum(&RESOURCES)
+}
+
+fn step_actions(gen: &mut SchemaGenerator) -> Schema {
+    validated_list::<WorkflowStepAction>(
+        gen,
+        Some(1),
+        &[
+            (
+                "self.action != 'scale' || self.targets.all(t, has(t.replicas))",
+                "scale targets must set replicas",
+            ),
+            (
+                "self.action != 'http_check' || self.targets.all(t, has(t.url))",
+                "http_check targets must set url",
+            ),
+            (
+                "self.action != 'run_job' || self.targets.all(t, has(t.job) || has(t.template))",
+                "run_job targets must set job or template",
+            ),
+        ],
+    )
+}
+
+// Analysis could be null in this version.
+fn nullable_step_analysis(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = step_analysis(gen);
+    if let Schema::Object(object) = &mut schema {
+        object
+            .extensions
+            .insert("nullable".to_string(), true.into());
+    }
+    schema
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowStepActionTarget {
+    #[schemars(schema_with = "resource_kind")]
+    pub(crate) resource: String,
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) containers: Vec<String>,
+    // Used by the `update_env` action.
+    pub(crate) env: Option<Vec<WorkflowEnvVar>>,
+    // Used by the `update_config` action.
+    pub(crate) data: Option<BTreeMap<String, String>>,
+    // Used by the `update_config` action to restart deployments that consume the config map.
+    pub(crate) deployments: Option<Vec<String>>,
+    // Used by the `scale` action. Either an absolute count like "3" or a
+    // relative change like "+2" or "-1".
+    #[schemars(regex(pattern = r"^[+-]?[0-9]+$"))]
+    pub(crate) replicas: Option<String>,
+    // Used by the `run_job` action. An inline job spec used to create the job.
+    #[serde(default)]
+    #[schemars(schema_with = "preserve_unknown_fields")]
+    pub(crate) job: Option<serde_json::Value>,
+    // Used by the `run_job` action. The name of a cron job in the namespace
+    // whose job template is used to create the job.
+    pub(crate) template: Option<String>,
+    // Used by the `run_job` and `http_check` actions.
+    #[schemars(range(min = 1))]
+    pub(crate) timeout_seconds: Option<u32>,
+    // Used by the `run_job` action. Deletes the job after it completes.
+    pub(crate) cleanup: Option<bool>,
+    // Used by the `http_check` action. The URL to request.
+    pub(crate) url: Option<String>,
+    // Used by the `http_check` action. The accepted response status codes. Defaults to 200.
+    #[schemars(inner(range(min = 100, max = 599)))]
+    pub(crate) status: Option<Vec<u16>>,
+    // Used by the `http_check` action. Text that the response body must contain.
+    pub(crate) body: Option<String>,
+    // Used by the `http_check` action. Seconds between requests. Defaults to 5.
+    #[schemars(range(min = 1))]
+    pub(crate) interval_seconds: Option<u32>,
+    // Used by the `http_check` action. Consecutive successful requests required. Defaults to 1.
+    #[schemars(range(min = 1))]
+    pub(crate) successes: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowStepAction {
+    #[schemars(schema_with = "action_name")]
+    pub(crate) action: String,
+    #[schemars(length(min = 1))]
+    pub(crate) targets: Vec<WorkflowStepActionTarget>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowStep {
+    #[schemars(schema_with = "step_actions")]
+    pub(crate) actions: Vec<WorkflowStepAction>,
+    #[serde(default)]
+    #[schemars(schema_with = "nullable_step_analysis")]
+    pub(crate) analysis: Option<Vec<WorkflowAnalysis>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub(crate) struct WorkflowNotification {
+    pub(crate) url: String,
+    pub(crate) events: Option<Vec<String>>,
+    pub(crate) template: Option<String>,
+}
+
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(
+    group = "workflow-deploy.ngerakines.me",
+    version = "v1alpha",
+    kind = "Workflow",
+    plural = "workflows",
+    shortname = "wf",
+    status = "WorkflowStatus",
+    printcolumn = r#"{"name": "Version", "type": "string", "jsonPath": ".spec.version"}"#,
+    printcolumn = r#"{"name": "Parallel", "type": "integer", "jsonPath": ".spec.parallel"}"#,
+    printcolumn = r#"{"name": "In-Flight", "type": "integer", "jsonPath": ".status.in_flight"}"#,
+    printcolumn = r#"{"name": "Last-Result", "type": "string", "jsonPath": ".status.last_result"}"#,
+    printcolumn = r#"{"name": "Age", "type": "date", "jsonPath": ".metadata.creationTimestamp"}"#
+)]
+pub(crate) struct WorkflowSpec {
+    #[schemars(schema_with = "unique_strings")]
+    pub(crate) namespaces: Vec<String>,
+    #[schemars(length(min = 1))]
+    pub(crate) version: String,
+    #[schemars(range(max = 86400))]
+    pub(crate) debounce: Option<u32>,
+    #[schemars(range(min = 1, max = 255))]
+    pub(crate) parallel: Option<u32>,
+    pub(crate) supression: Vec<String>,
+    pub(crate) steps: Vec<WorkflowStep>,
+    pub(crate) notifications: Option<Vec<WorkflowNotification>>,
+}
+
+impl From<WorkflowStepAction> for crd::WorkflowStepAction {
+    // Fields that an action doesn't use are dropped. Fields that an action
+    // requires but that are missing are left empty, and unknown actions are
+    // kept as they are, so that the workflow can still be read. Both are
+    // reported by validation instead.
+    fn from(action: WorkflowStepAction) -> Self {
+        let targets = action.targets.into_iter();
+        match action.action.as_str() {
+            "update_deployment" => crd::WorkflowStepAction::UpdateDeployment {
+                targets: targets
+                    .map(|target| crd::WorkflowImageTarget {
+                        name: target.name,
+                        containers: target.containers,
+                    })
+                    .collect(),
+            },
+            "update_env" => crd::WorkflowStepAction::UpdateEnv {
+                targets: targets
+                    .map(|target| crd::WorkflowEnvTarget {
+                        name: target.name,
+                        containers: target.containers,
+                        env: target.env.unwrap_or_default(),
+                    })
+                    .collect(),
+            },
+            "update_config" => crd::WorkflowStepAction::UpdateConfig {
+                targets: targets
+                    .map(|target| crd::WorkflowConfigTarget {
+                        name: target.name,
+                        data: target.data.unwrap_or_default(),
+                        deployments: target.deployments.unwrap_or_default(),
+                    })
+                    .collect(),
+            },
+            "restart_deployment" => crd::WorkflowStepAction::RestartDeployment {
+                targets: targets
+                    .map(|target| crd::WorkflowDeploymentTarget { name: target.name })
+                    .collect(),
+            },
+            "scale" => crd::WorkflowStepAction::Scale {
+                targets: targets
+                    .map(|target| crd::WorkflowScaleTarget {
+                        name: target.name,
+                        replicas: target.replicas.unwrap_or_default(),
+                    })
+                    .collect(),
+            },
+            "run_job" => crd::WorkflowStepAction::RunJob {
+                targets: targets
+                    .map(|target| crd::WorkflowJobTarget {
+                        name: target.name,
+                        job: target.job,
+                        template: target.template,
+                        containers: target.containers,
+                        timeout_seconds: target.timeout_seconds,
+                        cleanup: target.cleanup,
+                    })
+                    .collect(),
+            },
+            "http_check" => crd::WorkflowStepAction::HttpCheck {
+                targets: targets
+                    .map(|target| crd::WorkflowHttpCheckTarget {
+                        name: target.name,
+                        url: target.url.unwrap_or_default(),
+                        status: target.status,
+                        body: target.body,
+                        interval_seconds: target.interval_seconds,
+                        timeout_seconds: target.timeout_seconds,
+                        successes: target.successes,
+                    })
+                    .collect(),
+            },
+            _ => crd::WorkflowStepAction::Unknown {
+                targets: targets
+                    .filter_map(|target| serde_json::to_value(target).ok())
+                    .collect(),
+                action: action.action,
+            },
+        }
+    }
+}
+
+// A target with only a resource and name set.
+fn target(resource: &str, name: String) -> WorkflowStepActionTarget {
+    WorkflowStepActionTarget {
+        resource: resource.to_string(),
+        name,
+        containers: vec![],
+        env: None,
+        data: None,
+        deployments: None,
+        replicas: None,
+        job: None,
+        template: None,
+        timeout_seconds: None,
+        cleanup: None,
+        url: None,
+        status: None,
+        body: None,
+        interval_seconds: None,
+        successes: None,
+    }
+}
+
+impl From<crd::WorkflowStepAction> for WorkflowStepAction {
+    fn from(action: crd::WorkflowStepAction) -> Self {
+        let name = action.name().to_string();
+        let targets = match action {
+            crd::WorkflowStepAction::UpdateDeployment { targets } => targets
+                .into_iter()
+                .map(|x| WorkflowStepActionTarget {
+                    containers: x.containers,
+                    ..target("Deployment", x.name)
+                })
+                .collect(),
+            crd::WorkflowStepAction::UpdateEnv { targets } => targets
+                .into_iter()
+                .map(|x| WorkflowStepActionTarget {
+                    containers: x.containers,
+                    env: Some(x.env),
+                    ..target("Deployment", x.name)
+                })
+                .collect(),
+            crd::WorkflowStepAction::UpdateConfig { targets } => targets
+                .into_iter()
+                .map(|x| WorkflowStepActionTarget {
+                    data: Some(x.data),
+                    deployments: Some(x.deployments).filter(|x| !x.is_empty()),
+                    ..target("ConfigMap", x.name)
+                })
+                .collect(),
+            crd::WorkflowStepAction::RestartDeployment { targets } => targets
+                .into_iter()
+                .map(|x| target("Deployment", x.name))
+                .collect(),
+            crd::WorkflowStepAction::Scale { targets } => targets
+                .into_iter()
+                .map(|x| WorkflowStepActionTarget {
+                    replicas: Some(x.replicas),
+                    ..target("Deployment", x.name)
+                })
+                .collect(),
+            crd::WorkflowStepAction::RunJob { targets } => targets
+                .into_iter()
+                .map(|x| WorkflowStepActionTarget {
+                    containers: x.containers,
+                    job: x.job,
+                    template: x.template,
+                    timeout_seconds: x.timeout_seconds,
+                    cleanup: x.cleanup,
+                    ..target("Job", x.name)
+                })
+                .collect(),
+            crd::WorkflowStepAction::HttpCheck { targets } => targets
+                .into_iter()
+                .map(|x| WorkflowStepActionTarget {
+                    url: Some(x.url),
+                    status: x.status,
+                    body: x.body,
+                    interval_seconds: x.interval_seconds,
+                    timeout_seconds: x.timeout_seconds,
+                    successes: x.successes,
+                    ..target("Service", x.name)
+                })
+                .collect(),
+            crd::WorkflowStepAction::Unknown { targets, .. } => targets
+                .into_iter()
+                .filter_map(|x| serde_json::from_value(x).ok())
+                .collect(),
+        };
+        WorkflowStepAction {
+            action: name,
+            targets,
+        }
+    }
+}
+
+impl From<WorkflowSpec> for crd::WorkflowSpec {
+    fn from(spec: WorkflowSpec) -> Self {
+        crd::WorkflowSpec {
+            namespaces: spec.namespaces,
+            version: spec.version,
+            debounce: spec.debounce,
+            parallel: spec.parallel,
+            suppressions: spec.supression,
+            steps: spec
+                .steps
+                .into_iter()
+                .map(|step| crd::WorkflowStep {
+                    actions: step.actions.into_iter().map(Into::into).collect(),
+                    analysis: step.analysis.unwrap_or_default(),
+                })
+                .collect(),
+            notifications: spec
+                .notifications
+                .into_iter()
+                .flatten()
+                .map(|notification| crd::WorkflowNotification {
+                    url: notification.url,
+                    events: notification.events.unwrap_or_default(),
+                    template: notification.template,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<crd::WorkflowSpec> for WorkflowSpec {
+    fn from(spec: crd::WorkflowSpec) -> Self {
+        let notifications: Vec<WorkflowNotification> = spec
+            .notifications
+            .into_iter()
+            .map(|notification| WorkflowNotification {
+                url: notification.url,
+                events: Some(notification.events).filter(|x| !x.is_empty()),
+                template: notification.template,
+            })
+            .collect();
+
+        WorkflowSpec {
+            namespaces: spec.namespaces,
+            version: spec.version,
+            debounce: spec.debounce,
+            parallel: spec.parallel,
+            supression: spec.suppressions,
+            steps: spec
+                .steps
+                .into_iter()
+                .map(|step| WorkflowStep {
+                    actions: step.actions.into_iter().map(Into::into).collect(),
+                    analysis: Some(step.analysis).filter(|x| !x.is_empty()),
+                })
+                .collect(),
+            notifications: Some(notifications).filter(|x| !x.is_empty()),
+        }
+    }
+}
+
+fn same_spec<A: Serialize, B: Serialize>(a: &A, b: &B) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+fn set_spec_annotation(metadata: &mut ObjectMeta, spec: Option<String>) {
+    let mut annotations = metadata.annotations.take().unwrap_or_default();
+    annotations.remove(SPEC_ANNOTATION);
+    if let Some(spec) = spec {
+        annotations.insert(SPEC_ANNOTATION.to_string(), spec);
+    }
+    metadata.annotations = Some(annotations).filter(|x| !x.is_empty());
+}
+
+impl From<Workflow> for crd::Workflow {
+    // When converting the spec back wouldn't give the same spec, for example
+    // because a target sets fields that its action doesn't use, the spec is
+    // kept in an annotation.
+    fn from(workflow: Workflow) -> Self {
+        let spec = crd::WorkflowSpec::from(workflow.spec.clone());
+        let mut metadata = workflow.metadata;
+        let original = (!same_spec(&WorkflowSpec::from(spec.clone()), &workflow.spec))
+            .then(|| serde_json::to_string(&workflow.spec).ok())
+            .flatten();
+        set_spec_annotation(&mut metadata, original);
+
+        crd::Workflow {
+            metadata,
+            status: workflow.status,
+            spec,
+        }
+    }
+}
+
+impl From<crd::Workflow> for Workflow {
+    fn from(workflow: crd::Workflow) -> Self {
+        let spec = WorkflowSpec::from_workflow(&workflow);
+        let mut metadata = workflow.metadata;
+        set_spec_annotation(&mut metadata, None);
+
+        Workflow {
+            metadata,
+            status: workflow.status,
+            spec,
+        }
+    }
+}
+
+impl WorkflowSpec {
+    // The spec that a workflow has in this version. A workflow converted from
+    // this version gets back the spec it was converted from, unless its spec
+    // has changed since.
+    pub(crate) fn from_workflow(workflow: &crd::Workflow) -> Self {
+        workflow
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(SPEC_ANNOTATION))
+            .and_then(|spec| serde_json::from_str::<WorkflowSpec>(spec).ok())
+            .filter(|spec| same_spec(&crd::WorkflowSpec::from(spec.clone()), &workflow.spec))
+            .unwrap_or_else(|| workflow.spec.clone().into())
+    }
+
+    pub(crate) fn checksum(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        hasher.write(format!("version={}", self.version).as_bytes());
+
+        let mut namespaces = self.namespaces.clone();
+        namespaces.sort();
+        for namespace in namespaces {
+            hasher.write(format!("namespace={namespace}").as_bytes());
+        }
+
+        hasher.write(format!("debounce={}", self.debounce.unwrap_or_default()).as_bytes());
+
+        let mut supression = self.supression.clone();
+        supression.sort();
+        for value in supression.iter() {
+            hasher.write(format!("supression={}", value).as_bytes());
+        }
+
+        for step in self.steps.iter() {
+            hasher.write(format!("step={}", step.checksum()).as_bytes());
+        }
+
+        hasher.finish()
+    }
+}
+
+impl WorkflowStep {
+    pub(crate) fn checksum(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        for action in self.actions.iter() {
+            hasher.write(format!("step={}", action.checksum()).as_bytes());
+        }
+        for analysis in self.analysis.iter().flatten() {
+            hasher.write(
+                format!(
+                    "analysis={} query={} min={:?} max={:?} window_seconds={:?} interval_seconds={:?}",
+                    analysis.name,
+                    analysis.query,
+                    analysis.min,
+                    analysis.max,
+                    analysis.window_seconds,
+                    analysis.interval_seconds
+                )
+                .as_bytes(),
+            );
+        }
+        hasher.finish()
+    }
+}
+
+impl WorkflowStepAction {
+    pub(crate) fn checksum(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        // update_deployment was the only action before the others were added,
+        // and is hashed as it was then so that existing workflows aren't
+        // deployed again.
+        if self.action != "update_deployment" {
+            hasher.write(format!("action={}", self.action).as_bytes());
+        }
+        for target in self.targets.iter() {
+            hasher.write(format!("step={}", target.checksum()).as_bytes());
+        }
+        hasher.finish()
+    }
+}
+
+impl WorkflowStepActionTarget {
+    pub(crate) fn checksum(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        hasher.write(format!("resource={} name={}", self.resource, self.name).as_bytes());
+        let mut containers = self.containers.clone();
+        containers.sort();
+        for container in containers {
+            hasher.write(format!("container={}", container).as_bytes());
+        }
+        for env in self.env.iter().flatten() {
+            hasher.write(format!("env={}={:?}", env.name, env.value).as_bytes());
+        }
+        for (key, value) in self.data.iter().flatten() {
+            hasher.write(format!("data={}={}", key, value).as_bytes());
+        }
+        let mut deployments = self.deployments.clone().unwrap_or_default();
+        deployments.sort();
+        for deployment in deployments {
+            hasher.write(format!("deployment={}", deployment).as_bytes());
+        }
+        if let Some(replicas) = &self.replicas {
+            hasher.write(format!("replicas={}", replicas).as_bytes());
+        }
+        if let Some(job) = &self.job {
+            hasher.write(format!("job={}", job).as_bytes());
+        }
+        if let Some(template) = &self.template {
+            hasher.write(format!("template={}", template).as_bytes());
+        }
+        if let Some(timeout_seconds) = self.timeout_seconds {
+            hasher.write(format!("timeout_seconds={}", timeout_seconds).as_bytes());
+        }
+        if let Some(cleanup) = self.cleanup {
+            hasher.write(format!("cleanup={}", cleanup).as_bytes());
+        }
+        if let Some(url) = &self.url {
+            hasher.write(format!("url={}", url).as_bytes());
+        }
+        for status in self.status.iter().flatten() {
+            hasher.write(format!("status={}", status).as_bytes());
+        }
+        if let Some(body) = &self.body {
+            hasher.write(format!("body={}", body).as_bytes());
+        }
+        if let Some(interval_seconds) = self.interval_seconds {
+            hasher.write(format!("interval_seconds={}", interval_seconds).as_bytes());
+        }
+        if let Some(successes) = self.successes {
+            hasher.write(format!("successes={}", successes).as_bytes());
+        }
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::validate_workflow;
+
+    #[test]
+    fn test_round_trip() {
+        let workflow: Workflow = serde_yaml::from_str(
+            r#"
+apiVersion: workflow-deploy.ngerakines.me/v1alpha
+kind: Workflow
+metadata:
+  name: tenants
+spec:
+  namespaces: ["foo", "bar"]
+  version: "1.0.1"
+  supression: ["2023-05-02T14:00:00-04:00"]
+  steps:
+  - actions:
+    - action: update_deployment
+      targets:
+      - resource: Deployment
+        name: app
+        containers: ["app"]
+    - action: scale
+      targets:
+      - resource: Deployment
+        name: app
+        replicas: "+1"
+    - action: update_config
+      targets:
+      - resource: ConfigMap
+        name: app-config
+        data:
+          version: "{{version}}"
+        deployments: ["app"]
+    - action: http_check
+      targets:
+      - resource: Service
+        name: app
+        url: "http://app.{{namespace}}/health"
+        status: [200, 204]
+  notifications:
+  - url: http://hooks.local/deploys
+"#,
+        )
+        .unwrap();
+
+        let converted = crd::Workflow::from(workflow.clone());
+        assert_eq!(converted.spec.suppressions, workflow.spec.supression);
+        assert!(matches!(
+            &converted.spec.steps[0].actions[1],
+            crd::WorkflowStepAction::Scale { targets } if targets[0].replicas == "+1"
+        ));
+        assert!(converted.spec.notifications[0].events.is_empty());
+        // Nothing is lost, so the spec isn't kept.
+        assert!(converted.metadata.annotations.is_none());
+        assert_eq!(converted.checksum(), workflow.spec.checksum());
+
+        let round_trip = Workflow::from(converted);
+        assert_eq!(
+            serde_json::to_value(round_trip).unwrap(),
+            serde_json::to_value(workflow).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_lossy_round_trip() {
+        let workflow: Workflow = serde_yaml::from_str(
+            r#"
+apiVersion: workflow-deploy.ngerakines.me/v1alpha
+kind: Workflow
+metadata:
+  name: tenants
+  annotations:
+    workflow-deploy.ngerakines.me/triggered-by: ci
+spec:
+  namespaces: ["foo"]
+  version: "1.0.1"
+  supression: []
+  steps:
+  - actions:
+    - action: run_job
+      targets:
+      - resource: CronJob
+        name: migrate
+        template: migrate
+    - action: update_config
+      targets:
+      - resource: ConfigMap
+        name: app-config
+        data:
+          version: "{{version}}"
+        deployments: []
+        url: http://unused
+    - action: update_env
+      targets:
+      - resource: Deployment
+        name: app
+    analysis: []
+  notifications: []
+"#,
+        )
+        .unwrap();
+
+        let converted = crd::Workflow::from(workflow.clone());
+        let annotations = converted.metadata.annotations.clone().unwrap();
+        assert!(annotations.contains_key(SPEC_ANNOTATION));
+        // The checksum is the one the workflow had before it was converted.
+        assert_eq!(converted.checksum(), workflow.spec.checksum());
+
+        let round_trip = Workflow::from(converted.clone());
+        assert_eq!(
+            serde_json::to_value(round_trip).unwrap(),
+            serde_json::to_value(&workflow).unwrap()
+        );
+
+        // Once the spec changes, the kept spec is ignored.
+        let mut changed = converted;
+        changed.spec.version = "1.0.2".to_string();
+        let round_trip = Workflow::from(changed.clone());
+        assert_eq!(round_trip.spec.version, "1.0.2");
+        assert_eq!(
+            round_trip.spec.steps[0].actions[0].targets[0].resource,
+            "Job"
+        );
+        assert!(round_trip.spec.steps[0].actions[2].targets[0]
+            .env
+            .as_ref()
+            .is_some_and(|env| env.is_empty()));
+        assert_eq!(
+            round_trip
+                .metadata
+                .annotations
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            ["workflow-deploy.ngerakines.me/triggered-by"]
+        );
+        assert_eq!(changed.checksum(), round_trip.spec.checksum());
+    }
+
+    #[test]
+    fn test_unknown_action() {
+        let workflow: Workflow = serde_json::from_value(serde_json::json!({
+            "apiVersion": "workflow-deploy.ngerakines.me/v1alpha",
+            "kind": "Workflow",
+            "metadata": {"name": "tenants"},
+            "spec": {
+                "namespaces": ["foo"],
+                "version": "1.0.1",
+                "supression": [],
+                "steps": [{"actions": [{
+                    "action": "deploy",
+                    "targets": [{"resource": "Deployment", "name": "app", "containers": ["app"]}]
+                }]}]
+            }
+        }))
+        .unwrap();
+        let converted = crd::Workflow::from(workflow.clone());
+        assert!(matches!(
+            &converted.spec.steps[0].actions[0],
+            crd::WorkflowStepAction::Unknown { action, targets }
+                if action == "deploy" && targets.len() == 1
+        ));
+        assert_eq!(
+            validate_workflow(&converted),
+            ["steps[0].actions[0] has unknown action \"deploy\""]
+        );
+
+        // The unknown action survives being read as v1beta1.
+        let stored = serde_json::to_value(&converted).unwrap();
+        let converted: crd::Workflow = serde_json::from_value(stored).unwrap();
+        assert_eq!(
+            serde_json::to_value(Workflow::from(converted)).unwrap(),
+            serde_json::to_value(workflow).unwrap()
+        );
+    }
+}
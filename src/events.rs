@@ -0,0 +1,96 @@
+use k8s_openapi::{
+    api::{apps::v1::Deployment, core::v1::ObjectReference},
+    Resource as _,
+};
+use kube::{
+    runtime::events::{Event, EventType, Recorder, Reporter},
+    Client, Resource,
+};
+use tracing::warn;
+
+use crate::context::Context;
+
+fn reporter() -> Reporter {
+    Reporter {
+        controller: "workflow-deploy".to_string(),
+        instance: std::env::var("POD_NAME").ok(),
+    }
+}
+
+// Publishes an event in the background so that callers are never blocked on
+// the API server. Failures are logged and otherwise ignored.
+pub(crate) fn publish_event(
+    client: Client,
+    reference: ObjectReference,
+    type_: EventType,
+    reason: &str,
+    note: String,
+) {
+    let reason = reason.to_string();
+    tokio::spawn(async move {
+        let recorder = Recorder::new(client, reporter(), reference);
+        if let Err(err) = recorder
+            .publish(Event {
+                type_,
+                reason: reason.clone(),
+                note: Some(note),
+                action: reason.clone(),
+                secondary: None,
+            })
+            .await
+        {
+            warn!("Failed to publish {} event: {}", reason, err);
+        }
+    });
+}
+
+// Publishes an event on the latest version of a workflow.
+pub(crate) fn publish_workflow_event(
+    context: &Context,
+    client: &Client,
+    workflow_name: &str,
+    type_: EventType,
+    reason: &str,
+    note: String,
+) {
+    let context = context.clone();
+    let client = client.clone();
+    let workflow_name = workflow_name.to_string();
+    let reason = reason.to_string();
+    tokio::spawn(async move {
+        match context
+            .workflow_storage
+            .get_workflow(workflow_name.clone(), None)
+            .await
+        {
+            Ok(workflow) => {
+                publish_event(client, workflow.object_ref(&()), type_, &reason, note);
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to publish {} event for workflow {}: {}",
+                    reason, workflow_name, err
+                );
+            }
+        }
+    });
+}
+
+// Publishes an event on a deployment.
+pub(crate) fn publish_deployment_event(
+    client: &Client,
+    namespace: &str,
+    name: &str,
+    type_: EventType,
+    reason: &str,
+    note: String,
+) {
+    let reference = ObjectReference {
+        api_version: Some(Deployment::API_VERSION.to_string()),
+        kind: Some(Deployment::KIND.to_string()),
+        namespace: Some(namespace.to_string()),
+        name: Some(name.to_string()),
+        ..Default::default()
+    };
+    publish_event(client.clone(), reference, type_, reason, note);
+}
@@ -0,0 +1,636 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::Infallible,
+    future::ready,
+    sync::Arc,
+};
+
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use kube::Client;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+// An in-process stand-in for the Kubernetes API server. Objects of any kind
+// are kept as JSON keyed by their plural resource name, namespace and name,
+// and can be listed, watched, fetched, created, patched and deleted through a
+// kube `Client`. Deployments finish rolling out as soon as they change unless
+// they are held. It backs the tests and replays of recordings.
+#[derive(Clone, Default)]
+pub(crate) struct FakeApi(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    resource_version: u64,
+    objects: BTreeMap<Key, Value>,
+    // Every change, so that watches can start from a resource version.
+    changes: Vec<Change>,
+    watchers: Option<broadcast::Sender<Change>>,
+    held: BTreeSet<(String, String)>,
+}
+
+// The plural resource name, namespace and name of an object. Cluster scoped
+// objects have an empty namespace.
+type Key = (String, String, String);
+
+#[derive(Clone)]
+struct Change {
+    resource_version: u64,
+    key: Key,
+    type_: &'static str,
+    object: Value,
+}
+
+// The parts of a request path.
+struct Target {
+    plural: String,
+    namespace: Option<String>,
+    name: Option<String>,
+    subresource: Option<String>,
+}
+
+impl Target {
+    fn parse(path: &str) -> Option<Self> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let rest = match segments.as_slice() {
+            ["api", _, rest @ ..] => rest,
+            ["apis", _, _, rest @ ..] => rest,
+            _ => return None,
+        };
+        let (namespace, rest) = match rest {
+            ["namespaces", namespace, rest @ ..] if !rest.is_empty() => {
+                (Some(namespace.to_string()), rest)
+            }
+            _ => (None, rest),
+        };
+        Some(Target {
+            plural: rest.first()?.to_string(),
+            namespace,
+            name: rest.get(1).map(|name| name.to_string()),
+            subresource: rest.get(2).map(|subresource| subresource.to_string()),
+        })
+    }
+
+    fn key(&self, name: &str) -> Key {
+        (
+            self.plural.clone(),
+            self.namespace.clone().unwrap_or_default(),
+            name.to_string(),
+        )
+    }
+
+    fn matches(&self, key: &Key, object: &Value, selector: Option<&str>) -> bool {
+        key.0 == self.plural
+            && self
+                .namespace
+                .as_ref()
+                .map(|namespace| namespace == &key.1)
+                .unwrap_or(true)
+            && selector
+                .map(|selector| matches_selector(object, selector))
+                .unwrap_or(true)
+    }
+}
+
+// Supports equality, inequality and existence requirements.
+fn matches_selector(object: &Value, selector: &str) -> bool {
+    let labels = &object["metadata"]["labels"];
+    selector
+        .split(',')
+        .filter(|requirement| !requirement.is_empty())
+        .all(|requirement| {
+            if let Some((key, value)) = requirement.split_once("!=") {
+                labels[key].as_str() != Some(value)
+            } else if let Some((key, value)) = requirement.split_once('=') {
+                labels[key].as_str() == Some(value.trim_start_matches('='))
+            } else {
+                !labels[requirement].is_null()
+            }
+        })
+}
+
+#[cfg(test)]
+fn plural(kind: &str) -> String {
+    format!("{}s", kind.to_lowercase())
+}
+
+#[cfg(test)]
+fn key_of(object: &Value) -> Key {
+    (
+        plural(object["kind"].as_str().unwrap_or_default()),
+        object["metadata"]["namespace"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        object["metadata"]["name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+    )
+}
+
+fn respond(code: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("response is valid")
+}
+
+fn failure(code: StatusCode, reason: &str, message: String) -> Response<Body> {
+    respond(
+        code,
+        json!({
+            "apiVersion": "v1",
+            "kind": "Status",
+            "status": "Failure",
+            "message": message,
+            "reason": reason,
+            "code": code.as_u16()
+        }),
+    )
+}
+
+// ConfigMaps hold at most 1 MiB of data, as they do in a real cluster.
+fn too_large(key: &Key, object: &Value) -> Option<Response<Body>> {
+    let size: usize = object["data"]
+        .as_object()
+        .filter(|_| key.0 == "configmaps")?
+        .iter()
+        .map(|(key, value)| key.len() + value.as_str().map(str::len).unwrap_or_default())
+        .sum();
+    (size > 1024 * 1024).then(|| {
+        failure(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Invalid",
+            format!("{} {}: data must have at most 1048576 bytes", key.0, key.2),
+        )
+    })
+}
+
+// Sets the status of a deployment that has finished rolling out.
+fn roll_out(deployment: &mut Value) {
+    let replicas = deployment["spec"]["replicas"].as_i64().unwrap_or(1);
+    deployment["status"] = json!({
+        "observedGeneration": deployment["metadata"]["generation"],
+        "replicas": replicas,
+        "updatedReplicas": replicas,
+        "readyReplicas": replicas,
+        "availableReplicas": replicas,
+        "conditions": [
+            {"type": "Available", "status": "True", "reason": "MinimumReplicasAvailable"},
+            {"type": "Progressing", "status": "True", "reason": "NewReplicaSetAvailable"}
+        ]
+    });
+}
+
+// Sets the status of a deployment in the middle of a rolling update whose new
+// pod never becomes available. The pods of the old revision are still up, so
+// the deployment is available and progressing like a real one would be.
+fn stall(deployment: &mut Value) {
+    let replicas = deployment["spec"]["replicas"].as_i64().unwrap_or(1);
+    deployment["status"] = json!({
+        "observedGeneration": deployment["metadata"]["generation"],
+        "replicas": replicas + 1,
+        "updatedReplicas": 1,
+        "readyReplicas": replicas,
+        "availableReplicas": replicas,
+        "unavailableReplicas": 1,
+        "conditions": [
+            {"type": "Available", "status": "True", "reason": "MinimumReplicasAvailable"},
+            {"type": "Progressing", "status": "True", "reason": "ReplicaSetUpdated"}
+        ]
+    });
+}
+
+impl State {
+    // Stores an object with a new resource version and notifies watches.
+    fn store(&mut self, key: Key, mut object: Value, type_: &'static str) -> Value {
+        self.resource_version += 1;
+        object["metadata"]["resourceVersion"] = self.resource_version.to_string().into();
+        if key.0 == "deployments" {
+            if self.held.contains(&(key.1.clone(), key.2.clone())) {
+                stall(&mut object);
+            } else {
+                roll_out(&mut object);
+            }
+        }
+        self.objects.insert(key.clone(), object.clone());
+        self.notify(key, object.clone(), type_);
+        object
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Value> {
+        let object = self.objects.remove(key)?;
+        self.resource_version += 1;
+        self.notify(key.clone(), object.clone(), "DELETED");
+        Some(object)
+    }
+
+    fn notify(&mut self, key: Key, object: Value, type_: &'static str) {
+        let change = Change {
+            resource_version: self.resource_version,
+            key,
+            type_,
+            object,
+        };
+        self.changes.push(change.clone());
+        if let Some(watchers) = &self.watchers {
+            let _ = watchers.send(change);
+        }
+    }
+
+    // Fills in the metadata that the API server sets when objects are created.
+    fn created(&mut self, object: &mut Value) {
+        let metadata = &mut object["metadata"];
+        if metadata["name"].is_null() {
+            let prefix = metadata["generateName"].as_str().unwrap_or_default();
+            metadata["name"] = format!("{prefix}{:05}", self.resource_version + 1).into();
+        }
+        metadata["uid"] = format!("uid-{}", self.resource_version + 1).into();
+        metadata["creationTimestamp"] = Utc::now().to_rfc3339().into();
+        metadata["generation"] = 1.into();
+    }
+}
+
+impl FakeApi {
+    // A client whose requests are handled by this API server.
+    pub(crate) fn client(&self) -> Client {
+        let api = self.clone();
+        let service = tower::service_fn(move |request: Request<Body>| {
+            let api = api.clone();
+            async move { Ok::<_, Infallible>(api.handle(request).await) }
+        });
+        Client::new(service, "default")
+    }
+
+    // Creates or replaces an object, as if it was applied with kubectl.
+    #[cfg(test)]
+    pub(crate) fn apply(&self, mut object: Value) -> Value {
+        let key = key_of(&object);
+        let mut state = self.0.lock();
+        match state.objects.get(&key).cloned() {
+            Some(existing) => {
+                object["metadata"]["uid"] = existing["metadata"]["uid"].clone();
+                object["metadata"]["creationTimestamp"] =
+                    existing["metadata"]["creationTimestamp"].clone();
+                let generation = existing["metadata"]["generation"].as_i64().unwrap_or(1);
+                object["metadata"]["generation"] = if existing["spec"] != object["spec"] {
+                    generation + 1
+                } else {
+                    generation
+                }
+                .into();
+                if object.get("status").is_none() {
+                    object["status"] = existing["status"].clone();
+                }
+                state.store(key, object, "MODIFIED")
+            }
+            None => {
+                state.created(&mut object);
+                state.store(key, object, "ADDED")
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get(&self, plural: &str, namespace: &str, name: &str) -> Option<Value> {
+        self.0
+            .lock()
+            .objects
+            .get(&(plural.to_string(), namespace.to_string(), name.to_string()))
+            .cloned()
+    }
+
+    // Every object of a kind across namespaces.
+    #[cfg(test)]
+    pub(crate) fn list(&self, plural: &str) -> Vec<Value> {
+        self.0
+            .lock()
+            .objects
+            .iter()
+            .filter(|(key, _)| key.0 == plural)
+            .map(|(_, object)| object.clone())
+            .collect()
+    }
+
+    // Keeps a deployment from becoming available until it is released.
+    #[cfg(test)]
+    pub(crate) fn hold(&self, namespace: &str, name: &str) {
+        let mut state = self.0.lock();
+        state.held.insert((namespace.to_string(), name.to_string()));
+        let key = (
+            "deployments".to_string(),
+            namespace.to_string(),
+            name.to_string(),
+        );
+        if let Some(deployment) = state.objects.get(&key).cloned() {
+            state.store(key, deployment, "MODIFIED");
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn release(&self, namespace: &str, name: &str) {
+        let mut state = self.0.lock();
+        state
+            .held
+            .remove(&(namespace.to_string(), name.to_string()));
+        let key = (
+            "deployments".to_string(),
+            namespace.to_string(),
+            name.to_string(),
+        );
+        if let Some(deployment) = state.objects.get(&key).cloned() {
+            state.store(key, deployment, "MODIFIED");
+        }
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let method = request.method().clone();
+        let query: HashMap<String, String> =
+            serde_urlencoded::from_str(request.uri().query().unwrap_or_default())
+                .unwrap_or_default();
+        let content_type = request
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let target = match Target::parse(request.uri().path()) {
+            Some(target) => target,
+            None => {
+                return failure(
+                    StatusCode::NOT_FOUND,
+                    "NotFound",
+                    format!("{} not found", request.uri().path()),
+                )
+            }
+        };
+        let body = hyper::body::to_bytes(request.into_body())
+            .await
+            .unwrap_or_default();
+        let dry_run = query.contains_key("dryRun");
+
+        match (method, target.name.clone()) {
+            (Method::GET, None) if query.get("watch").map(String::as_str) == Some("true") => {
+                self.watch(target, &query)
+            }
+            (Method::GET, None) => self.list_response(&target, &query),
+            (Method::GET, Some(name)) => match self.0.lock().objects.get(&target.key(&name)) {
+                Some(object) => respond(StatusCode::OK, object.clone()),
+                None => failure(
+                    StatusCode::NOT_FOUND,
+                    "NotFound",
+                    format!("{} {} not found", target.plural, name),
+                ),
+            },
+            (Method::POST, None) => match serde_json::from_slice(&body) {
+                Ok(object) => self.create(&target, object, dry_run),
+                Err(err) => failure(StatusCode::BAD_REQUEST, "BadRequest", err.to_string()),
+            },
+            (Method::PATCH, Some(name)) => {
+                self.patch(&target, &name, &content_type, &body, dry_run)
+            }
+            (Method::DELETE, Some(name)) => match self.0.lock().remove(&target.key(&name)) {
+                Some(object) => respond(StatusCode::OK, object),
+                None => failure(
+                    StatusCode::NOT_FOUND,
+                    "NotFound",
+                    format!("{} {} not found", target.plural, name),
+                ),
+            },
+            (method, _) => failure(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "MethodNotAllowed",
+                format!("{method} is not supported"),
+            ),
+        }
+    }
+
+    fn list_response(&self, target: &Target, query: &HashMap<String, String>) -> Response<Body> {
+        let state = self.0.lock();
+        let selector = query.get("labelSelector").map(String::as_str);
+        let items: Vec<Value> = state
+            .objects
+            .iter()
+            .filter(|(key, object)| target.matches(key, object, selector))
+            .map(|(_, object)| object.clone())
+            .collect();
+        respond(
+            StatusCode::OK,
+            json!({
+                "apiVersion": "v1",
+                "kind": "List",
+                "metadata": {"resourceVersion": state.resource_version.to_string()},
+                "items": items
+            }),
+        )
+    }
+
+    // Streams the changes after the requested resource version, followed by
+    // changes as they happen.
+    fn watch(&self, target: Target, query: &HashMap<String, String>) -> Response<Body> {
+        let since: u64 = query
+            .get("resourceVersion")
+            .and_then(|version| version.parse().ok())
+            .unwrap_or_default();
+        let selector = query.get("labelSelector").cloned();
+
+        let (backlog, receiver) = {
+            let mut state = self.0.lock();
+            let receiver = state
+                .watchers
+                .get_or_insert_with(|| broadcast::channel(1024).0)
+                .subscribe();
+            let backlog: Vec<Change> = state
+                .changes
+                .iter()
+                .filter(|change| change.resource_version > since)
+                .cloned()
+                .collect();
+            (backlog, receiver)
+        };
+
+        // A watch that falls behind ends, and the watcher starts over with a list.
+        let live = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.ok().map(|change| (change, receiver))
+        });
+        let events = stream::iter(backlog)
+            .chain(live)
+            .filter(move |change| {
+                ready(target.matches(&change.key, &change.object, selector.as_deref()))
+            })
+            .map(|change| {
+                Ok::<_, Infallible>(format!(
+                    "{}\n",
+                    json!({"type": change.type_, "object": change.object})
+                ))
+            });
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::wrap_stream(events))
+            .expect("response is valid")
+    }
+
+    fn create(&self, target: &Target, mut object: Value, dry_run: bool) -> Response<Body> {
+        let mut state = self.0.lock();
+        if let Some(namespace) = &target.namespace {
+            object["metadata"]["namespace"] = namespace.clone().into();
+        }
+        state.created(&mut object);
+        let key = target.key(object["metadata"]["name"].as_str().unwrap_or_default());
+        if state.objects.contains_key(&key) {
+            return failure(
+                StatusCode::CONFLICT,
+                "AlreadyExists",
+                format!("{} {} already exists", key.0, key.2),
+            );
+        }
+        if let Some(response) = too_large(&key, &object) {
+            return response;
+        }
+        if dry_run {
+            return respond(StatusCode::CREATED, object);
+        }
+        respond(StatusCode::CREATED, state.store(key, object, "ADDED"))
+    }
+
+    fn patch(
+        &self,
+        target: &Target,
+        name: &str,
+        content_type: &str,
+        body: &[u8],
+        dry_run: bool,
+    ) -> Response<Body> {
+        let mut state = self.0.lock();
+        let key = target.key(name);
+        let existing = match state.objects.get(&key) {
+            Some(existing) => existing.clone(),
+            // Server-side applies create objects that don't exist yet.
+            None if content_type.starts_with("application/apply-patch") => {
+                drop(state);
+                return match serde_json::from_slice(body) {
+                    Ok(object) => self.create(target, object, dry_run),
+                    Err(err) => failure(StatusCode::BAD_REQUEST, "BadRequest", err.to_string()),
+                };
+            }
+            None => {
+                return failure(
+                    StatusCode::NOT_FOUND,
+                    "NotFound",
+                    format!("{} {} not found", target.plural, name),
+                )
+            }
+        };
+
+        let mut patched = existing.clone();
+        let result = if content_type.starts_with("application/json-patch+json") {
+            serde_json::from_slice::<json_patch::Patch>(body)
+                .map_err(|err| err.to_string())
+                .and_then(|patch| {
+                    json_patch::patch(&mut patched, &patch).map_err(|err| err.to_string())
+                })
+        } else {
+            // Merge, strategic merge and apply patches are all treated as merge patches.
+            serde_json::from_slice::<Value>(body)
+                .map(|patch| json_patch::merge(&mut patched, &patch))
+                .map_err(|err| err.to_string())
+        };
+        if let Err(err) = result {
+            return failure(StatusCode::UNPROCESSABLE_ENTITY, "Invalid", err);
+        }
+
+        // The status subresource only changes the status, and the resource
+        // itself never does.
+        let mut updated = existing.clone();
+        if target.subresource.as_deref() == Some("status") {
+            updated["status"] = patched["status"].take();
+        } else {
+            patched["status"] = existing["status"].clone();
+            if patched["spec"] != existing["spec"] {
+                let generation = existing["metadata"]["generation"].as_i64().unwrap_or(1);
+                patched["metadata"]["generation"] = (generation + 1).into();
+            }
+            updated = patched;
+        }
+
+        if let Some(response) = too_large(&key, &updated) {
+            return response;
+        }
+        if dry_run {
+            return respond(StatusCode::OK, updated);
+        }
+        respond(StatusCode::OK, state.store(key, updated, "MODIFIED"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::api::apps::v1::Deployment;
+    use kube::{
+        api::{Patch, PatchParams},
+        runtime::{watcher, WatchStreamExt},
+        Api,
+    };
+
+    #[tokio::test]
+    async fn test_fake_api() {
+        let api = FakeApi::default();
+        api.apply(json!({
+            "apiVersion": "apps/v1",
+            "kind": "Deployment",
+            "metadata": {"name": "app", "namespace": "foo", "labels": {"app": "app"}},
+            "spec": {
+                "replicas": 2,
+                "selector": {"matchLabels": {"app": "app"}},
+                "template": {"spec": {"containers": [{"name": "app", "image": "app:1"}]}}
+            }
+        }));
+
+        let deployments: Api<Deployment> = Api::namespaced(api.client(), "foo");
+        let mut watch = watcher(
+            deployments.clone(),
+            watcher::Config::default().labels("app=app"),
+        )
+        .applied_objects()
+        .boxed();
+        let deployment = watch.next().await.unwrap().unwrap();
+        assert_eq!(deployment.metadata.generation, Some(1));
+        assert_eq!(deployment.status.unwrap().available_replicas, Some(2));
+
+        api.hold("foo", "app");
+        deployments
+            .patch(
+                "app",
+                &PatchParams::default(),
+                &Patch::Merge(json!({"spec": {"replicas": 3}})),
+            )
+            .await
+            .unwrap();
+        // The hold and the patch.
+        watch.next().await.unwrap().unwrap();
+        let deployment = watch.next().await.unwrap().unwrap();
+        assert_eq!(deployment.metadata.generation, Some(2));
+        let status = deployment.status.unwrap();
+        assert_eq!(status.updated_replicas, Some(1));
+        assert_eq!(status.unavailable_replicas, Some(1));
+
+        api.release("foo", "app");
+        let deployment = watch.next().await.unwrap().unwrap();
+        assert_eq!(deployment.status.unwrap().available_replicas, Some(3));
+
+        assert!(deployments.get_opt("worker").await.unwrap().is_none());
+        assert!(Api::<Deployment>::namespaced(api.client(), "bar")
+            .list(&Default::default())
+            .await
+            .unwrap()
+            .items
+            .is_empty());
+    }
+}
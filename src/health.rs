@@ -0,0 +1,40 @@
+use std::collections::BTreeSet;
+
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+// Tracks the watchers that are disconnected. The controller is only ready
+// while every watcher is connected.
+pub(crate) struct Health {
+    disconnected: Mutex<BTreeSet<String>>,
+    ready: watch::Sender<bool>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            disconnected: Mutex::new(BTreeSet::new()),
+            ready: watch::channel(true).0,
+        }
+    }
+}
+
+impl Health {
+    pub(crate) fn disconnected(&self, watcher: &str) {
+        let mut disconnected = self.disconnected.lock();
+        disconnected.insert(watcher.to_string());
+        self.ready.send_replace(false);
+    }
+
+    pub(crate) fn connected(&self, watcher: &str) {
+        let mut disconnected = self.disconnected.lock();
+        if disconnected.remove(watcher) && disconnected.is_empty() {
+            self.ready.send_replace(true);
+        }
+    }
+
+    // A receiver of whether the controller is ready.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.ready.subscribe()
+    }
+}
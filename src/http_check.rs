@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+// Makes a single health check request, returning an error that describes why
+// the check failed. The check passes when the response status is one of the
+// expected status codes and, if set, the response body contains `body`.
+pub(crate) async fn http_check(
+    client: &reqwest::Client,
+    url: &str,
+    status: &[u16],
+    body: Option<&str>,
+    timeout: Duration,
+) -> Result<()> {
+    let response = client.get(url).timeout(timeout).send().await?;
+
+    let response_status = response.status().as_u16();
+    if !status.contains(&response_status) {
+        return Err(anyhow!("unexpected status code {}", response_status));
+    }
+
+    if let Some(body) = body {
+        let response_body = response.text().await?;
+        if !response_body.contains(body) {
+            return Err(anyhow!("response body does not contain {:?}", body));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::http_stand_in;
+
+    #[tokio::test]
+    async fn test_http_check() {
+        let base_url = http_stand_in(|target| match target {
+            "/healthz" => (200, r#"{"status":"ok"}"#.to_string()),
+            _ => (503, "".to_string()),
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let timeout = Duration::from_secs(5);
+
+        let healthz = format!("{base_url}/healthz");
+        assert!(http_check(&client, &healthz, &[200], None, timeout)
+            .await
+            .is_ok());
+        assert!(http_check(&client, &healthz, &[200], Some("ok"), timeout)
+            .await
+            .is_ok());
+        assert!(
+            http_check(&client, &healthz, &[200], Some("degraded"), timeout)
+                .await
+                .is_err()
+        );
+        assert!(http_check(&client, &healthz, &[204], None, timeout)
+            .await
+            .is_err());
+
+        let missing = format!("{base_url}/missing");
+        assert!(http_check(&client, &missing, &[200], None, timeout)
+            .await
+            .is_err());
+        assert!(http_check(&client, &missing, &[200, 503], None, timeout)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::batch::v1::{Job, JobSpec};
+use kube::api::ObjectMeta;
+
+use crate::k8s_util::replace_tag;
+
+// Builds a job for a workflow from a job spec, setting the tag of the image of
+// each named container to the version. When no containers are named, every
+// container is updated.
+pub(crate) fn build_job(
+    name: &str,
+    workflow: &str,
+    mut spec: JobSpec,
+    containers: &[(String, String)],
+    version: &str,
+) -> Job {
+    if let Some(pod_spec) = spec.template.spec.as_mut() {
+        for container in pod_spec.containers.iter_mut() {
+            let container_version = if containers.is_empty() {
+                Some(version.to_string())
+            } else {
+                containers
+                    .iter()
+                    .find(|x| x.0 == container.name)
+                    .map(|x| x.1.clone())
+            };
+            if let Some(container_version) = container_version {
+                container.image = replace_tag(container.image.clone(), &container_version);
+            }
+        }
+    }
+
+    Job {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{name}-")),
+            annotations: Some(BTreeMap::from([
+                (
+                    "workflow-deploy.ngerakines.me/workflow".to_string(),
+                    workflow.to_string(),
+                ),
+                (
+                    "workflow-deploy.ngerakines.me/version".to_string(),
+                    version.to_string(),
+                ),
+            ])),
+            labels: Some(BTreeMap::from([(
+                "workflow-deploy.ngerakines.me/job".to_string(),
+                name.to_string(),
+            )])),
+            ..Default::default()
+        },
+        spec: Some(spec),
+        status: None,
+    }
+}
+
+// Returns `Some(true)` when a job completed, `Some(false)` when it failed, and
+// `None` when it is still running.
+pub(crate) fn job_finished(job: &Job) -> Option<bool> {
+    let conditions = job.status.as_ref()?.conditions.as_ref()?;
+    for condition in conditions {
+        if condition.status != "True" {
+            continue;
+        }
+        match condition.type_.as_str() {
+            "Complete" => return Some(true),
+            "Failed" => return Some(false),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_build_job() {
+        let spec: JobSpec = serde_json::from_value(json!({
+            "template": {
+                "spec": {
+                    "restartPolicy": "Never",
+                    "containers": [
+                        {"name": "migrate", "image": "localhost:5000/app:1.0.0"},
+                        {"name": "proxy", "image": "proxy:1.0"}
+                    ]
+                }
+            }
+        }))
+        .unwrap();
+
+        let job = build_job(
+            "migrate",
+            "tenants",
+            spec,
+            &[("migrate".to_string(), "1.0.1".to_string())],
+            "1.0.1",
+        );
+        assert_eq!(job.metadata.generate_name, Some("migrate-".to_string()));
+        let containers = job.spec.unwrap().template.spec.unwrap().containers;
+        assert_eq!(
+            containers[0].image,
+            Some("localhost:5000/app:1.0.1".to_string())
+        );
+        assert_eq!(containers[1].image, Some("proxy:1.0".to_string()));
+    }
+
+    #[test]
+    fn test_job_finished() {
+        let job = |conditions: serde_json::Value| -> Job {
+            serde_json::from_value(json!({"metadata": {}, "status": {"conditions": conditions}}))
+                .unwrap()
+        };
+        assert_eq!(job_finished(&job(json!([]))), None);
+        assert_eq!(
+            job_finished(&job(json!([{"type": "Complete", "status": "True"}]))),
+            Some(true)
+        );
+        assert_eq!(
+            job_finished(&job(json!([{"type": "Failed", "status": "True"}]))),
+            Some(false)
+        );
+        assert_eq!(
+            job_finished(&job(json!([{"type": "Failed", "status": "False"}]))),
+            None
+        );
+    }
+}
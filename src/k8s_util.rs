@@ -1,5 +1,7 @@
 use std::collections::BTreeMap;
 
+use k8s_openapi::api::apps::v1::Deployment;
+
 pub(crate) fn annotation_true(annotations: &BTreeMap<String, String>, search: &str) -> bool {
     annotations
         .get(search)
@@ -18,12 +20,68 @@ pub(crate) fn annotation_maybe_int(
         .unwrap_or(None)
 }
 
-pub(crate) fn replace_last(data: Option<String>, search: char, replace: &str) -> Option<String> {
-    data.map(|inner| {
-        let mut parts = inner.rsplitn(2, search);
-        let mut result = String::new();
-        result.push_str(parts.next().unwrap_or_default());
-        result.push_str(replace);
-        result
+// Sets the tag of an image reference, dropping any digest. Only a ':' after the
+// last '/' separates the tag, so that a registry port is not taken for one.
+pub(crate) fn replace_tag(image: Option<String>, tag: &str) -> Option<String> {
+    image.map(|image| {
+        let name = image
+            .split_once('@')
+            .map(|(name, _)| name)
+            .unwrap_or(&image);
+        let path_start = name.rfind('/').map(|index| index + 1).unwrap_or_default();
+        let name = match name[path_start..].rfind(':') {
+            Some(index) => &name[..path_start + index],
+            None => name,
+        };
+        format!("{name}:{tag}")
     })
 }
+
+// A deployment has finished scaling when the controller has observed the
+// latest spec and the number of available and total replicas both match the
+// desired count.
+pub(crate) fn deployment_scaled(deployment: &Deployment) -> bool {
+    let generation = deployment.metadata.generation.unwrap_or_default();
+    let desired = deployment
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.replicas)
+        .unwrap_or(1);
+    deployment
+        .status
+        .as_ref()
+        .map(|status| {
+            status.observed_generation.unwrap_or_default() >= generation
+                && status.available_replicas.unwrap_or_default() == desired
+                && status.replicas.unwrap_or_default() == desired
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_tag() {
+        let cases = [
+            ("app", "app:1.0.1"),
+            ("app:1.0.0", "app:1.0.1"),
+            ("localhost:5000/app", "localhost:5000/app:1.0.1"),
+            ("localhost:5000/app:1.0.0", "localhost:5000/app:1.0.1"),
+            ("app@sha256:4f53cda1", "app:1.0.1"),
+            (
+                "localhost:5000/team/app:1.0.0@sha256:4f53cda1",
+                "localhost:5000/team/app:1.0.1",
+            ),
+        ];
+        for (image, expected) in cases {
+            assert_eq!(
+                replace_tag(Some(image.to_string()), "1.0.1"),
+                Some(expected.to_string()),
+                "{image}"
+            );
+        }
+        assert_eq!(replace_tag(None, "1.0.1"), None);
+    }
+}
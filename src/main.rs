@@ -1,8 +1,10 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use kube::Client;
 use std::borrow::BorrowMut;
-use std::env::args_os;
 use std::fs::OpenOptions;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::signal;
 use tokio::sync::broadcast::Receiver;
@@ -14,42 +16,159 @@ use tracing::{error, info};
 
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use kube::CustomResourceExt;
-
 mod action;
 mod action_loop;
+mod admission;
+mod analysis;
+mod api;
+mod clock;
 mod config;
+mod configmap_storage;
 mod context;
+mod conversion;
 mod crd;
 mod crd_storage;
+mod crd_v1alpha;
+mod events;
+mod fake_api;
+mod health;
+mod http_check;
+mod job;
 mod k8s_util;
 mod metrics;
+mod notify;
+mod patch;
+mod plan;
+mod readiness;
 mod reconcile;
+mod recorder;
+mod supervisor;
+mod template;
+#[cfg(test)]
+mod test_util;
+mod validate;
 mod watch_deployment;
+mod watch_job;
 mod watch_namespace;
+mod watch_pod;
 mod watch_workflow;
 mod when;
+mod work_queue;
 
 use crate::action::Action;
 use crate::action_loop::action_loop;
+use crate::admission::admission_loop;
+use crate::api::api_loop;
+use crate::clock::SystemClock;
 use crate::config::Settings;
-use crate::crd::Workflow;
-use crate::crd_storage::get_workflow_storage;
+use crate::configmap_storage::ConfigMapWorkflowStorager;
+use crate::conversion::conversion_loop;
+use crate::crd::workflow_crd;
+use crate::crd_storage::{get_workflow_storage, WorkflowStorage};
+use crate::notify::{notify_loop, Notifier};
+use crate::plan::{plan_workflow, Resources};
 use crate::reconcile::reconcile_loop;
+use crate::recorder::{read_recording, replay, Recorder};
+use crate::validate::{load_workflow, validate_workflow};
 use crate::watch_deployment::watch_deployment;
+use crate::watch_job::watch_job;
 use crate::watch_namespace::watch_namespace;
 use crate::watch_workflow::watch_workflow;
 
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the controller. This is the default.
+    Run,
+    /// Print the Workflow CRD.
+    DumpCrd {
+        /// Only include this version of the CRD.
+        #[arg(long)]
+        version: Option<String>,
+    },
+    /// Check a workflow file for problems.
+    Validate { workflow: PathBuf },
+    /// Print the dispatch order and the changes a workflow would make to local manifests.
+    Plan {
+        workflow: PathBuf,
+        /// A directory of manifests for the resources that the workflow targets.
+        #[arg(long)]
+        resources: PathBuf,
+    },
+    /// Replay a recording and print the jobs that are dispatched.
+    Replay { recording: PathBuf },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    if args_os().any(|value| value == "--dump-crd") {
-        println!(
-            "{}",
-            serde_yaml::to_string(&Workflow::crd()).expect("crd can be serialized")
-        );
-        return Ok(());
+    match Cli::parse().command.unwrap_or(Command::Run) {
+        Command::Run => run().await,
+        Command::DumpCrd { version } => {
+            println!(
+                "{}",
+                serde_yaml::to_string(&workflow_crd(version.as_deref())?)?
+            );
+            Ok(())
+        }
+        Command::Validate { workflow } => {
+            let problems = validate_workflow(&load_workflow(&workflow)?);
+            for problem in problems.iter() {
+                println!("{}: {}", workflow.display(), problem);
+            }
+            if !problems.is_empty() {
+                return Err(anyhow!("{} problems found", problems.len()));
+            }
+            println!("{}: ok", workflow.display());
+            Ok(())
+        }
+        Command::Plan {
+            workflow,
+            resources,
+        } => {
+            let workflow = load_workflow(&workflow)?;
+            let plan = plan_workflow(&workflow, &Resources::load(&resources)?, Utc::now());
+            println!("{}", serde_yaml::to_string(&plan)?);
+            if plan.plans.iter().any(|plan| !plan.ok) {
+                return Err(anyhow!("unable to plan every group"));
+            }
+            Ok(())
+        }
+        Command::Replay { recording } => {
+            let replay = replay(read_recording(&recording)?, Settings::new()?).await?;
+            for (at, dispatch) in replay.replayed.iter() {
+                println!(
+                    "{} dispatched {} {} {}",
+                    at.to_rfc3339(),
+                    dispatch.workflow,
+                    dispatch.group,
+                    dispatch.checksum
+                );
+            }
+            if let Some(index) = replay.first_difference() {
+                return Err(anyhow!(
+                    "dispatch {} differs from the recording: recorded {:?}, replayed {:?}",
+                    index + 1,
+                    replay.recorded.get(index).map(|(_, dispatch)| dispatch),
+                    replay.replayed.get(index).map(|(_, dispatch)| dispatch)
+                ));
+            }
+            println!(
+                "{} records replayed, {} dispatches match the recording",
+                replay.records,
+                replay.replayed.len()
+            );
+            Ok(())
+        }
     }
+}
 
+async fn run() -> Result<()> {
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(
             std::env::var("RUST_LOG")
@@ -64,19 +183,33 @@ async fn main() -> Result<()> {
     let settings = Settings::new()?;
     settings.validate()?;
 
-    let workflow_storage = get_workflow_storage("memory");
+    let client = Client::try_default().await?;
+    // History is kept in ConfigMaps so that it survives restarts.
+    let workflow_storage: Box<dyn WorkflowStorage> = if settings.storage.namespace.is_empty() {
+        get_workflow_storage("memory")
+    } else {
+        Box::new(ConfigMapWorkflowStorager::load(client.clone(), &settings).await?)
+    };
     let metrics_client = metrics::metrics_client(settings.clone())?;
 
     let (action_tx, mut action_rx) = tokio::sync::mpsc::channel::<Action>(100);
+    let (notifier, mut notification_rx) = Notifier::new(settings.notifications.queue_size);
 
     let app_context = context::Context(Arc::new(context::InnerContext::new(
         settings.clone(),
+        client,
         workflow_storage,
         action_tx.clone(),
         Arc::new(metrics_client),
+        notifier,
+        Arc::new(SystemClock),
+        Recorder::new(&settings.recorder.path)?,
     )));
 
     let (shutdown_tx, _) = tokio::sync::broadcast::channel::<bool>(100);
+    // The action loop is shut down on its own, before everything else, so that
+    // the jobs it drains still see the watchers and the other loops.
+    let (action_shutdown_tx, _) = tokio::sync::broadcast::channel::<bool>(1);
     let (rev_shutdown_tx, mut rev_shutdown_rx) = tokio::sync::broadcast::channel::<bool>(100);
 
     let namespace_join_handler = {
@@ -105,6 +238,19 @@ async fn main() -> Result<()> {
         })
     };
 
+    let job_join_handler = {
+        let j_shutdown_tx = shutdown_tx.clone();
+        let app_context = app_context.clone();
+        let j_rev_shutdown_tx = rev_shutdown_tx.clone();
+        tokio::spawn(async move {
+            let mut loop_rx = j_shutdown_tx.subscribe();
+            if let Err(err) = watch_job(app_context, &mut loop_rx).await {
+                error!(cause = ?err, "watch_job error");
+                j_rev_shutdown_tx.send(true).unwrap();
+            }
+        })
+    };
+
     let workflow_join_handler = {
         let w_shutdown_tx = shutdown_tx.clone();
         let app_context = app_context.clone();
@@ -132,7 +278,7 @@ async fn main() -> Result<()> {
     };
 
     let action_join_handler = {
-        let a_shutdown_tx = shutdown_tx.clone();
+        let a_shutdown_tx = action_shutdown_tx.clone();
         let app_context = app_context.clone();
         let a_rev_shutdown_tx = rev_shutdown_tx.clone();
         tokio::spawn(async move {
@@ -144,28 +290,119 @@ async fn main() -> Result<()> {
         })
     };
 
+    let notify_join_handler = {
+        let n_shutdown_tx = shutdown_tx.clone();
+        let app_context = app_context.clone();
+        let n_rev_shutdown_tx = rev_shutdown_tx.clone();
+        tokio::spawn(async move {
+            let mut loop_rx = n_shutdown_tx.subscribe();
+            if let Err(err) = notify_loop(app_context, &mut loop_rx, &mut notification_rx).await {
+                error!(cause = ?err, "notify_loop error");
+                n_rev_shutdown_tx.send(true).unwrap();
+            }
+        })
+    };
+
+    let api_join_handler = settings.api.enabled.then(|| {
+        let api_shutdown_tx = shutdown_tx.clone();
+        let app_context = app_context.clone();
+        let api_rev_shutdown_tx = rev_shutdown_tx.clone();
+        tokio::spawn(async move {
+            let mut loop_rx = api_shutdown_tx.subscribe();
+            if let Err(err) = api_loop(app_context, &mut loop_rx).await {
+                error!(cause = ?err, "api_loop error");
+                api_rev_shutdown_tx.send(true).unwrap();
+            }
+        })
+    });
+
+    let admission_join_handler = settings.admission.enabled.then(|| {
+        let ad_shutdown_tx = shutdown_tx.clone();
+        let app_context = app_context.clone();
+        let ad_rev_shutdown_tx = rev_shutdown_tx.clone();
+        tokio::spawn(async move {
+            let mut loop_rx = ad_shutdown_tx.subscribe();
+            if let Err(err) = admission_loop(app_context, &mut loop_rx).await {
+                error!(cause = ?err, "admission_loop error");
+                ad_rev_shutdown_tx.send(true).unwrap();
+            }
+        })
+    });
+
+    let conversion_join_handler = settings.conversion.enabled.then(|| {
+        let cv_shutdown_tx = shutdown_tx.clone();
+        let app_context = app_context.clone();
+        let cv_rev_shutdown_tx = rev_shutdown_tx.clone();
+        tokio::spawn(async move {
+            let mut loop_rx = cv_shutdown_tx.subscribe();
+            if let Err(err) = conversion_loop(app_context, &mut loop_rx).await {
+                error!(cause = ?err, "conversion_loop error");
+                cv_rev_shutdown_tx.send(true).unwrap();
+            }
+        })
+    });
+
     OpenOptions::new()
         .create(true)
+        .truncate(true)
         .write(true)
         .open(Path::new("/tmp/started"))?;
     OpenOptions::new()
         .create(true)
+        .truncate(true)
         .write(true)
         .open(Path::new("/tmp/alive"))?;
-    OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(Path::new("/tmp/ready"))?;
+
+    // The controller is ready while all of the watchers are connected.
+    let mut ready_rx = app_context.health.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let ready = *ready_rx.borrow_and_update();
+            let res = if ready {
+                OpenOptions::new()
+                    .create(true)
+                    .truncate(true)
+                    .write(true)
+                    .open(Path::new("/tmp/ready"))
+                    .map(|_| ())
+            } else {
+                std::fs::remove_file("/tmp/ready").or_else(|err| match err.kind() {
+                    std::io::ErrorKind::NotFound => Ok(()),
+                    _ => Err(err),
+                })
+            };
+            if let Err(err) = res {
+                error!(cause = ?err, "unable to update /tmp/ready");
+            }
+            if ready_rx.changed().await.is_err() {
+                break;
+            }
+        }
+    });
 
     shutdown_signal(rev_shutdown_rx.borrow_mut()).await;
 
+    // The action loop may already have stopped on an error.
+    let _ = action_shutdown_tx.send(true);
+    action_join_handler.await?;
+
     shutdown_tx.send(true)?;
 
     namespace_join_handler.await?;
     deployment_join_handler.await?;
+    job_join_handler.await?;
     workflow_join_handler.await?;
     reconcile_join_handler.await?;
-    action_join_handler.await?;
+    notify_join_handler.await?;
+    if let Some(api_join_handler) = api_join_handler {
+        api_join_handler.await?;
+    }
+    if let Some(admission_join_handler) = admission_join_handler {
+        admission_join_handler.await?;
+    }
+    if let Some(conversion_join_handler) = conversion_join_handler {
+        conversion_join_handler.await?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,346 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::{
+    broadcast::Receiver,
+    mpsc::{self, error::TrySendError},
+};
+use tracing::{debug, info, warn};
+
+use crate::{config::Webhook, context::Context, template::render};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NotificationEvent {
+    WorkflowStarted,
+    GroupSucceeded,
+    GroupFailed,
+    Purge,
+    Rollback,
+    SuppressionEntered,
+}
+
+impl NotificationEvent {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            NotificationEvent::WorkflowStarted => "workflow_started",
+            NotificationEvent::GroupSucceeded => "group_succeeded",
+            NotificationEvent::GroupFailed => "group_failed",
+            NotificationEvent::Purge => "purge",
+            NotificationEvent::Rollback => "rollback",
+            NotificationEvent::SuppressionEntered => "suppression_entered",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Notification {
+    pub(crate) event: NotificationEvent,
+    pub(crate) workflow: String,
+    pub(crate) group: Option<String>,
+    pub(crate) version: Option<String>,
+    pub(crate) message: String,
+    pub(crate) timestamp: DateTime<Utc>,
+}
+
+impl Notification {
+    pub(crate) fn new(
+        event: NotificationEvent,
+        workflow: &str,
+        message: String,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            event,
+            workflow: workflow.to_string(),
+            group: None,
+            version: None,
+            message,
+            timestamp,
+        }
+    }
+
+    pub(crate) fn with_group(mut self, group: &str) -> Self {
+        self.group = Some(group.to_string());
+        self
+    }
+
+    pub(crate) fn with_version(mut self, version: &str) -> Self {
+        self.version = Some(version.to_string());
+        self
+    }
+
+    // The request body for a webhook. Without a template, the notification is
+    // sent as JSON. Templates are rendered with JSON escaped values so that
+    // they can be used to build JSON bodies like `{"text": "{{message}}"}`.
+    pub(crate) fn body(&self, template: Option<&str>) -> Result<String> {
+        let template = match template {
+            Some(template) => template,
+            None => return Ok(serde_json::to_string(self)?),
+        };
+
+        let escape = |value: &str| -> Result<String> {
+            let quoted = serde_json::to_string(value)?;
+            Ok(quoted[1..quoted.len() - 1].to_string())
+        };
+        let event = escape(self.event.name())?;
+        let workflow = escape(&self.workflow)?;
+        let group = escape(self.group.as_deref().unwrap_or_default())?;
+        let version = escape(self.version.as_deref().unwrap_or_default())?;
+        let message = escape(&self.message)?;
+        let timestamp = self.timestamp.to_rfc3339();
+
+        Ok(render(
+            template,
+            &[
+                ("event", &event),
+                ("workflow", &workflow),
+                ("group", &group),
+                ("namespace", &group),
+                ("version", &version),
+                ("message", &message),
+                ("timestamp", &timestamp),
+            ],
+        ))
+    }
+}
+
+// Queues notifications for delivery. Notifications are dropped when the queue
+// is full so that a slow receiver never blocks the caller.
+#[derive(Clone)]
+pub(crate) struct Notifier {
+    tx: mpsc::Sender<Notification>,
+}
+
+impl Notifier {
+    pub(crate) fn new(queue_size: usize) -> (Self, mpsc::Receiver<Notification>) {
+        let (tx, rx) = mpsc::channel(queue_size.max(1));
+        (Self { tx }, rx)
+    }
+
+    pub(crate) fn notify(&self, notification: Notification) -> bool {
+        match self.tx.try_send(notification) {
+            Ok(()) => true,
+            Err(TrySendError::Full(notification)) => {
+                warn!(
+                    "notification queue is full, dropping {} notification for {}",
+                    notification.event.name(),
+                    notification.workflow
+                );
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+pub(crate) async fn notify_loop(
+    context: Context,
+    shutdown: &mut Receiver<bool>,
+    rx: &mut mpsc::Receiver<Notification>,
+) -> Result<()> {
+    info!("notify loop started");
+
+    'outer: loop {
+        tokio::select! {
+            biased;
+            _ = shutdown.recv() => {
+                break 'outer;
+            },
+            r = rx.recv() => {
+                let notification = match r {
+                    Some(notification) => notification,
+                    None => break 'outer,
+                };
+                debug!("notify loop got notification: {:?}", notification);
+
+                for webhook in webhooks(&context, &notification.workflow).await {
+                    if !webhook.events.is_empty() && !webhook.events.iter().any(|event| event == notification.event.name()) {
+                        continue;
+                    }
+
+                    let sent = deliver(&context, &webhook, &notification).await;
+                    context
+                        .metrics
+                        .count_with_tags("notify_loop.delivery", 1)
+                        .with_tag("event", notification.event.name())
+                        .with_tag("workflow_name", notification.workflow.as_str())
+                        .with_tag("ok", sent.is_ok().to_string().as_str())
+                        .send();
+                    if let Err(err) = sent {
+                        warn!("unable to deliver {} notification to {}: {}", notification.event.name(), webhook.url, err);
+                    }
+                }
+            }
+        }
+    }
+
+    info!("notify loop ended");
+    Ok(())
+}
+
+// The webhooks configured in settings followed by those of the workflow.
+async fn webhooks(context: &Context, workflow_name: &str) -> Vec<Webhook> {
+    let mut webhooks = context.settings.notifications.webhooks.clone();
+    if let Ok(workflow) = context
+        .workflow_storage
+        .get_workflow(workflow_name.to_string(), None)
+        .await
+    {
+        for notification in workflow.spec.notifications.iter() {
+            webhooks.push(Webhook {
+                url: notification.url.clone(),
+                events: notification.events.clone(),
+                template: notification.template.clone(),
+            });
+        }
+    }
+    webhooks
+}
+
+// Posts a notification to a webhook, retrying with exponential backoff.
+async fn deliver(context: &Context, webhook: &Webhook, notification: &Notification) -> Result<()> {
+    let body = notification.body(webhook.template.as_deref())?;
+    let retries = context.settings.notifications.retries;
+
+    let mut backoff = Duration::from_millis(500);
+    let mut attempt = 0;
+    loop {
+        let result = context
+            .http_client
+            .post(&webhook.url)
+            .header("content-type", "application/json")
+            .body(body.clone())
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|response| {
+                if response.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(anyhow!("unexpected status code {}", response.status()))
+                }
+            });
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt >= retries => return Err(err),
+            Err(err) => {
+                debug!(
+                    "notification to {} failed, retrying in {:?}: {}",
+                    webhook.url, backoff, err
+                );
+                context.clock.sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, ManualClock},
+        config::Settings,
+        fake_api::FakeApi,
+        test_util::{build_context, http_stand_in},
+    };
+    use chrono::TimeZone;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn test_body() {
+        let notification = Notification::new(
+            NotificationEvent::GroupFailed,
+            "tenants",
+            "deployment \"api\" did not become ready".to_string(),
+            Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap(),
+        )
+        .with_group("foo")
+        .with_version("1.0.1");
+
+        assert_eq!(
+            notification
+                .body(Some(
+                    r#"{"text": "{{workflow}} {{version}} {{event}} in {{group}}: {{message}}"}"#
+                ))
+                .unwrap(),
+            r#"{"text": "tenants 1.0.1 group_failed in foo: deployment \"api\" did not become ready"}"#
+        );
+
+        let body: serde_json::Value =
+            serde_json::from_str(&notification.body(None).unwrap()).unwrap();
+        assert_eq!(body["event"], "group_failed");
+        assert_eq!(body["group"], "foo");
+        assert_eq!(body["timestamp"], "2023-05-01T12:00:00Z");
+    }
+
+    #[test]
+    fn test_notifier_drops_when_full() {
+        let (notifier, _rx) = Notifier::new(1);
+        let notification = Notification::new(
+            NotificationEvent::WorkflowStarted,
+            "tenants",
+            "started".to_string(),
+            DateTime::default(),
+        );
+        assert!(notifier.notify(notification.clone()));
+        assert!(!notifier.notify(notification));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let url = http_stand_in({
+            let requests = requests.clone();
+            move |_| match requests.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => (500, String::new()),
+                _ => (200, String::new()),
+            }
+        })
+        .await;
+
+        let start = Utc::now();
+        let clock = Arc::new(ManualClock::new(start));
+        let mut settings = Settings::new().unwrap();
+        settings.notifications.retries = 3;
+        let (action_tx, _) = mpsc::channel(1);
+        let context = build_context(settings, FakeApi::default(), action_tx, clock.clone());
+        let webhook = Webhook {
+            url,
+            events: vec![],
+            template: None,
+        };
+        let notification = Notification::new(
+            NotificationEvent::WorkflowStarted,
+            "tenants",
+            "started".to_string(),
+            clock.now(),
+        );
+
+        // The retries back off for 500ms and then 1s of clock time.
+        let delivery = deliver(&context, &webhook, &notification);
+        tokio::pin!(delivery);
+        let result = loop {
+            tokio::select! {
+                result = &mut delivery => break result,
+                _ = tokio::time::sleep(Duration::from_millis(5)) => {
+                    clock.advance_to(clock.now() + chrono::Duration::milliseconds(100));
+                }
+            }
+        };
+        assert!(result.is_ok());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        let elapsed = clock.now() - start;
+        assert!(elapsed >= chrono::Duration::milliseconds(1500));
+        assert!(elapsed < chrono::Duration::seconds(5));
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+
+use json_patch::{AddOperation, Patch, PatchOperation, RemoveOperation, ReplaceOperation};
+use k8s_openapi::api::{apps::v1::Deployment, core::v1::Container};
+use serde_json::{json, Value};
+
+use crate::k8s_util::replace_tag;
+
+fn deployment_containers(deployment: &Deployment) -> Vec<Container> {
+    deployment
+        .spec
+        .clone()
+        .unwrap_or_default()
+        .template
+        .spec
+        .unwrap_or_default()
+        .containers
+}
+
+// The index, current image and new image of each named container whose image
+// tag is replaced with the given version.
+pub(crate) fn image_changes(
+    deployment: &Deployment,
+    containers: &[(String, String)],
+) -> Vec<(usize, String, String)> {
+    let mut changes = vec![];
+    for (index, container) in deployment_containers(deployment).iter().enumerate() {
+        if let Some(version) = containers
+            .iter()
+            .find(|x| x.0 == container.name)
+            .map(|x| x.1.clone())
+        {
+            if let Some(container_image) = replace_tag(container.image.clone(), &version) {
+                changes.push((
+                    index,
+                    container.image.clone().unwrap_or_default(),
+                    container_image,
+                ));
+            }
+        }
+    }
+    changes
+}
+
+// Replaces the tag of each named container's image with the given version.
+pub(crate) fn image_patch(deployment: &Deployment, containers: &[(String, String)]) -> Patch {
+    Patch(
+        image_changes(deployment, containers)
+            .into_iter()
+            .map(|(index, _, container_image)| {
+                PatchOperation::Replace(ReplaceOperation {
+                    path: format!("/spec/template/spec/containers/{index}/image"),
+                    value: json!(container_image),
+                })
+            })
+            .collect(),
+    )
+}
+
+// Sets or removes environment variables on each of the named containers. An
+// env value of `None` removes the variable. Replacements are emitted first,
+// then removals in descending index order so that earlier indexes stay valid,
+// and finally additions.
+pub(crate) fn env_patch(
+    deployment: &Deployment,
+    containers: &[String],
+    env: &[(String, Option<String>)],
+) -> Patch {
+    let mut json_patch = Patch(vec![]);
+    for (index, container) in deployment_containers(deployment).iter().enumerate() {
+        if !containers.contains(&container.name) {
+            continue;
+        }
+        let path = format!("/spec/template/spec/containers/{index}/env");
+        let existing = container.env.clone().unwrap_or_default();
+
+        let mut removals: Vec<usize> = vec![];
+        let mut additions: Vec<Value> = vec![];
+
+        for (name, value) in env {
+            let found = existing.iter().position(|var| &var.name == name);
+            match (found, value) {
+                (Some(env_index), Some(value)) => {
+                    json_patch.0.push(PatchOperation::Replace(ReplaceOperation {
+                        path: format!("{path}/{env_index}"),
+                        value: json!({"name": name, "value": value}),
+                    }));
+                }
+                (Some(env_index), None) => removals.push(env_index),
+                (None, Some(value)) => additions.push(json!({"name": name, "value": value})),
+                (None, None) => {}
+            }
+        }
+
+        removals.sort_unstable();
+        removals.dedup();
+        for env_index in removals.into_iter().rev() {
+            json_patch.0.push(PatchOperation::Remove(RemoveOperation {
+                path: format!("{path}/{env_index}"),
+            }));
+        }
+
+        if container.env.is_none() && !additions.is_empty() {
+            json_patch.0.push(PatchOperation::Add(AddOperation {
+                path: path.clone(),
+                value: json!([]),
+            }));
+        }
+        for addition in additions {
+            json_patch.0.push(PatchOperation::Add(AddOperation {
+                path: format!("{path}/-"),
+                value: addition,
+            }));
+        }
+    }
+    json_patch
+}
+
+// A merge patch that sets a single annotation on the pod template, which
+// causes the deployment to roll its pods.
+pub(crate) fn pod_template_annotation_patch(key: &str, value: &str) -> Value {
+    json!({
+        "spec": {
+            "template": {
+                "metadata": {
+                    "annotations": {
+                        key: value
+                    }
+                }
+            }
+        }
+    })
+}
+
+// A change to the number of replicas of a deployment, parsed from values like
+// "3" (absolute), "+2" or "-1" (relative to the current replica count).
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub(crate) enum ReplicaChange {
+    Absolute(i32),
+    Relative(i32),
+}
+
+impl ReplicaChange {
+    pub(crate) fn parse(value: &str) -> Option<ReplicaChange> {
+        let value = value.trim();
+        if value.starts_with('+') || value.starts_with('-') {
+            return value.parse::<i32>().ok().map(ReplicaChange::Relative);
+        }
+        value
+            .parse::<i32>()
+            .ok()
+            .filter(|replicas| *replicas >= 0)
+            .map(ReplicaChange::Absolute)
+    }
+
+    pub(crate) fn apply(&self, current: i32) -> i32 {
+        match self {
+            ReplicaChange::Absolute(replicas) => *replicas,
+            ReplicaChange::Relative(delta) => (current + delta).max(0),
+        }
+    }
+}
+
+// A merge patch that sets the number of replicas of a deployment.
+pub(crate) fn scale_patch(replicas: i32) -> Value {
+    json!({ "spec": { "replicas": replicas } })
+}
+
+// A merge patch that sets the given keys of a config map's data.
+pub(crate) fn config_map_data_patch(data: &BTreeMap<String, String>) -> Value {
+    json!({ "data": data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment() -> Deployment {
+        serde_json::from_value(json!({
+            "metadata": {"name": "app"},
+            "spec": {
+                "selector": {},
+                "template": {
+                    "spec": {
+                        "containers": [
+                            {"name": "sidecar", "image": "proxy:1.0"},
+                            {"name": "app", "image": "localhost:5000/app:1.0.0", "env": [
+                                {"name": "A", "value": "1"},
+                                {"name": "B", "value": "2"},
+                                {"name": "C", "value": "3"}
+                            ]}
+                        ]
+                    }
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_image_patch() {
+        let patch = image_patch(&deployment(), &[("app".to_string(), "1.0.1".to_string())]);
+        assert_eq!(
+            serde_json::to_value(patch).unwrap(),
+            json!([{"op": "replace", "path": "/spec/template/spec/containers/1/image", "value": "localhost:5000/app:1.0.1"}])
+        );
+    }
+
+    #[test]
+    fn test_replica_change() {
+        assert_eq!(ReplicaChange::parse("3"), Some(ReplicaChange::Absolute(3)));
+        assert_eq!(ReplicaChange::parse("+2"), Some(ReplicaChange::Relative(2)));
+        assert_eq!(
+            ReplicaChange::parse("-1"),
+            Some(ReplicaChange::Relative(-1))
+        );
+        assert_eq!(ReplicaChange::parse("many"), None);
+        assert_eq!(ReplicaChange::Absolute(3).apply(5), 3);
+        assert_eq!(ReplicaChange::Relative(2).apply(5), 7);
+        assert_eq!(ReplicaChange::Relative(-7).apply(5), 0);
+    }
+
+    #[test]
+    fn test_env_patch() {
+        let patch = env_patch(
+            &deployment(),
+            &["app".to_string(), "sidecar".to_string()],
+            &[
+                ("A".to_string(), None),
+                ("B".to_string(), Some("two".to_string())),
+                ("C".to_string(), None),
+                ("D".to_string(), Some("4".to_string())),
+            ],
+        );
+        assert_eq!(
+            serde_json::to_value(patch).unwrap(),
+            json!([
+                {"op": "add", "path": "/spec/template/spec/containers/0/env", "value": []},
+                {"op": "add", "path": "/spec/template/spec/containers/0/env/-", "value": {"name": "B", "value": "two"}},
+                {"op": "add", "path": "/spec/template/spec/containers/0/env/-", "value": {"name": "D", "value": "4"}},
+                {"op": "replace", "path": "/spec/template/spec/containers/1/env/1", "value": {"name": "B", "value": "two"}},
+                {"op": "remove", "path": "/spec/template/spec/containers/1/env/2"},
+                {"op": "remove", "path": "/spec/template/spec/containers/1/env/0"},
+                {"op": "add", "path": "/spec/template/spec/containers/1/env/-", "value": {"name": "D", "value": "4"}}
+            ])
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::{apps::v1::Deployment, batch::v1::JobSpec};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    action_loop::{build_work_queue, WorkflowAction},
+    crd::Workflow,
+    crd_storage::{PlanStep, WorkflowPlan},
+    job::build_job,
+    patch::{
+        config_map_data_patch, env_patch, image_patch, pod_template_annotation_patch, scale_patch,
+        ReplicaChange,
+    },
+};
+
+// Manifests read from local files. Manifests without a namespace are used for
+// every namespace of a workflow.
+pub(crate) struct Resources {
+    objects: Vec<Value>,
+}
+
+impl Resources {
+    // Reads every YAML and JSON manifest in a directory and its subdirectories.
+    // Files can contain multiple YAML documents.
+    pub(crate) fn load(dir: &Path) -> Result<Self> {
+        let mut objects = vec![];
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir)
+                .map_err(|err| anyhow!("unable to read {}: {}", dir.display(), err))?
+            {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let extension = path.extension().and_then(|extension| extension.to_str());
+                if !matches!(extension, Some("yaml") | Some("yml") | Some("json")) {
+                    continue;
+                }
+                let content = std::fs::read_to_string(&path)?;
+                for document in serde_yaml::Deserializer::from_str(&content) {
+                    let value = Value::deserialize(document)
+                        .map_err(|err| anyhow!("unable to parse {}: {}", path.display(), err))?;
+                    if value.get("kind").is_some() {
+                        objects.push(value);
+                    }
+                }
+            }
+        }
+        Ok(Self { objects })
+    }
+
+    fn find(&self, kind: &str, namespace: &str, name: &str) -> Option<Value> {
+        let matches = |object: &&Value, namespace: Option<&str>| {
+            object["kind"] == kind
+                && object["metadata"]["name"] == name
+                && object["metadata"]["namespace"].as_str() == namespace
+        };
+        self.objects
+            .iter()
+            .find(|object| matches(object, Some(namespace)))
+            .or_else(|| self.objects.iter().find(|object| matches(object, None)))
+            .cloned()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Plan {
+    // The namespaces dispatched together, in order, given the workflow's parallel limit.
+    pub(crate) dispatch_order: Vec<Vec<String>>,
+    pub(crate) plans: Vec<WorkflowPlan>,
+}
+
+pub(crate) fn plan_workflow(
+    workflow: &Workflow,
+    resources: &Resources,
+    now: DateTime<Utc>,
+) -> Plan {
+    Plan {
+        dispatch_order: dispatch_order(workflow),
+        plans: workflow
+            .spec
+            .namespaces
+            .iter()
+            .map(|namespace| plan_group(workflow, namespace, resources, now))
+            .collect(),
+    }
+}
+
+// Groups are dispatched in the order they are listed, with at most `parallel`
+// in flight at a time.
+pub(crate) fn dispatch_order(workflow: &Workflow) -> Vec<Vec<String>> {
+    let mut seen = HashSet::new();
+    let namespaces: Vec<String> = workflow
+        .spec
+        .namespaces
+        .iter()
+        .filter(|namespace| seen.insert(namespace.as_str()))
+        .cloned()
+        .collect();
+    namespaces
+        .chunks(workflow.spec.parallel.unwrap_or(1).max(1) as usize)
+        .map(|chunk| chunk.to_vec())
+        .collect()
+}
+
+// Plans the changes that a workflow job would make to a group. Each change is
+// applied to a local copy of its target so that later actions see the result
+// of earlier ones.
+pub(crate) fn plan_group(
+    workflow: &Workflow,
+    namespace: &str,
+    resources: &Resources,
+    now: DateTime<Utc>,
+) -> WorkflowPlan {
+    let mut objects: BTreeMap<(String, String), Value> = BTreeMap::new();
+    let mut steps = vec![];
+    let mut reason = None;
+
+    for action in build_work_queue(workflow, namespace) {
+        if let WorkflowAction::Started() = action {
+            continue;
+        }
+        match plan_action(workflow, namespace, resources, &mut objects, &action, now) {
+            Ok(patch) => steps.push(PlanStep {
+                action: action.name().to_string(),
+                target: action.target().to_string(),
+                patch,
+            }),
+            Err(err) => {
+                reason = Some(format!("{} {}: {}", action.name(), action.target(), err));
+                break;
+            }
+        }
+    }
+
+    WorkflowPlan {
+        workflow: workflow.metadata.name.clone().unwrap_or_default(),
+        checksum: workflow.checksum(),
+        namespace: namespace.to_string(),
+        version: workflow.spec.version.clone(),
+        planned_at: now,
+        ok: reason.is_none(),
+        reason,
+        steps,
+    }
+}
+
+// The local copy of a target, read from the manifests when first used.
+fn object<'a>(
+    resources: &Resources,
+    namespace: &str,
+    objects: &'a mut BTreeMap<(String, String), Value>,
+    kind: &str,
+    name: &str,
+) -> Result<&'a mut Value> {
+    let key = (kind.to_string(), name.to_string());
+    if !objects.contains_key(&key) {
+        let object = resources
+            .find(kind, namespace, name)
+            .ok_or_else(|| anyhow!("{} {} not found", kind, name))?;
+        objects.insert(key.clone(), object);
+    }
+    Ok(objects.get_mut(&key).unwrap())
+}
+
+fn plan_action(
+    workflow: &Workflow,
+    namespace: &str,
+    resources: &Resources,
+    objects: &mut BTreeMap<(String, String), Value>,
+    action: &WorkflowAction,
+    now: DateTime<Utc>,
+) -> Result<Option<Value>> {
+    let deployment =
+        |value: &Value| -> Result<Deployment> { Ok(serde_json::from_value(value.clone())?) };
+
+    let patch = match action {
+        _ if action.observes() => return Ok(None),
+        WorkflowAction::UpdateDeployment(name, containers) => {
+            let target = object(resources, namespace, objects, "Deployment", name)?;
+            let patch = image_patch(&deployment(target)?, containers);
+            json_patch::patch(target, &patch)?;
+            serde_json::to_value(patch)?
+        }
+        WorkflowAction::UpdateEnv(name, containers, env) => {
+            let target = object(resources, namespace, objects, "Deployment", name)?;
+            let patch = env_patch(&deployment(target)?, containers, env);
+            json_patch::patch(target, &patch)?;
+            serde_json::to_value(patch)?
+        }
+        WorkflowAction::UpdateConfig(name, data) => {
+            let patch = config_map_data_patch(data);
+            json_patch::merge(
+                object(resources, namespace, objects, "ConfigMap", name)?,
+                &patch,
+            );
+            patch
+        }
+        WorkflowAction::AnnotateDeployment(name, key, value) => {
+            let patch = pod_template_annotation_patch(key, value);
+            json_patch::merge(
+                object(resources, namespace, objects, "Deployment", name)?,
+                &patch,
+            );
+            patch
+        }
+        WorkflowAction::RestartDeployment(name) => {
+            let patch = pod_template_annotation_patch(
+                "kubectl.kubernetes.io/restartedAt",
+                &now.to_rfc3339(),
+            );
+            json_patch::merge(
+                object(resources, namespace, objects, "Deployment", name)?,
+                &patch,
+            );
+            patch
+        }
+        WorkflowAction::ScaleDeployment(name, replicas) => {
+            let target = object(resources, namespace, objects, "Deployment", name)?;
+            let current = target["spec"]["replicas"].as_i64().unwrap_or(1) as i32;
+            let replicas = ReplicaChange::parse(replicas)
+                .ok_or_else(|| anyhow!("invalid replicas {:?}", replicas))?;
+            let patch = scale_patch(replicas.apply(current));
+            json_patch::merge(target, &patch);
+            patch
+        }
+        WorkflowAction::RunJob(target) => {
+            let spec: JobSpec = match (&target.spec, &target.template) {
+                (Some(spec), _) => serde_json::from_str(spec)?,
+                (None, Some(template)) => serde_json::from_value(
+                    object(resources, namespace, objects, "CronJob", template)?["spec"]
+                        ["jobTemplate"]["spec"]
+                        .clone(),
+                )?,
+                (None, None) => return Err(anyhow!("no job or template set")),
+            };
+            let job = build_job(
+                &target.name,
+                workflow.metadata.name.as_deref().unwrap_or_default(),
+                spec,
+                &target.containers,
+                &workflow.spec.version,
+            );
+            serde_json::to_value(job)?
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(patch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_workflow() {
+        let dir = std::env::temp_dir().join(format!("plan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("app.yaml"),
+            r#"
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: app
+spec:
+  replicas: 2
+  selector: {}
+  template:
+    spec:
+      containers:
+      - name: app
+        image: localhost:5000/app:1.0.0
+---
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: app
+  namespace: bar
+spec:
+  replicas: 4
+  selector: {}
+  template:
+    spec:
+      containers:
+      - name: app
+        image: localhost:5000/app:0.9.0
+"#,
+        )
+        .unwrap();
+        let resources = Resources::load(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Externally tagged enums are read through JSON values, as they are
+        // from the API server.
+        let workflow: serde_json::Value = serde_yaml::from_str(
+            r#"
+apiVersion: workflow-deploy.ngerakines.me/v1beta1
+kind: Workflow
+metadata:
+  name: tenants
+spec:
+  namespaces: ["foo", "bar", "baz"]
+  version: "1.0.1"
+  parallel: 2
+  steps:
+  - actions:
+    - update_deployment:
+        targets:
+        - name: app
+          containers: ["app"]
+    - scale:
+        targets:
+        - name: app
+          replicas: "+1"
+    - restart_deployment:
+        targets:
+        - name: worker
+"#,
+        )
+        .unwrap();
+        let workflow: Workflow = serde_json::from_value(workflow).unwrap();
+
+        let plan = plan_workflow(&workflow, &resources, Utc::now());
+        assert_eq!(
+            plan.dispatch_order,
+            vec![
+                vec!["foo".to_string(), "bar".to_string()],
+                vec!["baz".to_string()]
+            ]
+        );
+
+        let bar = &plan.plans[1];
+        assert_eq!(bar.namespace, "bar");
+        assert_eq!(
+            bar.steps
+                .iter()
+                .map(|step| step.action.as_str())
+                .collect::<Vec<&str>>(),
+            vec![
+                "update_deployment",
+                "wait_deployment_ready",
+                "scale",
+                "wait_deployment_scaled"
+            ]
+        );
+        assert_eq!(
+            bar.steps[0].patch,
+            Some(
+                serde_json::json!([{"op": "replace", "path": "/spec/template/spec/containers/0/image", "value": "localhost:5000/app:1.0.1"}])
+            )
+        );
+        assert_eq!(
+            bar.steps[2].patch,
+            Some(serde_json::json!({"spec": {"replicas": 5}}))
+        );
+        assert!(!bar.ok);
+        assert_eq!(
+            bar.reason.as_deref(),
+            Some("restart_deployment worker: Deployment worker not found")
+        );
+        assert_eq!(
+            plan.plans[0].steps[2].patch,
+            Some(serde_json::json!({"spec": {"replicas": 3}}))
+        );
+    }
+}
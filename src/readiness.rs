@@ -0,0 +1,209 @@
+use std::collections::{HashMap, HashSet};
+
+use k8s_openapi::api::apps::v1::Deployment;
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+// The state of a deployment as last seen by the deployment watcher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DeploymentState {
+    pub(crate) generation: i64,
+    // Whether the current generation has rolled out and is available.
+    pub(crate) ready: bool,
+}
+
+impl DeploymentState {
+    pub(crate) fn new(deployment: &Deployment) -> Self {
+        let generation = deployment.metadata.generation.unwrap_or_default();
+        let replicas = deployment
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.replicas)
+            .unwrap_or(1);
+        // A deployment is available and progressing for most of a rolling
+        // update, so it has only rolled out once every replica is updated and
+        // available and the pods of older revisions are gone. A replica set
+        // that fails to create pods is reported with a ReplicaFailure
+        // condition that is true.
+        let ready = deployment
+            .status
+            .as_ref()
+            .map(|status| {
+                let condition = |type_: &str| {
+                    status
+                        .conditions
+                        .iter()
+                        .flatten()
+                        .find(|condition| condition.type_ == type_)
+                        .map(|condition| condition.status.as_str())
+                };
+                status.observed_generation.unwrap_or_default() >= generation
+                    && status.updated_replicas.unwrap_or_default() == replicas
+                    && status.available_replicas.unwrap_or_default() == replicas
+                    && status.replicas.unwrap_or_default() == replicas
+                    && condition("Available") == Some("True")
+                    && condition("Progressing").unwrap_or("True") == "True"
+                    && condition("ReplicaFailure") != Some("True")
+            })
+            .unwrap_or_default();
+        Self { generation, ready }
+    }
+
+    // Whether the given generation, or a later one, is ready.
+    pub(crate) fn ready_at(&self, generation: i64) -> bool {
+        self.ready && self.generation >= generation
+    }
+}
+
+pub(crate) type Key = (String, String);
+
+// Publishes the state of each deployment or job, keyed by namespace and name,
+// so that waits are woken when the object they wait on changes instead of
+// polling.
+pub(crate) struct Readiness<T>(Mutex<HashMap<Key, watch::Sender<Option<T>>>>);
+
+impl<T> Default for Readiness<T> {
+    fn default() -> Self {
+        Self(Mutex::default())
+    }
+}
+
+impl<T: Copy> Readiness<T> {
+    // Sets the state of an object, or clears it when the object is deleted.
+    pub(crate) fn update(&self, namespace: &str, name: &str, state: Option<T>) {
+        let mut senders = self.0.lock();
+        let key = (namespace.to_string(), name.to_string());
+        match senders.get(&key) {
+            Some(sender) => {
+                sender.send_replace(state);
+                if state.is_none() && sender.receiver_count() == 0 {
+                    senders.remove(&key);
+                }
+            }
+            None => {
+                if state.is_some() {
+                    senders.insert(key, watch::channel(state).0);
+                }
+            }
+        }
+    }
+
+    // Clears the state of the objects that are not in the given set, after
+    // their watcher has resynced.
+    pub(crate) fn retain(&self, objects: &HashSet<Key>) {
+        let mut senders = self.0.lock();
+        senders.retain(|key, sender| {
+            if objects.contains(key) {
+                return true;
+            }
+            sender.send_replace(None);
+            sender.receiver_count() > 0
+        });
+    }
+
+    // A receiver of the state of an object, which is none until the object
+    // has been seen.
+    pub(crate) fn subscribe(&self, namespace: &str, name: &str) -> watch::Receiver<Option<T>> {
+        self.0
+            .lock()
+            .entry((namespace.to_string(), name.to_string()))
+            .or_insert_with(|| watch::channel(None).0)
+            .subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let deployment: Deployment = serde_json::from_value(json!({
+            "metadata": {"name": "app", "namespace": "foo", "generation": 3},
+            "status": {
+                "observedGeneration": 2,
+                "conditions": [{"type": "Available", "status": "True"}]
+            }
+        }))
+        .unwrap();
+        let stale = DeploymentState::new(&deployment);
+        assert!(!stale.ready_at(3));
+
+        // The new spec has been observed, but the rolling update is still
+        // replacing the old pods.
+        let rolling: Deployment = serde_json::from_value(json!({
+            "metadata": {"name": "app", "namespace": "foo", "generation": 3},
+            "spec": {"replicas": 2, "selector": {}},
+            "status": {
+                "observedGeneration": 3,
+                "replicas": 3,
+                "updatedReplicas": 1,
+                "availableReplicas": 2,
+                "conditions": [
+                    {"type": "Available", "status": "True", "reason": "MinimumReplicasAvailable"},
+                    {"type": "Progressing", "status": "True", "reason": "ReplicaSetUpdated"}
+                ]
+            }
+        }))
+        .unwrap();
+        assert!(!DeploymentState::new(&rolling).ready_at(3));
+
+        let rolled_out: Deployment = serde_json::from_value(json!({
+            "metadata": {"name": "app", "namespace": "foo", "generation": 3},
+            "spec": {"replicas": 2, "selector": {}},
+            "status": {
+                "observedGeneration": 3,
+                "replicas": 2,
+                "updatedReplicas": 2,
+                "availableReplicas": 2,
+                "conditions": [
+                    {"type": "Available", "status": "True", "reason": "MinimumReplicasAvailable"},
+                    {"type": "Progressing", "status": "True", "reason": "NewReplicaSetAvailable"}
+                ]
+            }
+        }))
+        .unwrap();
+        assert!(DeploymentState::new(&rolled_out).ready_at(3));
+
+        // A replica set that can't create pods, for example because of a
+        // quota, is not ready even though the counts and the other conditions
+        // are.
+        let mut replica_failure = rolled_out.clone();
+        replica_failure
+            .status
+            .as_mut()
+            .unwrap()
+            .conditions
+            .as_mut()
+            .unwrap()
+            .push(
+                serde_json::from_value(json!({
+                    "type": "ReplicaFailure",
+                    "status": "True",
+                    "reason": "FailedCreate"
+                }))
+                .unwrap(),
+            );
+        assert!(!DeploymentState::new(&replica_failure).ready_at(3));
+
+        let readiness = Readiness::<DeploymentState>::default();
+        let mut changes = readiness.subscribe("foo", "app");
+        assert_eq!(*changes.borrow_and_update(), None);
+
+        readiness.update("foo", "app", Some(stale));
+        changes.changed().await.unwrap();
+        readiness.update(
+            "foo",
+            "app",
+            Some(DeploymentState {
+                generation: 3,
+                ready: true,
+            }),
+        );
+        assert!(changes.borrow_and_update().unwrap().ready_at(3));
+
+        readiness.update("foo", "app", None);
+        assert_eq!(*changes.borrow_and_update(), None);
+    }
+}
@@ -3,10 +3,7 @@ use std::collections::HashMap;
 use anyhow::Result;
 use chrono::{DateTime, Duration, Utc};
 use kube::api::ResourceExt;
-use tokio::{
-    sync::broadcast::Receiver,
-    time::{sleep, Instant},
-};
+use tokio::sync::broadcast::Receiver;
 use tracing::{debug, error, info};
 
 use crate::{action::Action, context::Context};
@@ -16,8 +13,7 @@ pub(crate) async fn reconcile_loop(context: Context, shutdown: &mut Receiver<boo
 
     let interval = Duration::seconds(60).to_std()?;
 
-    let sleeper = sleep(interval);
-    tokio::pin!(sleeper);
+    let mut sleeper = context.clock.sleep(interval);
 
     let mut reconcile_checks: HashMap<String, DateTime<Utc>> = HashMap::new();
 
@@ -28,7 +24,7 @@ pub(crate) async fn reconcile_loop(context: Context, shutdown: &mut Receiver<boo
             },
             () = &mut sleeper => {
                 debug!("Reconcile loop tick");
-                let now = Utc::now();
+                let now = context.clock.now();
 
                 let workflows = context.workflow_storage.get_latest_workflows().await?;
 
@@ -55,8 +51,23 @@ pub(crate) async fn reconcile_loop(context: Context, shutdown: &mut Receiver<boo
                     }
                 }
 
+                let history_cutoff = now - Duration::seconds(context.settings.history.retention_seconds as i64);
+                match context.workflow_storage.prune_history(history_cutoff).await {
+                    Ok(pruned) if pruned > 0 => {
+                        context
+                            .metrics
+                            .count_with_tags("reconcile_loop.history_pruned", pruned as i64)
+                            .send();
+                        debug!("Pruned {pruned} history records finished before {history_cutoff}");
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        error!("Failed to prune history: {}", err);
+                    }
+                }
+
                 // TODO: Warn if workflow intervals are less than the cycle interval.
-                sleeper.as_mut().reset(Instant::now() + interval);
+                sleeper = context.clock.sleep(interval);
             }
         }
     }
@@ -0,0 +1,272 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use cadence::{NopMetricSink, StatsdClient};
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::{apps::v1::Deployment, core::v1::Namespace};
+use kube::runtime::watcher;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::{
+    action::Action,
+    action_loop::{Dispatcher, WorkflowJob, DISPATCH_INTERVAL},
+    clock::ManualClock,
+    config::Settings,
+    context::{Context, InnerContext},
+    crd::Workflow,
+    crd_storage::get_workflow_storage,
+    fake_api::FakeApi,
+    notify::Notifier,
+    watch_deployment::handle_deployment_event,
+    watch_namespace::handle_namespace_event,
+    watch_workflow::handle_workflow_event,
+};
+
+// An event seen by a watcher. The watcher restarts with the full set of
+// objects when it starts and after it reconnects.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WatchEvent<K> {
+    Restarted(Vec<K>),
+    Applied(K),
+    Deleted(K),
+}
+
+impl<K> From<watcher::Event<K>> for WatchEvent<K> {
+    fn from(event: watcher::Event<K>) -> Self {
+        match event {
+            watcher::Event::Restarted(objects) => WatchEvent::Restarted(objects),
+            watcher::Event::Applied(object) => WatchEvent::Applied(object),
+            watcher::Event::Deleted(object) => WatchEvent::Deleted(object),
+        }
+    }
+}
+
+// A job that the action loop dispatched.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Dispatch {
+    pub(crate) workflow: String,
+    pub(crate) checksum: u64,
+    pub(crate) group: String,
+}
+
+impl From<&WorkflowJob> for Dispatch {
+    fn from(job: &WorkflowJob) -> Self {
+        Self {
+            workflow: job.workflow.clone(),
+            checksum: job.checksum,
+            group: job.group.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Entry {
+    Namespace(WatchEvent<Namespace>),
+    Deployment(WatchEvent<Deployment>),
+    Workflow(WatchEvent<Workflow>),
+    // An action received by the action loop.
+    Action(Action),
+    Dispatched(Dispatch),
+}
+
+// A line of a recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Record {
+    pub(crate) at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub(crate) entry: Entry,
+}
+
+// Appends records to a newline-delimited JSON file. Nothing is recorded when
+// no path is configured.
+#[derive(Default)]
+pub(crate) struct Recorder(Option<Mutex<BufWriter<File>>>);
+
+impl Recorder {
+    pub(crate) fn new(path: &str) -> Result<Self> {
+        if path.is_empty() {
+            return Ok(Self(None));
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self(Some(Mutex::new(BufWriter::new(file)))))
+    }
+
+    pub(crate) fn record(&self, at: DateTime<Utc>, entry: Entry) {
+        let writer = match &self.0 {
+            Some(writer) => writer,
+            None => return,
+        };
+        let line = match serde_json::to_string(&Record { at, entry }) {
+            Ok(line) => line,
+            Err(err) => {
+                warn!("Unable to serialize record: {}", err);
+                return;
+            }
+        };
+        // Records are flushed as they are written so that a recording is
+        // complete up to the moment the controller stops.
+        let mut writer = writer.lock();
+        if let Err(err) = writeln!(writer, "{line}").and_then(|_| writer.flush()) {
+            warn!("Unable to write record: {}", err);
+        }
+    }
+}
+
+pub(crate) fn read_recording(path: &Path) -> Result<Vec<Record>> {
+    let mut records = vec![];
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(
+            serde_json::from_str(&line).map_err(|err| anyhow!("line {}: {}", number + 1, err))?,
+        );
+    }
+    Ok(records)
+}
+
+// The jobs dispatched while a recording was made and when it was replayed.
+#[derive(Debug, Default)]
+pub(crate) struct Replay {
+    pub(crate) records: usize,
+    pub(crate) recorded: Vec<(DateTime<Utc>, Dispatch)>,
+    pub(crate) replayed: Vec<(DateTime<Utc>, Dispatch)>,
+}
+
+impl Replay {
+    // The index of the first dispatch that differs, if any.
+    pub(crate) fn first_difference(&self) -> Option<usize> {
+        let recorded = self.recorded.iter().map(|(_, dispatch)| dispatch);
+        let replayed = self.replayed.iter().map(|(_, dispatch)| dispatch);
+        recorded
+            .zip(replayed)
+            .position(|(recorded, replayed)| recorded != replayed)
+            .or_else(|| {
+                (self.recorded.len() != self.replayed.len())
+                    .then(|| self.recorded.len().min(self.replayed.len()))
+            })
+    }
+}
+
+// Feeds recorded watcher events through the watcher handlers and recorded
+// actions through the action loop's dispatcher, with in-memory storage, a fake
+// API server and a clock that follows the recording. Jobs are not run, their
+// outcomes are the recorded actions.
+pub(crate) async fn replay(records: Vec<Record>, settings: Settings) -> Result<Replay> {
+    let mut replay = Replay {
+        records: records.len(),
+        ..Default::default()
+    };
+    let started_at = match records.first() {
+        Some(record) => record.at,
+        None => return Ok(replay),
+    };
+
+    let clock = Arc::new(ManualClock::new(started_at));
+    // Actions sent by the watcher handlers are dropped, the recorded actions
+    // are replayed instead.
+    let (action_tx, _) = mpsc::channel(1);
+    let (notifier, _) = Notifier::new(settings.notifications.queue_size);
+    let context = Context(Arc::new(InnerContext::new(
+        settings,
+        FakeApi::default().client(),
+        get_workflow_storage("memory"),
+        action_tx,
+        Arc::new(StatsdClient::from_sink("", NopMetricSink)),
+        notifier,
+        clock.clone(),
+        Recorder::default(),
+    )));
+
+    let mut dispatcher = Dispatcher::default();
+    let interval = chrono::Duration::from_std(DISPATCH_INTERVAL)?;
+    let mut next_tick = started_at + interval;
+
+    let dispatch = |dispatcher: &mut Dispatcher, replayed: &mut Vec<_>| {
+        for job in dispatcher.dispatch(&context) {
+            replayed.push((context.clock.now(), Dispatch::from(&job)));
+        }
+    };
+
+    for record in records {
+        // The action loop also dispatches on a timer when no actions arrive.
+        while next_tick < record.at {
+            clock.advance_to(next_tick);
+            dispatch(&mut dispatcher, &mut replay.replayed);
+            next_tick += interval;
+        }
+        clock.advance_to(record.at);
+
+        match record.entry {
+            Entry::Namespace(event) => handle_namespace_event(&context, event).await,
+            Entry::Deployment(event) => handle_deployment_event(&context, event).await,
+            Entry::Workflow(event) => handle_workflow_event(&context, event).await,
+            Entry::Action(action) => {
+                dispatcher.handle(&context, action).await;
+                dispatch(&mut dispatcher, &mut replay.replayed);
+            }
+            Entry::Dispatched(recorded) => replay.recorded.push((record.at, recorded)),
+        }
+    }
+
+    Ok(replay)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::{test_deployment, test_namespace, test_workflow, Harness};
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("recording-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut settings = Settings::new().unwrap();
+        settings.recorder.path = path.display().to_string();
+
+        let api = FakeApi::default();
+        let namespaces = ["alpha", "beta", "gamma"];
+        for name in namespaces {
+            api.apply(test_namespace(name));
+            api.apply(test_deployment(name, "app", "app:1.0.0"));
+        }
+        let harness =
+            Harness::start_with_workflow(api.clone(), settings.clone(), &namespaces, 1).await;
+        api.apply(test_workflow(&namespaces, "1.0.1", 1, &[]));
+        harness
+            .run_until(Duration::from_secs(1), Duration::from_secs(120), || async {
+                harness.history().await.len() == 3
+            })
+            .await;
+        harness.stop();
+
+        let records = read_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(records
+            .iter()
+            .any(|record| matches!(record.entry, Entry::Workflow(WatchEvent::Applied(_)))));
+
+        let replay = replay(records, settings).await.unwrap();
+        assert_eq!(replay.first_difference(), None);
+        let groups: Vec<&str> = replay
+            .replayed
+            .iter()
+            .map(|(_, dispatch)| dispatch.group.as_str())
+            .collect();
+        assert_eq!(groups, namespaces);
+    }
+}
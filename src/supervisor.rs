@@ -0,0 +1,698 @@
+use std::{future::Future, time::Duration};
+
+use anyhow::{anyhow, Result};
+use backoff::backoff::Backoff;
+use chrono::{DateTime, Utc};
+use futures::{
+    future::{self, join_all},
+    stream::BoxStream,
+    StreamExt,
+};
+use kube::runtime::watcher::{self, default_backoff};
+use tokio::{
+    sync::watch,
+    task::{AbortHandle, JoinHandle},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    action::Action,
+    action_loop::{deployed_version, triggered_by, WorkflowJob},
+    config::ShutdownPolicy,
+    context::Context,
+    crd_storage::{HistoryRecord, JobChanges, JobCheckpoint},
+};
+
+// How long jobs that are told to stop, after the drain deadline or the group
+// deadline, have to reach their next step before they are aborted.
+const STOP_GRACE: Duration = Duration::from_secs(10);
+
+// Runs a watcher until its stream fails, then starts it again after a backoff.
+// Every start begins with the full set of objects, so the handler resyncs
+// after each restart. The controller is not ready while a watcher is
+// disconnected, and only shuts down after too many failures in a row.
+pub(crate) async fn supervise_watcher<K, S, H, F>(
+    context: &Context,
+    name: &str,
+    mut start: S,
+    mut handle: H,
+) -> Result<()>
+where
+    S: FnMut() -> BoxStream<'static, Result<watcher::Event<K>, watcher::Error>>,
+    H: FnMut(watcher::Event<K>) -> F,
+    F: Future<Output = ()>,
+{
+    let mut backoff = default_backoff();
+    let mut failures = 0;
+
+    // Watchers are disconnected until they have synced.
+    context.health.disconnected(name);
+
+    loop {
+        let mut events = start();
+        let err = loop {
+            match events.next().await {
+                Some(Ok(event)) => {
+                    failures = 0;
+                    backoff.reset();
+                    context.health.connected(name);
+                    handle(event).await;
+                }
+                Some(Err(err)) => break anyhow!(err),
+                None => break anyhow!("stream ended"),
+            }
+        };
+
+        failures += 1;
+        context.health.disconnected(name);
+        context
+            .metrics
+            .count_with_tags("watcher.restart", 1)
+            .with_tag("watcher", name)
+            .send();
+
+        if failures >= context.settings.watchers.max_failures {
+            return Err(anyhow!(
+                "{} watcher failed {} times in a row: {}",
+                name,
+                failures,
+                err
+            ));
+        }
+
+        let delay = backoff.next_backoff().unwrap_or(Duration::from_secs(30));
+        warn!(
+            "{} watcher failed, restarting in {:?}: {}",
+            name, delay, err
+        );
+        context.clock.sleep(delay).await;
+    }
+}
+
+// The outcome of a workflow job.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct JobOutcome {
+    pub(crate) ok: bool,
+    pub(crate) reason: Option<String>,
+}
+
+// Tells a job that the controller is shutting down, or that the job has run
+// past the group deadline, and hears from the job how far it has got.
+pub(crate) struct StopSignal {
+    shutdown: watch::Receiver<bool>,
+    deadline: watch::Receiver<bool>,
+    progress: watch::Sender<(usize, JobChanges)>,
+}
+
+impl StopSignal {
+    // Records the number of actions the job has completed and what it has
+    // changed, so that it can resume from there if it is aborted.
+    pub(crate) fn progress(&self, completed: usize, changes: JobChanges) {
+        self.progress.send_replace((completed, changes));
+    }
+
+    // Completes once the job should stop at its next step.
+    pub(crate) fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        signalled(self.shutdown.clone())
+    }
+
+    // Completes once the job should fail at its next step.
+    pub(crate) fn expired(&self) -> impl Future<Output = ()> + Send + 'static {
+        signalled(self.deadline.clone())
+    }
+}
+
+async fn signalled(mut signal: watch::Receiver<bool>) {
+    if signal.wait_for(|signalled| *signalled).await.is_err() {
+        future::pending::<()>().await;
+    }
+}
+
+struct RunningJob {
+    job: WorkflowJob,
+    progress: watch::Receiver<(usize, JobChanges)>,
+    started_at: DateTime<Utc>,
+    handle: JoinHandle<()>,
+    // The job itself runs in its own task so that its panics are caught, and
+    // has to be aborted along with the task that watches it.
+    task: AbortHandle,
+}
+
+// Runs workflow jobs and makes sure that the action loop hears about every job
+// finishing, including jobs that return an error, panic or run past the group
+// deadline, so that they don't hold a parallel slot forever. Jobs that run past
+// the group deadline are told to fail, and are aborted if they don't. Jobs that
+// don't finish on their own are recorded in the history as failed. Jobs that
+// stop on shutdown are not reported, they resume from their checkpoint when the
+// controller starts again.
+pub(crate) struct JobSupervisor {
+    running: Vec<RunningJob>,
+    stop: watch::Sender<bool>,
+    // The action loop no longer reads reports once shutdown has started.
+    shutting_down: watch::Sender<bool>,
+}
+
+impl Default for JobSupervisor {
+    fn default() -> Self {
+        Self {
+            running: vec![],
+            stop: watch::channel(false).0,
+            shutting_down: watch::channel(false).0,
+        }
+    }
+}
+
+impl JobSupervisor {
+    pub(crate) fn spawn<R, F>(&mut self, context: &Context, job: WorkflowJob, run: R)
+    where
+        R: FnOnce(StopSignal) -> F,
+        F: Future<Output = Result<Option<JobOutcome>>> + Send + 'static,
+    {
+        let deadline = Duration::from_secs(context.settings.jobs.deadline_seconds);
+        let started_at = context.clock.now();
+        let context = context.clone();
+        let (expired, deadline_rx) = watch::channel(false);
+        let (progress, progress_rx) = watch::channel((job.completed, job.changes.clone()));
+        let mut task = tokio::spawn(run(StopSignal {
+            shutdown: self.stop.subscribe(),
+            deadline: deadline_rx,
+            progress,
+        }));
+        let abort = task.abort_handle();
+        let running = job.clone();
+        let shutting_down = self.shutting_down.subscribe();
+        let handle = tokio::spawn(async move {
+            let mut res = tokio::select! {
+                res = &mut task => Some(res),
+                () = context.clock.sleep(deadline) => None,
+            };
+            if res.is_none() {
+                warn!(
+                    "{} job for {} ran past the group deadline",
+                    job.workflow, job.group
+                );
+                expired.send_replace(true);
+                res = tokio::select! {
+                    res = &mut task => Some(res),
+                    () = context.clock.sleep(STOP_GRACE) => None,
+                };
+            }
+
+            let outcome = match res {
+                Some(Ok(Ok(Some(outcome)))) => outcome,
+                Some(Ok(Ok(None))) => return,
+                res => {
+                    let outcome = match res {
+                        Some(Ok(Err(err))) => {
+                            job_failed(&context, &job, "error", format!("job failed: {err}"))
+                        }
+                        Some(Err(err)) if err.is_panic() => {
+                            job_failed(&context, &job, "panic", "job panicked".to_string())
+                        }
+                        Some(_) => {
+                            job_failed(&context, &job, "cancelled", "job was cancelled".to_string())
+                        }
+                        None => {
+                            task.abort();
+                            job_failed(
+                                &context,
+                                &job,
+                                "timeout",
+                                format!("job did not finish within {deadline:?}"),
+                            )
+                        }
+                    };
+                    record_failure(&context, &job, started_at, &outcome).await;
+                    outcome
+                }
+            };
+
+            // Once shutdown has started the report is dropped rather than
+            // waiting for room in a channel that is no longer read.
+            let finished = Action::WorkflowJobFinished(
+                job.workflow.clone(),
+                job.group.clone(),
+                outcome.ok,
+                outcome.reason,
+            );
+            tokio::select! {
+                biased;
+                res = context.action_tx.send(finished) => {
+                    if let Err(err) = res {
+                        error!("Failed to notify that workflow updated job concluded: {}", err);
+                    }
+                }
+                () = signalled(shutting_down) => {
+                    info!("Not reporting that {} job for {} concluded during shutdown", job.workflow, job.group);
+                }
+            }
+        });
+        self.running.push(RunningJob {
+            job: running,
+            progress: progress_rx,
+            started_at,
+            handle,
+            task: abort,
+        });
+    }
+
+    // Forgets the jobs that have finished and reports how many are running and
+    // how many have been running for longer than expected.
+    pub(crate) fn report(&mut self, context: &Context) {
+        self.running.retain(|job| !job.handle.is_finished());
+
+        let stuck_after = chrono::Duration::seconds(context.settings.jobs.stuck_seconds as i64);
+        let now = context.clock.now();
+        let stuck = self
+            .running
+            .iter()
+            .filter(|job| now - job.started_at > stuck_after)
+            .count();
+
+        context
+            .metrics
+            .gauge_with_tags("action_loop.jobs_running", self.running.len() as f64)
+            .send();
+        context
+            .metrics
+            .gauge_with_tags("action_loop.jobs_stuck", stuck as f64)
+            .send();
+    }
+
+    // Waits for the running jobs on shutdown. With the drain policy jobs have
+    // until the drain deadline to finish before they are told to stop, with
+    // the checkpoint policy they are told to stop straight away. Jobs that
+    // don't stop in time are aborted, and resume from the last action they
+    // completed when the controller starts again.
+    pub(crate) async fn shutdown(&mut self, context: &Context) {
+        self.shutting_down.send_replace(true);
+        self.running.retain(|job| !job.handle.is_finished());
+        if self.running.is_empty() {
+            return;
+        }
+
+        let policy = context.settings.shutdown.policy;
+        let drain = Duration::from_secs(context.settings.shutdown.drain_seconds);
+        info!(
+            "Waiting for {} jobs to finish with the {:?} policy",
+            self.running.len(),
+            policy
+        );
+        if policy == ShutdownPolicy::Checkpoint {
+            self.stop.send_replace(true);
+        }
+        if self.wait(context, drain).await {
+            return;
+        }
+
+        if policy == ShutdownPolicy::Drain {
+            self.stop.send_replace(true);
+            if self.wait(context, STOP_GRACE).await {
+                return;
+            }
+        }
+
+        self.running.retain(|job| !job.handle.is_finished());
+        warn!("Aborting {} jobs that did not stop", self.running.len());
+        for job in self.running.drain(..) {
+            job.task.abort();
+            job.handle.abort();
+            let (completed, changes) = job.progress.borrow().clone();
+            record_checkpoint(
+                context,
+                JobCheckpoint {
+                    workflow: job.job.workflow,
+                    checksum: job.job.checksum,
+                    namespace: job.job.group,
+                    completed,
+                    changes,
+                    stopped_at: context.clock.now(),
+                },
+            )
+            .await;
+        }
+    }
+
+    // Whether all running jobs ended within the timeout.
+    async fn wait(&mut self, context: &Context, timeout: Duration) -> bool {
+        // Handles of jobs that ended during an earlier wait can't be polled again.
+        self.running.retain(|job| !job.handle.is_finished());
+        let handles = join_all(self.running.iter_mut().map(|job| &mut job.handle));
+        tokio::select! {
+            _ = handles => true,
+            () = context.clock.sleep(timeout) => false,
+        }
+    }
+}
+
+// Records where a job stopped, so that it resumes from there when the
+// controller starts again. Without persistent storage the checkpoint would be
+// lost with the controller, so the group is only stopped.
+pub(crate) async fn record_checkpoint(context: &Context, checkpoint: JobCheckpoint) {
+    if context.settings.storage.namespace.is_empty() {
+        warn!(
+            "Not recording a checkpoint for {} in {} without storage.namespace, the group will not resume",
+            checkpoint.workflow, checkpoint.namespace
+        );
+    } else if let Err(err) = context.workflow_storage.set_checkpoint(checkpoint).await {
+        error!("Failed to record job checkpoint: {}", err);
+    }
+}
+
+// Records a job that didn't finish on its own in the history of its group.
+async fn record_failure(
+    context: &Context,
+    job: &WorkflowJob,
+    started_at: DateTime<Utc>,
+    outcome: &JobOutcome,
+) {
+    let workflow = context
+        .workflow_storage
+        .get_workflow(job.workflow.clone(), Some(job.checksum))
+        .await
+        .ok();
+    let record = HistoryRecord {
+        workflow: job.workflow.clone(),
+        checksum: job.checksum,
+        namespace: job.group.clone(),
+        started_at,
+        finished_at: context.clock.now(),
+        succeeded: false,
+        reason: outcome.reason.clone(),
+        from_version: deployed_version(context, job).await,
+        to_version: workflow
+            .as_ref()
+            .map(|workflow| workflow.spec.version.clone())
+            .unwrap_or_default(),
+        triggered_by: workflow.as_ref().and_then(triggered_by),
+        entries: vec![],
+    };
+    if let Err(err) = context.workflow_storage.add_history(record).await {
+        error!("Failed to record workflow history: {}", err);
+    }
+}
+
+fn job_failed(context: &Context, job: &WorkflowJob, kind: &str, reason: String) -> JobOutcome {
+    error!("{} job for {} failed: {}", job.workflow, job.group, reason);
+    context
+        .metrics
+        .count_with_tags("action_loop.job_failure", 1)
+        .with_tag("workflow_name", job.workflow.as_str())
+        .with_tag("kind", kind)
+        .send();
+    JobOutcome {
+        ok: false,
+        reason: Some(reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use chrono::Utc;
+    use futures::{future, stream};
+    use k8s_openapi::api::core::v1::Namespace;
+    use tokio::sync::{mpsc, oneshot};
+
+    use super::*;
+    use crate::{
+        clock::{Clock, ManualClock},
+        config::Settings,
+        fake_api::FakeApi,
+        test_util::build_context,
+    };
+
+    type Events = BoxStream<'static, Result<watcher::Event<Namespace>, watcher::Error>>;
+
+    // Runs a supervisor, moving the clock forward until it returns.
+    async fn supervise(
+        context: &Context,
+        clock: &ManualClock,
+        start: impl FnMut() -> Events,
+    ) -> Result<()> {
+        let supervisor = supervise_watcher(context, "namespace", start, |_| async {});
+        tokio::pin!(supervisor);
+        loop {
+            tokio::select! {
+                res = &mut supervisor => return res,
+                _ = tokio::time::sleep(Duration::from_millis(5)) => {
+                    clock.advance_to(clock.now() + chrono::Duration::seconds(60));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_supervise_watcher() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let mut settings = Settings::new().unwrap();
+        settings.watchers.max_failures = 3;
+        let (action_tx, _) = mpsc::channel(1);
+        let context = build_context(settings, FakeApi::default(), action_tx, clock.clone());
+        let ready = context.health.subscribe();
+
+        // A watcher that fails twice before it syncs is restarted and the
+        // controller is ready again once it has synced.
+        let starts = AtomicUsize::new(0);
+        let res = tokio::time::timeout(
+            Duration::from_millis(500),
+            supervise(&context, &clock, || {
+                match starts.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => stream::iter([Err(watcher::Error::NoResourceVersion)]).boxed(),
+                    _ => stream::iter([Ok(watcher::Event::Restarted(vec![]))])
+                        .chain(stream::pending())
+                        .boxed(),
+                }
+            }),
+        )
+        .await;
+        assert!(res.is_err(), "the supervisor should still be running");
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+        assert!(*ready.borrow());
+
+        // A watcher that keeps failing shuts the controller down.
+        let starts = AtomicUsize::new(0);
+        let res = supervise(&context, &clock, || {
+            starts.fetch_add(1, Ordering::SeqCst);
+            stream::iter([Err(watcher::Error::NoResourceVersion)]).boxed()
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+        assert!(!*ready.borrow());
+    }
+
+    fn job(group: &str) -> WorkflowJob {
+        WorkflowJob {
+            workflow: "tenants".to_string(),
+            checksum: 1,
+            group: group.to_string(),
+            after: Utc::now(),
+            completed: 0,
+            changes: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_supervisor() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let (action_tx, mut action_rx) = mpsc::channel(10);
+        let context = build_context(
+            Settings::new().unwrap(),
+            FakeApi::default(),
+            action_tx,
+            clock.clone(),
+        );
+
+        let mut jobs = JobSupervisor::default();
+        jobs.spawn(&context, job("alpha"), |_| async {
+            Ok(Some(JobOutcome {
+                ok: true,
+                reason: None,
+            }))
+        });
+        jobs.spawn(&context, job("beta"), |_| async {
+            Err(anyhow!("not found"))
+        });
+        jobs.spawn(&context, job("gamma"), |_| async { panic!("boom") });
+        jobs.spawn(&context, job("delta"), |_| future::pending());
+        jobs.spawn(&context, job("epsilon"), |stop| async move {
+            stop.expired().await;
+            Ok(Some(JobOutcome {
+                ok: false,
+                reason: Some("deadline".to_string()),
+            }))
+        });
+
+        let mut finished = vec![];
+        while finished.len() < 5 {
+            tokio::select! {
+                action = action_rx.recv() => match action.unwrap() {
+                    Action::WorkflowJobFinished(_, group, ok, _) => finished.push((group, ok)),
+                    action => panic!("unexpected action {action:?}"),
+                },
+                _ = tokio::time::sleep(Duration::from_millis(5)) => {
+                    clock.advance_to(clock.now() + chrono::Duration::minutes(10));
+                }
+            }
+        }
+        finished.sort();
+        assert_eq!(
+            finished,
+            [
+                ("alpha".to_string(), true),
+                ("beta".to_string(), false),
+                ("delta".to_string(), false),
+                ("epsilon".to_string(), false),
+                ("gamma".to_string(), false),
+            ]
+        );
+
+        // A job that fails at the deadline records its own history, the jobs
+        // that don't finish on their own are recorded by the supervisor.
+        let mut recorded = context
+            .workflow_storage
+            .get_history("tenants".to_string(), None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|record| (record.namespace, record.succeeded))
+            .collect::<Vec<_>>();
+        recorded.sort();
+        assert_eq!(
+            recorded,
+            [
+                ("beta".to_string(), false),
+                ("delta".to_string(), false),
+                ("gamma".to_string(), false),
+            ]
+        );
+
+        jobs.report(&context);
+        assert!(jobs.running.is_empty());
+    }
+
+    // Shuts the jobs down, moving the clock forward until they have stopped.
+    async fn shutdown(jobs: &mut JobSupervisor, context: &Context, clock: &ManualClock) {
+        let shutdown = jobs.shutdown(context);
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                () = &mut shutdown => break,
+                _ = tokio::time::sleep(Duration::from_millis(5)) => {
+                    clock.advance_to(clock.now() + chrono::Duration::seconds(5));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let (action_tx, mut action_rx) = mpsc::channel(10);
+        let mut settings = Settings::new().unwrap();
+        settings.storage.namespace = "workflow-deploy".to_string();
+        let context = build_context(settings, FakeApi::default(), action_tx, clock.clone());
+
+        // With the drain policy a job that finishes before the deadline is
+        // reported, a job that stops when told to is not, and a job that does
+        // neither is aborted and checkpointed from the last action it
+        // completed.
+        let mut jobs = JobSupervisor::default();
+        let job_clock = clock.clone();
+        jobs.spawn(&context, job("alpha"), |_| async move {
+            job_clock.sleep(Duration::from_secs(5)).await;
+            Ok(Some(JobOutcome {
+                ok: true,
+                reason: None,
+            }))
+        });
+        jobs.spawn(&context, job("beta"), |stop| async move {
+            stop.stopped().await;
+            Ok(None)
+        });
+        let (gamma_tx, gamma_rx) = oneshot::channel::<()>();
+        let created_jobs = BTreeMap::from([("migrate".to_string(), "migrate-1".to_string())]);
+        let gamma_changes = JobChanges {
+            created_jobs,
+            ..Default::default()
+        };
+        let changes = gamma_changes.clone();
+        jobs.spawn(&context, job("gamma"), |stop| async move {
+            let _gamma_tx = gamma_tx;
+            stop.progress(3, changes);
+            future::pending().await
+        });
+
+        shutdown(&mut jobs, &context, &clock).await;
+        assert!(jobs.running.is_empty());
+        // The job that didn't stop is aborted too, not only the task that
+        // watches it.
+        assert!(tokio::time::timeout(Duration::from_secs(1), gamma_rx)
+            .await
+            .unwrap()
+            .is_err());
+
+        match action_rx.try_recv() {
+            Ok(Action::WorkflowJobFinished(_, group, ok, _)) => {
+                assert_eq!((group.as_str(), ok), ("alpha", true))
+            }
+            action => panic!("unexpected action {action:?}"),
+        }
+        assert!(action_rx.try_recv().is_err());
+
+        let checkpoints = context.workflow_storage.get_checkpoints().await.unwrap();
+        assert_eq!(checkpoints.len(), 1);
+        assert_eq!(checkpoints[0].namespace, "gamma");
+        assert_eq!(checkpoints[0].completed, 3);
+        assert_eq!(checkpoints[0].changes, gamma_changes);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_with_full_channel() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let (action_tx, mut action_rx) = mpsc::channel(1);
+        let context = build_context(
+            Settings::new().unwrap(),
+            FakeApi::default(),
+            action_tx.clone(),
+            clock.clone(),
+        );
+        action_tx
+            .send(Action::ReconcileWorkflow("tenants".to_string()))
+            .await
+            .unwrap();
+
+        // The action loop no longer reads reports once it is shutting down, so
+        // a job that finishes then doesn't hold up shutdown waiting for room in
+        // the channel.
+        let mut jobs = JobSupervisor::default();
+        let job_clock = clock.clone();
+        jobs.spawn(&context, job("alpha"), |_| async move {
+            job_clock.sleep(Duration::from_secs(5)).await;
+            Ok(Some(JobOutcome {
+                ok: true,
+                reason: None,
+            }))
+        });
+        let started_at = clock.now();
+        shutdown(&mut jobs, &context, &clock).await;
+        assert!(
+            clock.now() - started_at
+                < chrono::Duration::seconds(context.settings.shutdown.drain_seconds as i64)
+        );
+
+        assert_eq!(
+            action_rx.try_recv().unwrap(),
+            Action::ReconcileWorkflow("tenants".to_string())
+        );
+        assert!(action_rx.try_recv().is_err());
+    }
+}
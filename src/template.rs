@@ -0,0 +1,27 @@
+// Templates are plain strings with `{{name}}` (or `{{ name }}`) placeholders
+// that are substituted with values known at the time a workflow job runs.
+pub(crate) fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut result = template.to_string();
+    for (key, value) in values {
+        result = result
+            .replace(&format!("{{{{{key}}}}}"), value)
+            .replace(&format!("{{{{ {key} }}}}"), value);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let values = [("version", "1.2.3"), ("namespace", "foo")];
+        assert_eq!(render("{{version}}", &values), "1.2.3");
+        assert_eq!(
+            render("http://api.{{ namespace }}.svc/{{version}}", &values),
+            "http://api.foo.svc/1.2.3"
+        );
+        assert_eq!(render("{{unknown}}", &values), "{{unknown}}");
+    }
+}
@@ -0,0 +1,293 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use cadence::{NopMetricSink, StatsdClient};
+use chrono::Utc;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+    time::sleep,
+};
+
+use crate::{
+    action::Action,
+    action_loop::action_loop,
+    clock::{Clock, ManualClock, SystemClock},
+    config::Settings,
+    context::{Context, InnerContext},
+    crd_storage::{get_workflow_storage, HistoryRecord, WorkflowStorage},
+    fake_api::FakeApi,
+    notify::Notifier,
+    reconcile::reconcile_loop,
+    recorder::Recorder,
+    watch_deployment::watch_deployment,
+    watch_job::watch_job,
+    watch_namespace::watch_namespace,
+    watch_workflow::watch_workflow,
+};
+
+// A context with default settings, in-memory storage, no metrics, the system
+// clock and a client for an empty fake API server.
+pub(crate) fn test_context() -> Context {
+    let settings = Settings::new().unwrap();
+    let (action_tx, _) = mpsc::channel(100);
+    build_context(
+        settings,
+        FakeApi::default(),
+        action_tx,
+        Arc::new(SystemClock),
+    )
+}
+
+pub(crate) fn build_context(
+    settings: Settings,
+    api: FakeApi,
+    action_tx: mpsc::Sender<Action>,
+    clock: Arc<dyn Clock>,
+) -> Context {
+    build_context_with_storage(
+        settings,
+        api,
+        get_workflow_storage("memory"),
+        action_tx,
+        clock,
+    )
+}
+
+pub(crate) fn build_context_with_storage(
+    settings: Settings,
+    api: FakeApi,
+    storage: Box<dyn WorkflowStorage>,
+    action_tx: mpsc::Sender<Action>,
+    clock: Arc<dyn Clock>,
+) -> Context {
+    let (notifier, _) = Notifier::new(settings.notifications.queue_size);
+    let recorder = Recorder::new(&settings.recorder.path).unwrap();
+    Context(Arc::new(InnerContext::new(
+        settings,
+        api.client(),
+        storage,
+        action_tx,
+        Arc::new(StatsdClient::from_sink("", NopMetricSink)),
+        notifier,
+        clock,
+        recorder,
+    )))
+}
+
+// Runs the watchers, the action loop and the reconcile loop against a fake
+// API server, as main does against a cluster, on a test clock.
+pub(crate) struct Harness {
+    pub(crate) context: Context,
+    pub(crate) clock: Arc<ManualClock>,
+    shutdown_tx: broadcast::Sender<bool>,
+    action_shutdown_tx: broadcast::Sender<bool>,
+    action_loop: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Harness {
+    pub(crate) fn start(api: FakeApi, settings: Settings) -> Self {
+        Self::start_with_storage(api, settings, get_workflow_storage("memory"))
+    }
+
+    pub(crate) fn start_with_storage(
+        api: FakeApi,
+        settings: Settings,
+        storage: Box<dyn WorkflowStorage>,
+    ) -> Self {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let (action_tx, mut action_rx) = mpsc::channel(100);
+        let context = build_context_with_storage(settings, api, storage, action_tx, clock.clone());
+        let (shutdown_tx, _) = broadcast::channel(10);
+        let (action_shutdown_tx, _) = broadcast::channel(1);
+
+        macro_rules! spawn_loop {
+            ($loop:ident) => {{
+                let context = context.clone();
+                let mut shutdown = shutdown_tx.subscribe();
+                tokio::spawn(async move { $loop(context, &mut shutdown).await });
+            }};
+        }
+        spawn_loop!(watch_namespace);
+        spawn_loop!(watch_deployment);
+        spawn_loop!(watch_job);
+        spawn_loop!(watch_workflow);
+        spawn_loop!(reconcile_loop);
+        let action_loop = {
+            let context = context.clone();
+            let mut shutdown = action_shutdown_tx.subscribe();
+            tokio::spawn(async move { action_loop(context, &mut shutdown, &mut action_rx).await })
+        };
+
+        Harness {
+            context,
+            clock,
+            shutdown_tx,
+            action_shutdown_tx,
+            action_loop,
+        }
+    }
+
+    // Advances the clock a step at a time until the condition holds, panicking
+    // if it does not within the limit. Each step gives the loops a moment of
+    // real time to react, which is what lets watch events arrive before waits
+    // time out.
+    pub(crate) async fn run_until<F, Fut>(&self, step: Duration, limit: Duration, mut condition: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let deadline = self.clock.now() + chrono::Duration::from_std(limit).unwrap();
+        while !condition().await {
+            assert!(
+                self.clock.now() < deadline,
+                "condition did not hold within {limit:?}"
+            );
+            self.clock
+                .advance_to(self.clock.now() + chrono::Duration::from_std(step).unwrap());
+            sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    // Starts a harness once it has seen the first version of the tenants
+    // workflow, so that the next version is queued.
+    pub(crate) async fn start_with_workflow(
+        api: FakeApi,
+        settings: Settings,
+        namespaces: &[&str],
+        parallel: u32,
+    ) -> Self {
+        api.apply(test_workflow(namespaces, "1.0.0", parallel, &[]));
+        let harness = Harness::start(api, settings);
+        let context = harness.context.clone();
+        harness
+            .run_until(Duration::from_secs(1), Duration::from_secs(10), || {
+                let context = context.clone();
+                async move {
+                    context
+                        .workflow_storage
+                        .current_version("tenants".to_string())
+                        .await
+                        .is_some()
+                }
+            })
+            .await;
+        harness
+    }
+
+    pub(crate) async fn history(&self) -> Vec<HistoryRecord> {
+        self.context
+            .workflow_storage
+            .get_history("tenants".to_string(), None)
+            .await
+            .unwrap()
+    }
+
+    pub(crate) fn stop(&self) {
+        let _ = self.action_shutdown_tx.send(true);
+        let _ = self.shutdown_tx.send(true);
+    }
+
+    // Shuts down in two phases as main does, returning once the action loop
+    // has stopped its jobs and before the watchers are stopped.
+    pub(crate) async fn shutdown(self) {
+        let _ = self.action_shutdown_tx.send(true);
+        self.action_loop.await.unwrap().unwrap();
+        let _ = self.shutdown_tx.send(true);
+    }
+}
+
+// A namespace with workflows enabled.
+pub(crate) fn test_namespace(name: &str) -> Value {
+    json!({
+        "apiVersion": "v1",
+        "kind": "Namespace",
+        "metadata": {
+            "name": name,
+            "annotations": {"workflow-deploy.ngerakines.me/enabled": "true"}
+        }
+    })
+}
+
+// A deployment of the tenants workflow with a single container.
+pub(crate) fn test_deployment(namespace: &str, name: &str, image: &str) -> Value {
+    json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": {
+            "name": name,
+            "namespace": namespace,
+            "annotations": {"workflow-deploy.ngerakines.me/workflow": "tenants"}
+        },
+        "spec": {
+            "replicas": 1,
+            "selector": {"matchLabels": {"app": name}},
+            "template": {
+                "metadata": {"labels": {"app": name}},
+                "spec": {"containers": [{"name": name, "image": image}]}
+            }
+        }
+    })
+}
+
+// The tenants workflow, which updates the image of the app deployment.
+pub(crate) fn test_workflow(
+    namespaces: &[&str],
+    version: &str,
+    parallel: u32,
+    suppressions: &[String],
+) -> Value {
+    json!({
+        "apiVersion": "workflow-deploy.ngerakines.me/v1beta1",
+        "kind": "Workflow",
+        "metadata": {"name": "tenants"},
+        "spec": {
+            "namespaces": namespaces,
+            "version": version,
+            "debounce": 0,
+            "parallel": parallel,
+            "suppressions": suppressions,
+            "steps": [{"actions": [{"update_deployment": {"targets": [{"name": "app", "containers": ["app"]}]}}]}]
+        }
+    })
+}
+
+// Starts a local HTTP server that answers every request with the status and
+// body returned by the handler, which is given the request target (the path
+// and query). Returns the base URL of the server.
+pub(crate) async fn http_stand_in<F>(handler: F) -> String
+where
+    F: Fn(&str) -> (u16, String) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(_) => return,
+            };
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let mut buffer = vec![0; 8192];
+                let read = stream.read(&mut buffer).await.unwrap_or_default();
+                let request = String::from_utf8_lossy(&buffer[..read]).to_string();
+                let target = request.split(' ').nth(1).unwrap_or("/").to_string();
+
+                let (status, body) = handler(&target);
+                let response = format!(
+                    "HTTP/1.1 {status} OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+
+    format!("http://{address}")
+}
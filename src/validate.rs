@@ -0,0 +1,164 @@
+use std::{collections::HashSet, path::Path};
+
+use anyhow::{anyhow, Result};
+use kube::Resource;
+
+use crate::{
+    conversion::canonical_workflow,
+    crd::{Workflow, WorkflowStepAction},
+    patch::ReplicaChange,
+    when::parse_supression,
+};
+
+const MAX_DEBOUNCE_SECONDS: u32 = 86400;
+
+// Reads a workflow of any served version from a YAML or JSON file, checking
+// that it matches the schema.
+pub(crate) fn load_workflow(path: &Path) -> Result<Workflow> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|err| anyhow!("unable to read {}: {}", path.display(), err))?;
+    let value: serde_json::Value = serde_yaml::from_str(&content)
+        .map_err(|err| anyhow!("unable to parse {}: {}", path.display(), err))?;
+
+    let kind = Workflow::kind(&());
+    if value.get("kind").and_then(|value| value.as_str()) != Some(&kind) {
+        return Err(anyhow!("kind must be {}", kind));
+    }
+
+    canonical_workflow(value).map_err(|err| anyhow!("invalid workflow: {}", err))
+}
+
+// Returns a description of each problem found with a workflow. Problems are
+// things that the controller would otherwise ignore or silently drop.
+pub(crate) fn validate_workflow(workflow: &Workflow) -> Vec<String> {
+    let mut problems = vec![];
+
+    // The number of groups in flight is tracked as a u8.
+    if let Some(parallel) = workflow.spec.parallel {
+        if parallel == 0 || parallel > u8::MAX as u32 {
+            problems.push(format!("parallel must be between 1 and {}", u8::MAX));
+        }
+    }
+    if let Some(debounce) = workflow.spec.debounce {
+        if debounce > MAX_DEBOUNCE_SECONDS {
+            problems.push(format!(
+                "debounce must be at most {MAX_DEBOUNCE_SECONDS} seconds"
+            ));
+        }
+    }
+
+    let mut namespaces = HashSet::new();
+    for namespace in workflow.spec.namespaces.iter() {
+        if !namespaces.insert(namespace) {
+            problems.push(format!("namespace {namespace} is listed more than once"));
+        }
+    }
+
+    for (index, suppression) in workflow.spec.suppressions.iter().enumerate() {
+        if parse_supression(suppression).is_none() {
+            problems.push(format!(
+                "suppressions[{index}] {suppression:?} is not a time or time range"
+            ));
+        }
+    }
+
+    for (step_index, step) in workflow.spec.steps.iter().enumerate() {
+        for (action_index, action) in step.actions.iter().enumerate() {
+            let path = format!("steps[{step_index}].actions[{action_index}]");
+            if action.is_empty() {
+                problems.push(format!("{path} has no targets"));
+            }
+
+            // Workflows converted from v1alpha can be missing required fields.
+            match action {
+                WorkflowStepAction::Scale { targets } => {
+                    for target in targets {
+                        if ReplicaChange::parse(&target.replicas).is_none() {
+                            problems.push(format!(
+                                "{path} target {} has invalid replicas {:?}",
+                                target.name, target.replicas
+                            ));
+                        }
+                    }
+                }
+                WorkflowStepAction::HttpCheck { targets } => {
+                    for target in targets.iter().filter(|target| target.url.is_empty()) {
+                        problems.push(format!("{path} target {} has no url", target.name));
+                    }
+                }
+                WorkflowStepAction::RunJob { targets } => {
+                    for target in targets
+                        .iter()
+                        .filter(|target| target.job.is_none() && target.template.is_none())
+                    {
+                        problems.push(format!(
+                            "{path} target {} has no job or template",
+                            target.name
+                        ));
+                    }
+                }
+                WorkflowStepAction::Unknown { action, .. } => {
+                    problems.push(format!("{path} has unknown action {action:?}"));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_workflow() {
+        // Externally tagged enums are read through JSON values, as they are
+        // from the API server.
+        let workflow: serde_json::Value = serde_yaml::from_str(
+            r#"
+apiVersion: workflow-deploy.ngerakines.me/v1beta1
+kind: Workflow
+metadata:
+  name: tenants
+spec:
+  namespaces: ["foo", "bar", "foo"]
+  version: "1.0.1"
+  parallel: 0
+  suppressions:
+  - "2023-05-12T10:00:00Z"
+  - "next tuesday"
+  steps:
+  - actions:
+    - update_deployment:
+        targets:
+        - name: app
+          containers: ["app"]
+    - run_job:
+        targets:
+        - name: migrate
+    - restart_deployment:
+        targets: []
+    - scale:
+        targets:
+        - name: app
+          replicas: "lots"
+"#,
+        )
+        .unwrap();
+        let workflow: Workflow = serde_json::from_value(workflow).unwrap();
+
+        assert_eq!(
+            validate_workflow(&workflow),
+            vec![
+                "parallel must be between 1 and 255".to_string(),
+                "namespace foo is listed more than once".to_string(),
+                "suppressions[1] \"next tuesday\" is not a time or time range".to_string(),
+                "steps[0].actions[1] target migrate has no job or template".to_string(),
+                "steps[0].actions[2] has no targets".to_string(),
+                "steps[0].actions[3] target app has invalid replicas \"lots\"".to_string(),
+            ]
+        );
+    }
+}
@@ -1,174 +1,205 @@
+use std::collections::HashSet;
+
 use anyhow::Result;
 use futures::prelude::*;
 use k8s_openapi::{api::apps::v1::Deployment, Resource};
 use kube::{
-    api::{Api, ListParams, ResourceExt},
+    api::{Api, ResourceExt},
     runtime::watcher,
-    Client,
 };
 use tokio::sync::broadcast::Receiver;
 use tracing::{error, info};
 
-use crate::context::Context;
+use crate::{
+    context::Context,
+    readiness::DeploymentState,
+    recorder::{Entry, WatchEvent},
+    supervisor::supervise_watcher,
+};
 
 pub(crate) async fn watch_deployment(
     context: Context,
     shutdown: &mut Receiver<bool>,
 ) -> Result<()> {
-    let client = Client::try_default().await.map_err(anyhow::Error::msg)?;
+    let client = context.client.clone();
     let api = Api::<Deployment>::all(client.clone());
 
-    let deployment_kind = format!("{};{}", Deployment::API_VERSION, Deployment::KIND);
-
     info!("kubernetes deployment watcher started");
 
-    for deployment in api.list(&ListParams::default()).await?.into_iter() {
-        info!("deployment status: {:?}", deployment.status);
-        let namespace = deployment.namespace().unwrap_or("default".to_string());
-
-        let ready = deployment
-            .clone()
-            .status
-            .map(|status| {
-                status.conditions.iter().all(|conditions| {
-                    conditions
-                        .iter()
-                        .all(|condition| condition.status == "True")
-                })
-            })
-            .unwrap_or_default();
-        info!("deployment ready: {}", ready);
-
-        match deployment
-            .annotations()
-            .get("workflow-deploy.ngerakines.me/workflow")
-        {
-            Some(workflow) => {
-                if let Err(err) = context
-                    .workflow_storage
-                    .add_resource(
-                        namespace,
-                        deployment_kind.clone(),
-                        deployment.name_any(),
-                        workflow.to_string(),
-                        deployment.annotations().clone(),
-                        ready,
-                    )
-                    .await
-                {
-                    error!("Failed to add resource: {}", err);
-                }
+    // The watcher starts, and restarts after it reconnects, with the full set of
+    // deployments, so the initial list is handled the same way as a resync.
+    let deployment_watcher = supervise_watcher(
+        &context,
+        "deployment",
+        || watcher(api.clone(), watcher::Config::default()).boxed(),
+        |event| handle_deployment_event(&context, event.into()),
+    );
+
+    tokio::select! {
+        res = deployment_watcher => res?,
+        _ = shutdown.recv() => { },
+    };
+
+    info!("kubernetes deployment watcher stopped");
+
+    Ok(())
+}
+
+pub(crate) async fn handle_deployment_event(context: &Context, event: WatchEvent<Deployment>) {
+    context
+        .recorder
+        .record(context.clock.now(), Entry::Deployment(event.clone()));
+
+    match event {
+        WatchEvent::Restarted(deployments) => {
+            context
+                .metrics
+                .count_with_tags("deployment_event.encountered", 1)
+                .with_tag("action", "restarted")
+                .send();
+
+            let mut seen = HashSet::new();
+            for deployment in deployments {
+                seen.insert((namespace_of(&deployment), deployment.name_any()));
+                apply_deployment(context, &deployment).await;
             }
-            None => {
-                if let Err(err) = context
-                    .workflow_storage
-                    .remove_resource(namespace, deployment_kind.clone(), deployment.name_any())
-                    .await
-                {
-                    error!("Failed to remove resource: {}", err);
+
+            // Deployments that were deleted while the watcher was disconnected.
+            context.readiness.retain(&seen);
+            match context
+                .workflow_storage
+                .kind_resources(deployment_kind())
+                .await
+            {
+                Ok(resources) => {
+                    for resource in resources {
+                        if !seen.contains(&(resource.namespace.clone(), resource.name.clone())) {
+                            info!(
+                                "pruning deployment {}/{}",
+                                resource.namespace, resource.name
+                            );
+                            remove_deployment(context, resource.namespace, resource.name).await;
+                        }
+                    }
                 }
+                Err(err) => error!("Failed to get resources: {}", err),
             }
         }
+        WatchEvent::Applied(deployment) => {
+            context
+                .metrics
+                .count_with_tags("deployment_event.encountered", 1)
+                .with_tag("action", "applied")
+                .with_tag("namespace_name", deployment.name_any().as_str())
+                .send();
+            apply_deployment(context, &deployment).await;
+        }
+        WatchEvent::Deleted(deployment) => {
+            context
+                .metrics
+                .count_with_tags("deployment_event.encountered", 1)
+                .with_tag("action", "deleted")
+                .with_tag("namespace_name", deployment.name_any().as_str())
+                .send();
+            remove_deployment(context, namespace_of(&deployment), deployment.name_any()).await;
+        }
     }
+}
 
-    // There is a small, but real chance that in between the above list and the below watch, a deployment could be added, updated, or removed.
-
-    let deployment_watcher = watcher(api, watcher::Config::default()).try_for_each(|event| async {
-        match event {
-            kube::runtime::watcher::Event::Deleted(deployment) => {
-                context
-                    .metrics
-                    .count_with_tags("deployment_event.encountered", 1)
-                    .with_tag("action", "deleted")
-                    .with_tag("namespace_name", deployment.name_any().as_str())
-                    .send();
-
-                if let Err(err) = context
-                    .workflow_storage
-                    .remove_resource(
-                        deployment.namespace().unwrap_or("default".to_string()),
-                        deployment_kind.clone(),
-                        deployment.name_any(),
-                    )
-                    .await
-                {
-                    error!("Failed to remove resource: {}", err);
-                }
+fn deployment_kind() -> String {
+    format!("{};{}", Deployment::API_VERSION, Deployment::KIND)
+}
+
+fn namespace_of(deployment: &Deployment) -> String {
+    deployment.namespace().unwrap_or("default".to_string())
+}
+
+async fn apply_deployment(context: &Context, deployment: &Deployment) {
+    info!("deployment status: {:?}", deployment.status);
+    let namespace = namespace_of(deployment);
+
+    let state = DeploymentState::new(deployment);
+    let ready = state.ready;
+    info!("deployment ready: {}", ready);
+    context
+        .readiness
+        .update(&namespace, &deployment.name_any(), Some(state));
+
+    match deployment
+        .annotations()
+        .get("workflow-deploy.ngerakines.me/workflow")
+    {
+        Some(workflow) => {
+            if let Err(err) = context
+                .workflow_storage
+                .add_resource(
+                    namespace,
+                    deployment_kind(),
+                    deployment.name_any(),
+                    workflow.to_string(),
+                    deployment.annotations().clone(),
+                    ready,
+                )
+                .await
+            {
+                error!("Failed to add resource: {}", err);
             }
-            kube::runtime::watcher::Event::Applied(deployment) => {
-                context
-                    .metrics
-                    .count_with_tags("deployment_event.encountered", 1)
-                    .with_tag("action", "applied")
-                    .with_tag("namespace_name", deployment.name_any().as_str())
-                    .send();
-
-                info!("deployment status: {:?}", deployment.status);
-                let namespace = deployment.namespace().unwrap_or("default".to_string());
-
-                let ready = deployment
-                    .clone()
-                    .status
-                    .map(|status| {
-                        status.conditions.iter().all(|conditions| {
-                            conditions
-                                .iter()
-                                .all(|condition| condition.status == "True")
-                        })
-                    })
-                    .unwrap_or_default();
-                info!("deployment ready: {}", ready);
-
-                match deployment
-                    .annotations()
-                    .get("workflow-deploy.ngerakines.me/workflow")
-                {
-                    Some(workflow) => {
-                        if let Err(err) = context
-                            .workflow_storage
-                            .add_resource(
-                                namespace,
-                                deployment_kind.clone(),
-                                deployment.name_any(),
-                                workflow.to_string(),
-                                deployment.annotations().clone(),
-                                ready,
-                            )
-                            .await
-                        {
-                            error!("Failed to add resource: {}", err);
-                        }
-                    }
-                    None => {
-                        if let Err(err) = context
-                            .workflow_storage
-                            .remove_resource(
-                                namespace,
-                                deployment_kind.clone(),
-                                deployment.name_any(),
-                            )
-                            .await
-                        {
-                            error!("Failed to remove resource: {}", err);
-                        }
-                    }
-                }
+        }
+        None => {
+            if let Err(err) = context
+                .workflow_storage
+                .remove_resource(namespace, deployment_kind(), deployment.name_any())
+                .await
+            {
+                error!("Failed to remove resource: {}", err);
             }
-            _ => {}
         }
-        Ok(())
-    });
+    }
+}
 
-    tokio::select! {
-        res = deployment_watcher => {
-            if let Err(e) = res {
-                error!("kubernetes deployment watcher error: {}", e);
-            }
-        },
-        _ = shutdown.recv() => { },
-    };
+async fn remove_deployment(context: &Context, namespace: String, name: String) {
+    context.readiness.update(&namespace, &name, None);
+    if let Err(err) = context
+        .workflow_storage
+        .remove_resource(namespace, deployment_kind(), name)
+        .await
+    {
+        error!("Failed to remove resource: {}", err);
+    }
+}
 
-    info!("kubernetes deployment watcher stopped");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{test_context, test_deployment};
 
-    Ok(())
+    fn deployment(namespace: &str) -> Deployment {
+        serde_json::from_value(test_deployment(namespace, "app", "app:1.0.0")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_resync() {
+        let context = test_context();
+        handle_deployment_event(
+            &context,
+            WatchEvent::Restarted(vec![deployment("alpha"), deployment("beta")]),
+        )
+        .await;
+        let mut changes = context.readiness.subscribe("beta", "app");
+        assert!(changes.borrow_and_update().is_some());
+
+        // Beta was deleted while the watcher was disconnected.
+        handle_deployment_event(&context, WatchEvent::Restarted(vec![deployment("alpha")])).await;
+        let resources = context
+            .workflow_storage
+            .kind_resources(deployment_kind())
+            .await
+            .unwrap();
+        let namespaces: Vec<&str> = resources
+            .iter()
+            .map(|resource| resource.namespace.as_str())
+            .collect();
+        assert_eq!(namespaces, ["alpha"]);
+        assert!(changes.borrow_and_update().is_none());
+    }
 }
@@ -0,0 +1,145 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use futures::prelude::*;
+use k8s_openapi::{api::batch::v1::Job, Resource};
+use kube::{
+    api::{Api, ResourceExt},
+    runtime::watcher,
+};
+use tokio::sync::broadcast::Receiver;
+use tracing::{error, info};
+
+use crate::{
+    context::Context, job::job_finished, recorder::WatchEvent, supervisor::supervise_watcher,
+};
+
+pub(crate) async fn watch_job(context: Context, shutdown: &mut Receiver<bool>) -> Result<()> {
+    let client = context.client.clone();
+    let api = Api::<Job>::all(client.clone());
+
+    info!("kubernetes job watcher started");
+
+    // Only jobs created by workflows are labeled. The watcher starts, and
+    // restarts after it reconnects, with the full set of jobs.
+    let config = watcher::Config::default().labels("workflow-deploy.ngerakines.me/job");
+    let job_watcher = supervise_watcher(
+        &context,
+        "job",
+        || watcher(api.clone(), config.clone()).boxed(),
+        |event| handle_job_event(&context, event.into()),
+    );
+
+    tokio::select! {
+        res = job_watcher => res?,
+        _ = shutdown.recv() => { },
+    };
+
+    info!("kubernetes job watcher stopped");
+
+    Ok(())
+}
+
+async fn handle_job_event(context: &Context, event: WatchEvent<Job>) {
+    match event {
+        WatchEvent::Restarted(jobs) => {
+            context
+                .metrics
+                .count_with_tags("job_event.encountered", 1)
+                .with_tag("action", "restarted")
+                .send();
+
+            let mut seen = HashSet::new();
+            for job in jobs {
+                seen.insert((namespace_of(&job), job.name_any()));
+                apply_job(context, &job).await;
+            }
+            context.finished_jobs.retain(&seen);
+
+            // Jobs that were deleted while the watcher was disconnected.
+            match context.workflow_storage.kind_resources(job_kind()).await {
+                Ok(resources) => {
+                    for resource in resources {
+                        if !seen.contains(&(resource.namespace.clone(), resource.name.clone())) {
+                            info!("pruning job {}/{}", resource.namespace, resource.name);
+                            remove_job(context, resource.namespace, resource.name).await;
+                        }
+                    }
+                }
+                Err(err) => error!("Failed to get resources: {}", err),
+            }
+        }
+        WatchEvent::Applied(job) => {
+            context
+                .metrics
+                .count_with_tags("job_event.encountered", 1)
+                .with_tag("action", "applied")
+                .with_tag("namespace_name", job.name_any().as_str())
+                .send();
+            apply_job(context, &job).await;
+        }
+        WatchEvent::Deleted(job) => {
+            context
+                .metrics
+                .count_with_tags("job_event.encountered", 1)
+                .with_tag("action", "deleted")
+                .with_tag("namespace_name", job.name_any().as_str())
+                .send();
+            remove_job(context, namespace_of(&job), job.name_any()).await;
+        }
+    }
+}
+
+fn job_kind() -> String {
+    format!("{};{}", Job::API_VERSION, Job::KIND)
+}
+
+fn namespace_of(job: &Job) -> String {
+    job.namespace().unwrap_or("default".to_string())
+}
+
+async fn apply_job(context: &Context, job: &Job) {
+    info!("job status: {:?}", job.status);
+    let namespace = namespace_of(job);
+
+    // Jobs are ready once they have finished, whether they completed or failed.
+    let finished = job_finished(job);
+    info!("job finished: {:?}", finished);
+    context
+        .finished_jobs
+        .update(&namespace, &job.name_any(), finished);
+
+    match job
+        .annotations()
+        .get("workflow-deploy.ngerakines.me/workflow")
+    {
+        Some(workflow) => {
+            if let Err(err) = context
+                .workflow_storage
+                .add_resource(
+                    namespace,
+                    job_kind(),
+                    job.name_any(),
+                    workflow.to_string(),
+                    job.annotations().clone(),
+                    finished.is_some(),
+                )
+                .await
+            {
+                error!("Failed to add resource: {}", err);
+            }
+        }
+        None => remove_job(context, namespace, job.name_any()).await,
+    }
+}
+
+async fn remove_job(context: &Context, namespace: String, name: String) {
+    context.finished_jobs.update(&namespace, &name, None);
+    if let Err(err) = context
+        .workflow_storage
+        .remove_resource(namespace, job_kind(), name)
+        .await
+    {
+        error!("Failed to remove resource: {}", err);
+    }
+}
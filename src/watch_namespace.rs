@@ -1,115 +1,120 @@
+use std::collections::HashSet;
+
 use anyhow::Result;
 use futures::prelude::*;
 use k8s_openapi::api::core::v1::Namespace;
 use kube::{
-    api::{Api, ListParams, ResourceExt},
+    api::{Api, ResourceExt},
     runtime::watcher,
-    Client,
 };
 use tokio::sync::broadcast::Receiver;
 use tracing::{error, info};
 
-use crate::{context::Context, k8s_util::annotation_true};
+use crate::{
+    context::Context,
+    k8s_util::annotation_true,
+    recorder::{Entry, WatchEvent},
+    supervisor::supervise_watcher,
+};
 
 pub(crate) async fn watch_namespace(context: Context, shutdown: &mut Receiver<bool>) -> Result<()> {
-    let client = Client::try_default().await.map_err(anyhow::Error::msg)?;
+    let client = context.client.clone();
     let api = Api::<Namespace>::all(client.clone());
 
     info!("kubernetes namespace watcher started");
 
-    for namespace in api.list(&ListParams::default()).await?.into_iter() {
-        match annotation_true(
-            namespace.annotations(),
-            "workflow-deploy.ngerakines.me/enabled",
-        ) {
-            true => {
-                if let Err(err) = context
-                    .workflow_storage
-                    .enable_namespace(namespace.name_any())
-                    .await
-                {
-                    error!("Failed to enable namespace: {}", err);
-                }
-            }
-            false => {
-                if let Err(err) = context
-                    .workflow_storage
-                    .disable_namespace(namespace.name_any())
-                    .await
-                {
-                    error!("Failed to disable namespace: {}", err);
-                }
-            }
-        }
-    }
+    // The watcher starts, and restarts after it reconnects, with the full set of
+    // namespaces, so the initial list is handled the same way as a resync.
+    let namespace_watcher = supervise_watcher(
+        &context,
+        "namespace",
+        || watcher(api.clone(), watcher::Config::default()).boxed(),
+        |event| handle_namespace_event(&context, event.into()),
+    );
 
-    // There is a small, but real chance that in between the above list and the below watch, a namespace could be added, updated, or removed.
+    tokio::select! {
+        res = namespace_watcher => res?,
+        _ = shutdown.recv() => { },
+    };
 
-    let deployment_watcher = watcher(api, watcher::Config::default()).try_for_each(|event| async {
-        match event {
-            kube::runtime::watcher::Event::Deleted(namespace) => {
-                context
-                    .metrics
-                    .count_with_tags("namespace_event.encountered", 1)
-                    .with_tag("action", "deleted")
-                    .with_tag("namespace_name", namespace.name_any().as_str())
-                    .send();
+    info!("kubernetes namespace watcher stopped");
 
-                if let Err(err) = context
-                    .workflow_storage
-                    .disable_namespace(namespace.name_any())
-                    .await
-                {
-                    error!("Failed to disable namespace: {}", err);
-                }
+    Ok(())
+}
+
+pub(crate) async fn handle_namespace_event(context: &Context, event: WatchEvent<Namespace>) {
+    context
+        .recorder
+        .record(context.clock.now(), Entry::Namespace(event.clone()));
+
+    match event {
+        WatchEvent::Restarted(namespaces) => {
+            context
+                .metrics
+                .count_with_tags("namespace_event.encountered", 1)
+                .with_tag("action", "restarted")
+                .send();
+
+            let seen: HashSet<String> = namespaces
+                .iter()
+                .map(|namespace| namespace.name_any())
+                .collect();
+            for namespace in namespaces {
+                apply_namespace(context, &namespace).await;
             }
-            kube::runtime::watcher::Event::Applied(namespace) => {
-                context
-                    .metrics
-                    .count_with_tags("namespace_event.encountered", 1)
-                    .with_tag("action", "applied")
-                    .with_tag("namespace_name", namespace.name_any().as_str())
-                    .send();
 
-                match annotation_true(
-                    namespace.annotations(),
-                    "workflow-deploy.ngerakines.me/enabled",
-                ) {
-                    true => {
-                        if let Err(err) = context
-                            .workflow_storage
-                            .enable_namespace(namespace.name_any())
-                            .await
-                        {
-                            error!("Failed to enable namespace: {}", err);
-                        }
-                    }
-                    false => {
-                        if let Err(err) = context
-                            .workflow_storage
-                            .disable_namespace(namespace.name_any())
-                            .await
-                        {
-                            error!("Failed to disable namespace: {}", err);
-                        }
+            // Namespaces that were deleted while the watcher was disconnected.
+            match context.workflow_storage.enabled_namespaces().await {
+                Ok(enabled) => {
+                    for name in enabled.into_iter().filter(|name| !seen.contains(name)) {
+                        info!("pruning namespace {}", name);
+                        disable_namespace(context, name).await;
                     }
                 }
+                Err(err) => error!("Failed to get enabled namespaces: {}", err),
             }
-            _ => {}
         }
-        Ok(())
-    });
+        WatchEvent::Applied(namespace) => {
+            context
+                .metrics
+                .count_with_tags("namespace_event.encountered", 1)
+                .with_tag("action", "applied")
+                .with_tag("namespace_name", namespace.name_any().as_str())
+                .send();
+            apply_namespace(context, &namespace).await;
+        }
+        WatchEvent::Deleted(namespace) => {
+            context
+                .metrics
+                .count_with_tags("namespace_event.encountered", 1)
+                .with_tag("action", "deleted")
+                .with_tag("namespace_name", namespace.name_any().as_str())
+                .send();
+            disable_namespace(context, namespace.name_any()).await;
+        }
+    }
+}
 
-    tokio::select! {
-        res = deployment_watcher => {
-            if let Err(e) = res {
-                error!("kubernetes namespace watcher error: {}", e);
+async fn apply_namespace(context: &Context, namespace: &Namespace) {
+    match annotation_true(
+        namespace.annotations(),
+        "workflow-deploy.ngerakines.me/enabled",
+    ) {
+        true => {
+            if let Err(err) = context
+                .workflow_storage
+                .enable_namespace(namespace.name_any())
+                .await
+            {
+                error!("Failed to enable namespace: {}", err);
             }
-        },
-        _ = shutdown.recv() => { },
-    };
-
-    info!("kubernetes namespace watcher stopped");
+        }
+        false => disable_namespace(context, namespace.name_any()).await,
+    }
+}
 
-    Ok(())
+async fn disable_namespace(context: &Context, name: String) {
+    if let Err(err) = context.workflow_storage.disable_namespace(name).await {
+        error!("Failed to disable namespace: {}", err);
+    }
 }
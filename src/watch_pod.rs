@@ -0,0 +1,407 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, Result};
+use futures::prelude::*;
+use k8s_openapi::api::{
+    apps::v1::{Deployment, ReplicaSet},
+    core::v1::Pod,
+};
+use kube::{
+    api::{Api, ListParams, ResourceExt},
+    runtime::watcher,
+    Client,
+};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{info, warn};
+
+// Container waiting reasons that mean a pod will not become ready without intervention.
+const FAILED_WAITING_REASONS: [&str; 3] = ["CrashLoopBackOff", "ImagePullBackOff", "ErrImagePull"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PodFailure {
+    // A short reason suitable for use as a metric tag, like "OOMKilled".
+    pub(crate) reason: String,
+    pub(crate) message: String,
+}
+
+// The restart count of each container of a pod, keyed by container name.
+type Restarts = BTreeMap<String, i32>;
+
+fn restarts(pod: &Pod) -> Restarts {
+    pod.status
+        .as_ref()
+        .and_then(|status| status.container_statuses.as_ref())
+        .map(|statuses| {
+            statuses
+                .iter()
+                .map(|status| (status.name.clone(), status.restart_count))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Returns why a pod has failed, if it has. Any container restart since the
+// given restart counts is treated as a failure because pods of a new revision
+// are expected to start cleanly.
+pub(crate) fn pod_failure(pod: &Pod, previous: &Restarts) -> Option<PodFailure> {
+    let statuses = pod.status.as_ref()?.container_statuses.as_ref()?;
+    for status in statuses {
+        let restarted =
+            status.restart_count - previous.get(&status.name).copied().unwrap_or_default();
+        let state = status.state.as_ref();
+        if let Some(reason) = state
+            .and_then(|state| state.waiting.as_ref())
+            .and_then(|waiting| waiting.reason.as_ref())
+        {
+            if FAILED_WAITING_REASONS.contains(&reason.as_str()) {
+                return Some(PodFailure {
+                    reason: reason.clone(),
+                    message: format!(
+                        "pod {} container {} is waiting: {}",
+                        pod.name_any(),
+                        status.name,
+                        reason
+                    ),
+                });
+            }
+        }
+
+        let terminated = state
+            .and_then(|state| state.terminated.as_ref())
+            .or_else(|| {
+                status
+                    .last_state
+                    .as_ref()
+                    .and_then(|state| state.terminated.as_ref())
+                    .filter(|_| restarted > 0)
+            });
+        if terminated.and_then(|terminated| terminated.reason.as_deref()) == Some("OOMKilled") {
+            return Some(PodFailure {
+                reason: "OOMKilled".to_string(),
+                message: format!(
+                    "pod {} container {} was OOMKilled",
+                    pod.name_any(),
+                    status.name
+                ),
+            });
+        }
+
+        if restarted > 0 {
+            return Some(PodFailure {
+                reason: "Restarted".to_string(),
+                message: format!(
+                    "pod {} container {} restarted {} times",
+                    pod.name_any(),
+                    status.name,
+                    restarted
+                ),
+            });
+        }
+    }
+    None
+}
+
+pub(crate) fn deployment_revision(deployment: &Deployment) -> Option<String> {
+    deployment
+        .annotations()
+        .get("deployment.kubernetes.io/revision")
+        .cloned()
+}
+
+// Whether the deployment controller has observed the given generation and
+// moved the deployment to a revision other than the one it had before.
+pub(crate) fn is_new_revision(deployment: &Deployment, generation: i64, previous: &str) -> bool {
+    let observed = deployment
+        .status
+        .as_ref()
+        .and_then(|status| status.observed_generation)
+        .unwrap_or_default();
+    observed >= generation
+        && deployment_revision(deployment)
+            .map(|revision| revision != previous)
+            .unwrap_or_default()
+}
+
+// Builds a label selector for the pods of the current revision of a
+// deployment. The replica set of the current revision shares the deployment's
+// revision annotation, and its pods carry its pod-template-hash label.
+pub(crate) async fn revision_selector(client: Client, deployment: &Deployment) -> Result<String> {
+    let namespace = deployment.namespace().unwrap_or("default".to_string());
+    let revision =
+        deployment_revision(deployment).ok_or_else(|| anyhow!("deployment has no revision"))?;
+    let match_labels: BTreeMap<String, String> = deployment
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.selector.match_labels.clone())
+        .unwrap_or_default();
+    let selector = match_labels
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    let replica_sets: Api<ReplicaSet> = Api::namespaced(client, &namespace);
+    let replica_set = replica_sets
+        .list(&ListParams::default().labels(&selector))
+        .await?
+        .into_iter()
+        .find(|replica_set| {
+            replica_set
+                .owner_references()
+                .iter()
+                .any(|owner| Some(owner.uid.as_str()) == deployment.metadata.uid.as_deref())
+                && replica_set
+                    .annotations()
+                    .get("deployment.kubernetes.io/revision")
+                    == Some(&revision)
+        })
+        .ok_or_else(|| anyhow!("no replica set for revision {}", revision))?;
+
+    let pod_template_hash = replica_set
+        .labels()
+        .get("pod-template-hash")
+        .ok_or_else(|| anyhow!("replica set has no pod-template-hash"))?;
+
+    if selector.is_empty() {
+        return Ok(format!("pod-template-hash={pod_template_hash}"));
+    }
+    Ok(format!("{selector},pod-template-hash={pod_template_hash}"))
+}
+
+// The restart counts of the pods when a pod watch started, keyed by pod name,
+// used to find the failures in later pod events.
+#[derive(Default)]
+struct RestartBaseline {
+    previous: Option<HashMap<String, Restarts>>,
+}
+
+impl RestartBaseline {
+    fn failure(&mut self, event: watcher::Event<Pod>) -> Option<PodFailure> {
+        let pods = match event {
+            watcher::Event::Applied(pod) => vec![pod],
+            watcher::Event::Restarted(pods) => {
+                if self.previous.is_none() {
+                    self.previous = Some(
+                        pods.iter()
+                            .map(|pod| (pod.name_any(), restarts(pod)))
+                            .collect(),
+                    );
+                }
+                pods
+            }
+            watcher::Event::Deleted(_) => vec![],
+        };
+        // Only the initial list tells the pods that were already running
+        // apart from new ones, so nothing counts as a failure before it.
+        let previous = self.previous.as_ref()?;
+        let no_restarts = Restarts::new();
+        pods.iter()
+            .find_map(|pod| pod_failure(pod, previous.get(&pod.name_any()).unwrap_or(&no_restarts)))
+    }
+}
+
+// Watches the pods matching a selector in the background and records the
+// first failure seen. Restarts of pods that were already running when the
+// watch started only count from then on. The watch stops when this is dropped.
+pub(crate) struct PodFailureWatch {
+    failure: watch::Receiver<Option<PodFailure>>,
+    handle: JoinHandle<()>,
+}
+
+impl PodFailureWatch {
+    pub(crate) fn start(client: Client, namespace: &str, selector: &str) -> Self {
+        let api: Api<Pod> = Api::namespaced(client, namespace);
+        let (failure_tx, failure) = watch::channel(None);
+        let selector = selector.to_string();
+
+        let handle = tokio::spawn(async move {
+            info!("pod watcher started for {}", selector);
+            let mut baseline = RestartBaseline::default();
+            let pod_watcher = watcher(api, watcher::Config::default().labels(&selector))
+                .try_for_each(|event| {
+                    if let Some(pod_failure) = baseline.failure(event) {
+                        failure_tx.send_if_modified(|failure| {
+                            if failure.is_none() {
+                                *failure = Some(pod_failure);
+                                return true;
+                            }
+                            false
+                        });
+                    }
+                    future::ready(Ok(()))
+                });
+            if let Err(err) = pod_watcher.await {
+                warn!("pod watcher for {} failed: {}", selector, err);
+            }
+        });
+
+        Self { failure, handle }
+    }
+
+    pub(crate) fn failure(&self) -> Option<PodFailure> {
+        self.failure.borrow().clone()
+    }
+
+    // Completes once a failure has been seen.
+    pub(crate) fn failed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut failure = self.failure.clone();
+        async move {
+            if failure.wait_for(|failure| failure.is_some()).await.is_err() {
+                // The watch stopped without seeing a failure.
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+impl Drop for PodFailureWatch {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pod(container_status: serde_json::Value) -> Pod {
+        serde_json::from_value(json!({
+            "metadata": {"name": "app-1"},
+            "status": {"containerStatuses": [container_status]}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pod_failure() {
+        assert_eq!(
+            pod_failure(
+                &pod(json!({
+                    "name": "app", "image": "app:1", "imageID": "", "ready": true, "restartCount": 0,
+                    "state": {"running": {}}
+                })),
+                &Restarts::new()
+            ),
+            None
+        );
+        assert_eq!(
+            pod_failure(&pod(json!({
+                "name": "app", "image": "app:1", "imageID": "", "ready": false, "restartCount": 0,
+                "state": {"waiting": {"reason": "ImagePullBackOff"}}
+            })), &Restarts::new())
+            .map(|failure| failure.message),
+            Some("pod app-1 container app is waiting: ImagePullBackOff".to_string())
+        );
+        assert_eq!(
+            pod_failure(&pod(json!({
+                "name": "app", "image": "app:1", "imageID": "", "ready": false, "restartCount": 1,
+                "state": {"running": {}},
+                "lastState": {"terminated": {"exitCode": 137, "reason": "OOMKilled"}}
+            })), &Restarts::new())
+            .map(|failure| failure.reason),
+            Some("OOMKilled".to_string())
+        );
+        assert_eq!(
+            pod_failure(&pod(json!({
+                "name": "app", "image": "app:1", "imageID": "", "ready": false, "restartCount": 2,
+                "state": {"running": {}}
+            })), &Restarts::new())
+            .map(|failure| failure.message),
+            Some("pod app-1 container app restarted 2 times".to_string())
+        );
+
+        // Restarts from before the watch started are not failures.
+        let previous = Restarts::from([("app".to_string(), 2)]);
+        assert_eq!(
+            pod_failure(
+                &pod(json!({
+                    "name": "app", "image": "app:1", "imageID": "", "ready": true, "restartCount": 2,
+                    "state": {"running": {}},
+                    "lastState": {"terminated": {"exitCode": 137, "reason": "OOMKilled"}}
+                })),
+                &previous
+            ),
+            None
+        );
+        assert_eq!(
+            pod_failure(
+                &pod(json!({
+                    "name": "app", "image": "app:1", "imageID": "", "ready": false, "restartCount": 3,
+                    "state": {"running": {}}
+                })),
+                &previous
+            )
+            .map(|failure| failure.message),
+            Some("pod app-1 container app restarted 1 times".to_string())
+        );
+    }
+
+    #[test]
+    fn test_restart_baseline() {
+        let pod = |name: &str, restart_count: i32| -> Pod {
+            serde_json::from_value(json!({
+                "metadata": {"name": name},
+                "status": {"containerStatuses": [{
+                    "name": "app", "image": "app:1", "imageID": "", "ready": true,
+                    "restartCount": restart_count, "state": {"running": {}}
+                }]}
+            }))
+            .unwrap()
+        };
+        let mut baseline = RestartBaseline::default();
+
+        // Earlier restarts of a pod seen before the initial list are not failures.
+        assert_eq!(
+            baseline.failure(watcher::Event::Applied(pod("app-1", 2))),
+            None
+        );
+        assert_eq!(
+            baseline.failure(watcher::Event::Restarted(vec![pod("app-1", 2)])),
+            None
+        );
+        assert_eq!(
+            baseline.failure(watcher::Event::Applied(pod("app-1", 2))),
+            None
+        );
+        assert_eq!(
+            baseline
+                .failure(watcher::Event::Applied(pod("app-1", 3)))
+                .map(|failure| failure.message),
+            Some("pod app-1 container app restarted 1 times".to_string())
+        );
+
+        // Pods created after the initial list count every restart, including
+        // after the watch relists.
+        assert_eq!(
+            baseline
+                .failure(watcher::Event::Restarted(vec![
+                    pod("app-1", 2),
+                    pod("app-2", 1)
+                ]))
+                .map(|failure| failure.message),
+            Some("pod app-2 container app restarted 1 times".to_string())
+        );
+    }
+
+    #[test]
+    fn test_is_new_revision() {
+        let deployment = |revision: &str, observed: i64| -> Deployment {
+            serde_json::from_value(json!({
+                "metadata": {
+                    "name": "app",
+                    "generation": 4,
+                    "annotations": {"deployment.kubernetes.io/revision": revision}
+                },
+                "spec": {"selector": {}, "template": {}},
+                "status": {"observedGeneration": observed}
+            }))
+            .unwrap()
+        };
+        // The controller hasn't seen the change yet.
+        assert!(!is_new_revision(&deployment("2", 3), 4, "2"));
+        // The change didn't create a new revision.
+        assert!(!is_new_revision(&deployment("2", 4), 4, "2"));
+        assert!(is_new_revision(&deployment("3", 4), 4, "2"));
+    }
+}
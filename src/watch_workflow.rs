@@ -1,95 +1,158 @@
+use std::collections::HashSet;
+
 use anyhow::Result;
 use futures::prelude::*;
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::{
-    api::{Api, DeleteParams, ListParams, PostParams, ResourceExt},
+    api::{Api, DeleteParams, PostParams, ResourceExt},
     runtime::watcher,
-    Client, CustomResourceExt,
+    Client,
 };
 use tokio::sync::broadcast::Receiver;
 use tracing::{error, info, log::warn};
 
-use crate::context::Context;
-use crate::{action::Action, crd::Workflow};
+use crate::{
+    action::Action,
+    crd::{workflow_crd, Workflow},
+    recorder::{Entry, WatchEvent},
+};
+use crate::{context::Context, supervisor::supervise_watcher};
 
 pub(crate) async fn watch_workflow(context: Context, shutdown: &mut Receiver<bool>) -> Result<()> {
-    let client = Client::try_default().await.map_err(anyhow::Error::msg)?;
+    let client = context.client.clone();
     let api = Api::<Workflow>::all(client.clone());
 
     info!("kubernetes workflow watcher started");
 
-    for workflow in api.list(&ListParams::default()).await?.into_iter() {
-        if let Err(err) = context
-            .workflow_storage
-            .add_workflow(workflow.clone())
-            .await
-        {
-            error!("Failed to add workflow: {}", err);
-        }
-    }
+    // The watcher starts, and restarts after it reconnects, with the full set of
+    // workflows, so the initial list is handled the same way as a resync.
+    let workflow_watcher = supervise_watcher(
+        &context,
+        "workflow",
+        || watcher(api.clone(), watcher::Config::default()).boxed(),
+        |event| handle_workflow_event(&context, event.into()),
+    );
 
-    // There is a small, but real chance that in between the above list and the below watch, a workflow could be added, updated, or removed.
-
-    let deployment_watcher = watcher(api, watcher::Config::default()).try_for_each(|event| async {
-        match event {
-            kube::runtime::watcher::Event::Deleted(workflow) => {
-                context
-                    .metrics
-                    .count_with_tags("workflow_event.encountered", 1)
-                    .with_tag("action", "deleted")
-                    .with_tag("workflow_name", workflow.name_any().as_str())
-                    .send();
-                warn!("Deleting workflows is not supported");
-            }
-            kube::runtime::watcher::Event::Applied(workflow) => {
-                context
-                    .metrics
-                    .count_with_tags("workflow_event.encountered", 1)
-                    .with_tag("action", "applied")
-                    .with_tag("workflow_name", workflow.name_any().as_str())
-                    .send();
-                let current_version = context
-                    .workflow_storage
-                    .current_version(workflow.name_any())
-                    .await
-                    .unwrap_or("".to_string());
+    tokio::select! {
+        res = workflow_watcher => res?,
+        _ = shutdown.recv() => { },
+    };
+
+    info!("kubernetes workflow watcher stopped");
+
+    Ok(())
+}
 
-                if let Err(err) = context
+pub(crate) async fn handle_workflow_event(context: &Context, event: WatchEvent<Workflow>) {
+    context
+        .recorder
+        .record(context.clock.now(), Entry::Workflow(event.clone()));
+
+    match event {
+        WatchEvent::Restarted(workflows) => {
+            context
+                .metrics
+                .count_with_tags("workflow_event.encountered", 1)
+                .with_tag("action", "restarted")
+                .send();
+
+            let seen: HashSet<String> = workflows
+                .iter()
+                .map(|workflow| workflow.name_any())
+                .collect();
+            for workflow in workflows {
+                let known = context
                     .workflow_storage
-                    .add_workflow(workflow.clone())
+                    .lastest_workflow(workflow.name_any())
                     .await
-                {
-                    error!("Failed to add workflow: {}", err);
+                    .ok();
+                let current_version = context
+                    .workflow_storage
+                    .current_version(workflow.name_any())
+                    .await;
+
+                add_workflow(context, &workflow).await;
+
+                // Workflows seen for the first time are stored without queueing
+                // their groups, so that the groups that stopped at a checkpoint
+                // resume. Those that changed while the watcher was disconnected
+                // are handled as if they were applied.
+                if known.is_none() {
+                    send_workflow_updated(context, workflow.name_any(), false).await;
+                } else if known != Some(workflow.checksum()) {
+                    send_workflow_updated(
+                        context,
+                        workflow.name_any(),
+                        current_version.as_ref() != Some(&workflow.spec.version),
+                    )
+                    .await;
                 }
+            }
 
-                if let Err(err) = context
-                    .action_tx
-                    .send(Action::WorkflowUpdated(
-                        workflow.name_any(),
-                        current_version != workflow.spec.version,
-                    ))
-                    .await
+            if let Ok(stored) = context.workflow_storage.get_latest_workflows().await {
+                for workflow in stored
+                    .iter()
+                    .filter(|workflow| !seen.contains(&workflow.name_any()))
                 {
-                    error!("Failed to publish WorkflowUpdated message: {}", err);
+                    warn!(
+                        "Workflow {} was deleted, deleting workflows is not supported",
+                        workflow.name_any()
+                    );
                 }
             }
-            _ => {}
         }
-        Ok(())
-    });
-
-    tokio::select! {
-        res = deployment_watcher => {
-            if let Err(e) = res {
-                error!("kubernetes workflow watcher error: {}", e);
-            }
-        },
-        _ = shutdown.recv() => { },
-    };
+        WatchEvent::Deleted(workflow) => {
+            context
+                .metrics
+                .count_with_tags("workflow_event.encountered", 1)
+                .with_tag("action", "deleted")
+                .with_tag("workflow_name", workflow.name_any().as_str())
+                .send();
+            warn!("Deleting workflows is not supported");
+        }
+        WatchEvent::Applied(workflow) => {
+            context
+                .metrics
+                .count_with_tags("workflow_event.encountered", 1)
+                .with_tag("action", "applied")
+                .with_tag("workflow_name", workflow.name_any().as_str())
+                .send();
+            let current_version = context
+                .workflow_storage
+                .current_version(workflow.name_any())
+                .await
+                .unwrap_or("".to_string());
+
+            add_workflow(context, &workflow).await;
+
+            send_workflow_updated(
+                context,
+                workflow.name_any(),
+                current_version != workflow.spec.version,
+            )
+            .await;
+        }
+    }
+}
 
-    info!("kubernetes workflow watcher stopped");
+async fn add_workflow(context: &Context, workflow: &Workflow) {
+    if let Err(err) = context
+        .workflow_storage
+        .add_workflow(workflow.clone())
+        .await
+    {
+        error!("Failed to add workflow: {}", err);
+    }
+}
 
-    Ok(())
+async fn send_workflow_updated(context: &Context, name: String, version_changed: bool) {
+    if let Err(err) = context
+        .action_tx
+        .send(Action::WorkflowUpdated(name, version_changed))
+        .await
+    {
+        error!("Failed to publish WorkflowUpdated message: {}", err);
+    }
 }
 
 #[allow(unused)]
@@ -114,7 +177,7 @@ pub(crate) async fn init_workflow_crd() -> Result<()> {
             })
         });
 
-    let workflow_crd = Workflow::crd();
+    let workflow_crd = workflow_crd(None)?;
 
     let pp = PostParams::default();
     match crds.create(&pp, &workflow_crd).await {
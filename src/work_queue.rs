@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{DateTime, Utc};
+
+use crate::{action_loop::WorkflowJob, crd_storage::JobChanges};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum JobState {
+    Queued,
+    InFlight,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Job {
+    job: WorkflowJob,
+    state: JobState,
+}
+
+// The jobs of a single workflow. Jobs are keyed by group and checksum, and
+// queued jobs are also ordered by when they may start so that the next job is
+// found without scanning the queue.
+#[derive(Debug, Default)]
+struct WorkflowQueue {
+    jobs: BTreeMap<(String, u64), Job>,
+    queued: BTreeSet<(DateTime<Utc>, String, u64)>,
+    in_flight: usize,
+}
+
+impl WorkflowQueue {
+    fn in_flight_job(&self, group: &str) -> Option<&Job> {
+        self.jobs
+            .range((group.to_string(), u64::MIN)..=(group.to_string(), u64::MAX))
+            .map(|(_, job)| job)
+            .find(|job| job.state == JobState::InFlight)
+    }
+
+    fn remove_queued(&mut self, key: &(String, u64)) {
+        if let Some(job) = self.jobs.remove(key) {
+            self.queued.remove(&(job.job.after, key.0.clone(), key.1));
+        }
+    }
+}
+
+// The work queue of the action loop.
+#[derive(Debug, Default)]
+pub(crate) struct WorkQueue {
+    workflows: BTreeMap<String, WorkflowQueue>,
+}
+
+impl WorkQueue {
+    // Queues a job for each group of a workflow version. Jobs of the workflow
+    // that are queued or finished are replaced, jobs in flight are kept.
+    pub(crate) fn enqueue(
+        &mut self,
+        workflow: &str,
+        checksum: u64,
+        groups: &[String],
+        after: DateTime<Utc>,
+    ) {
+        let queue = self.workflows.entry(workflow.to_string()).or_default();
+        queue.jobs.retain(|_, job| job.state == JobState::InFlight);
+        queue.queued.clear();
+
+        for group in groups {
+            let key = (group.clone(), checksum);
+            if queue.jobs.contains_key(&key) {
+                continue;
+            }
+            queue.queued.insert((after, group.clone(), checksum));
+            queue.jobs.insert(
+                key,
+                Job {
+                    job: WorkflowJob {
+                        workflow: workflow.to_string(),
+                        checksum,
+                        group: group.clone(),
+                        after,
+                        completed: 0,
+                        changes: JobChanges::default(),
+                    },
+                    state: JobState::Queued,
+                },
+            );
+        }
+    }
+
+    // Queues a job that resumes from a checkpoint, unless the group already has
+    // a job for the same version.
+    pub(crate) fn resume(&mut self, job: WorkflowJob) {
+        let queue = self.workflows.entry(job.workflow.clone()).or_default();
+        let key = (job.group.clone(), job.checksum);
+        if queue.jobs.contains_key(&key) {
+            return;
+        }
+        queue
+            .queued
+            .insert((job.after, job.group.clone(), job.checksum));
+        queue.jobs.insert(
+            key,
+            Job {
+                job,
+                state: JobState::Queued,
+            },
+        );
+    }
+
+    // The workflows that have jobs, in name order.
+    pub(crate) fn workflows(&self) -> Vec<String> {
+        self.workflows.keys().cloned().collect()
+    }
+
+    pub(crate) fn queued(&self, workflow: &str) -> usize {
+        self.workflows
+            .get(workflow)
+            .map(|queue| queue.queued.len())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn in_flight(&self, workflow: &str) -> usize {
+        self.workflows
+            .get(workflow)
+            .map(|queue| queue.in_flight)
+            .unwrap_or_default()
+    }
+
+    // Whether the workflow has no jobs queued or in flight.
+    pub(crate) fn is_idle(&self, workflow: &str) -> bool {
+        self.queued(workflow) == 0 && self.in_flight(workflow) == 0
+    }
+
+    // Marks the first job that may start before the given time as in flight
+    // and returns it. A group is not started again while it has a job in
+    // flight.
+    pub(crate) fn next(&mut self, workflow: &str, now: DateTime<Utc>) -> Option<WorkflowJob> {
+        let queue = self.workflows.get_mut(workflow)?;
+        let (after, group, checksum) = queue
+            .queued
+            .iter()
+            .take_while(|(after, _, _)| *after < now)
+            .find(|(_, group, _)| queue.in_flight_job(group).is_none())
+            .cloned()?;
+
+        queue.queued.remove(&(after, group.clone(), checksum));
+        let job = queue.jobs.get_mut(&(group, checksum))?;
+        job.state = JobState::InFlight;
+        queue.in_flight += 1;
+        Some(job.job.clone())
+    }
+
+    // Marks the job in flight for a group as done or failed and returns it.
+    pub(crate) fn finish(&mut self, workflow: &str, group: &str, ok: bool) -> Option<WorkflowJob> {
+        let queue = self.workflows.get_mut(workflow)?;
+        let key = queue
+            .in_flight_job(group)
+            .map(|job| (group.to_string(), job.job.checksum))?;
+        let job = queue.jobs.get_mut(&key)?;
+        job.state = if ok { JobState::Done } else { JobState::Failed };
+        queue.in_flight -= 1;
+        Some(job.job.clone())
+    }
+
+    // Removes the queued jobs of a workflow, or only those of one version of
+    // it, and returns how many were removed.
+    pub(crate) fn purge(&mut self, workflow: &str, checksum: Option<u64>) -> usize {
+        let queue = match self.workflows.get_mut(workflow) {
+            Some(queue) => queue,
+            None => return 0,
+        };
+        let purged: Vec<(String, u64)> = queue
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.state == JobState::Queued)
+            .filter(|(_, job)| checksum.is_none() || checksum == Some(job.job.checksum))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &purged {
+            queue.remove_queued(key);
+        }
+        purged.len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn state(&self, workflow: &str, group: &str, checksum: u64) -> Option<JobState> {
+        self.workflows
+            .get(workflow)?
+            .jobs
+            .get(&(group.to_string(), checksum))
+            .map(|job| job.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn groups(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn group(job: Option<WorkflowJob>) -> Option<String> {
+        job.map(|job| job.group)
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let now = Utc::now();
+        let mut queue = WorkQueue::default();
+        queue.enqueue("tenants", 1, &groups(&["gamma", "alpha", "beta"]), now);
+
+        // Nothing starts before the debounce has passed.
+        assert_eq!(queue.next("tenants", now), None);
+
+        let later = now + Duration::seconds(1);
+        assert_eq!(group(queue.next("tenants", later)), Some("alpha".into()));
+        assert_eq!(group(queue.next("tenants", later)), Some("beta".into()));
+        assert_eq!(queue.in_flight("tenants"), 2);
+        assert_eq!(queue.queued("tenants"), 1);
+        assert_eq!(queue.state("tenants", "alpha", 1), Some(JobState::InFlight));
+
+        assert!(queue.finish("tenants", "alpha", true).is_some());
+        assert_eq!(queue.state("tenants", "alpha", 1), Some(JobState::Done));
+        assert!(queue.finish("tenants", "alpha", true).is_none());
+
+        assert_eq!(group(queue.next("tenants", later)), Some("gamma".into()));
+        assert_eq!(queue.next("tenants", later), None);
+        queue.finish("tenants", "beta", true);
+        queue.finish("tenants", "gamma", true);
+        assert!(queue.is_idle("tenants"));
+        assert_eq!(queue.next("other", later), None);
+    }
+
+    #[test]
+    fn test_new_version() {
+        let now = Utc::now();
+        let later = now + Duration::seconds(1);
+        let mut queue = WorkQueue::default();
+        queue.enqueue("tenants", 1, &groups(&["alpha", "beta"]), now);
+        queue.next("tenants", later);
+
+        // The new version replaces queued jobs but not the one in flight, and
+        // its group waits for the job in flight to finish.
+        queue.enqueue("tenants", 2, &groups(&["alpha", "beta"]), now);
+        assert_eq!(queue.state("tenants", "beta", 1), None);
+        assert_eq!(queue.state("tenants", "alpha", 1), Some(JobState::InFlight));
+        let job = queue.next("tenants", later).unwrap();
+        assert_eq!((job.group.as_str(), job.checksum), ("beta", 2));
+        assert_eq!(queue.next("tenants", later), None);
+
+        queue.finish("tenants", "alpha", true);
+        let job = queue.next("tenants", later).unwrap();
+        assert_eq!((job.group.as_str(), job.checksum), ("alpha", 2));
+    }
+
+    #[test]
+    fn test_purge() {
+        let now = Utc::now();
+        let later = now + Duration::seconds(1);
+        let mut queue = WorkQueue::default();
+        queue.enqueue("tenants", 1, &groups(&["alpha", "beta", "gamma"]), now);
+        queue.next("tenants", later);
+
+        // Only the queued jobs of the failed version are purged.
+        let failed = queue.finish("tenants", "alpha", false).unwrap();
+        assert_eq!(queue.state("tenants", "alpha", 1), Some(JobState::Failed));
+        assert_eq!(queue.purge("tenants", Some(2)), 0);
+        assert_eq!(queue.purge("tenants", Some(failed.checksum)), 2);
+        assert!(queue.is_idle("tenants"));
+        assert_eq!(queue.next("tenants", later), None);
+
+        queue.enqueue("tenants", 2, &groups(&["alpha", "beta"]), now);
+        assert_eq!(queue.state("tenants", "alpha", 1), None);
+        assert_eq!(queue.purge("tenants", None), 2);
+        assert_eq!(queue.purge("other", None), 0);
+    }
+
+    #[test]
+    fn test_resume() {
+        let now = Utc::now();
+        let later = now + Duration::seconds(1);
+        let mut queue = WorkQueue::default();
+        queue.enqueue("tenants", 1, &groups(&["alpha"]), now);
+
+        let resumed = |group: &str, completed| WorkflowJob {
+            workflow: "tenants".to_string(),
+            checksum: 1,
+            group: group.to_string(),
+            after: now,
+            completed,
+            changes: JobChanges::default(),
+        };
+        // Alpha is already queued for the same version.
+        queue.resume(resumed("alpha", 3));
+        queue.resume(resumed("beta", 3));
+        assert_eq!(queue.queued("tenants"), 2);
+
+        assert_eq!(queue.next("tenants", later).unwrap().completed, 0);
+        assert_eq!(queue.next("tenants", later).unwrap().completed, 3);
+    }
+}